@@ -0,0 +1,129 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use soil_sensor_common::crypto::{Key, SoftwareAes};
+use soil_sensor_common::{Body, Payload};
+use soil_sensor_common::history::History;
+use soil_sensor_common::payload::FLAG_ENCRYPTED;
+use thiserror::Error;
+use crate::config::AuthConfig;
+
+#[derive(Debug, Error, Clone, Copy, PartialEq, Eq)]
+pub enum Rejection {
+    #[error("payload is not authenticated, and unauthenticated sensors are not allowed")]
+    Unauthenticated,
+    #[error("sensor has a key configured, but the payload is not authenticated")]
+    MissingTag,
+    #[error("payload is authenticated, but no key is configured for this sensor")]
+    UnknownKey,
+    #[error("authentication tag does not match, or payload failed to decrypt")]
+    BadTag,
+    #[error("replayed payload: boot {boot_count} sequence {sequence} is older than the last one accepted")]
+    Replay { boot_count: u16, sequence: u16 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Freshness {
+    Fresh,
+    /// The same payload has already been accepted. Sensors repeat each advertisement many times,
+    /// and every adapter hears them, so this is expected.
+    Duplicate,
+}
+
+/// Checks and decrypts payloads with the configured per-sensor keys.
+///
+/// Replay protection relies on `(boot_count, sequence)` strictly increasing for every new
+/// measurement from a sensor. The last accepted value is only kept in memory, so a payload
+/// captured before the bridge restarted could be replayed once, until the sensor's next one.
+///
+/// Histories are checked against a counter of their own, the last record accepted from one, since
+/// they are sent after newer measurements.
+pub struct Authenticator {
+    keys: HashMap<u16, Key>,
+    allow_unauthenticated: bool,
+    last_accepted: Mutex<HashMap<u16, (u16, u16)>>,
+    last_history: Mutex<HashMap<u16, (u16, u16)>>,
+}
+
+impl Authenticator {
+    pub fn new(config: &AuthConfig) -> Self {
+        Self {
+            keys: config.keys.iter().map(|(id, key)| (id.0, key.0)).collect(),
+            allow_unauthenticated: config.allow_unauthenticated,
+            last_accepted: Mutex::new(HashMap::new()),
+            last_history: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn key_count(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn allows_unauthenticated(&self) -> bool {
+        self.allow_unauthenticated
+    }
+
+    /// Returns the body of the payload, decrypted if necessary.
+    ///
+    /// The records of an authenticated history which have already been accepted are left out of
+    /// the returned body, and if that is all of them, the history is a [`Freshness::Duplicate`].
+    /// Unauthenticated histories are returned whole, and the caller has to deduplicate them.
+    pub fn check(&self, payload: &Payload) -> Result<(Body, Freshness), Rejection> {
+        let id = payload.body.id();
+        let key = self.keys.get(&id);
+
+        let (auth, body) = match (payload.auth, key) {
+            (None, None) if self.allow_unauthenticated => return Ok((payload.body, Freshness::Fresh)),
+            (None, None) => return Err(Rejection::Unauthenticated),
+            (None, Some(_)) => return Err(Rejection::MissingTag),
+            (Some(_), None) => return Err(Rejection::UnknownKey),
+            (Some(auth), Some(key)) => {
+                let mut cipher = SoftwareAes::new(key);
+                let body = if payload.header.has_flag(FLAG_ENCRYPTED) {
+                    payload.decrypt(&mut cipher)
+                } else {
+                    payload.verify(&mut cipher).then_some(payload.body)
+                };
+                (auth, body.ok_or(Rejection::BadTag)?)
+            }
+        };
+
+        if let Body::History(history) = body {
+            return self.check_history(auth.boot_count, history);
+        }
+
+        let counter = (auth.boot_count, body.sequence());
+        let mut last_accepted = self.last_accepted.lock().unwrap();
+        match last_accepted.get(&id) {
+            Some(last) if counter == *last => Ok((body, Freshness::Duplicate)),
+            Some(last) if counter < *last => Err(Rejection::Replay { boot_count: counter.0, sequence: counter.1 }),
+            _ => {
+                last_accepted.insert(id, counter);
+                Ok((body, Freshness::Fresh))
+            }
+        }
+    }
+
+    /// Buffered measurements don't survive a reset, so a history from an earlier boot than any
+    /// payload accepted from the sensor is a replay.
+    fn check_history(&self, boot_count: u16, history: History) -> Result<(Body, Freshness), Rejection> {
+        let id = history.id;
+        let last_accepted = self.last_accepted.lock().unwrap();
+        let mut last_history = self.last_history.lock().unwrap();
+        let newest_boot = last_accepted.get(&id).into_iter().chain(last_history.get(&id)).map(|(boot, _)| *boot).max();
+        if newest_boot.is_some_and(|newest| boot_count < newest) {
+            return Err(Rejection::Replay { boot_count, sequence: history.first_sequence });
+        }
+
+        let fresh = match last_history.get(&id) {
+            Some(&(boot, sequence)) if boot == boot_count => history.after(sequence),
+            _ => Some(history),
+        };
+        match fresh {
+            Some(fresh) => {
+                last_history.insert(id, (boot_count, fresh.last_sequence()));
+                Ok((Body::History(fresh), Freshness::Fresh))
+            },
+            None => Ok((Body::History(history), Freshness::Duplicate)),
+        }
+    }
+}
@@ -0,0 +1,50 @@
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use chrono::{DateTime, Local, TimeDelta};
+use soil_sensor_common::Measurement;
+use soil_sensor_common::history::History;
+
+/// How long to remember a buffered measurement. Sensors only resend a history if advertising it
+/// failed, which they retry on their next measurement, an hour later.
+const FORGET_AFTER: Duration = Duration::from_secs(3 * 60 * 60);
+
+/// Remembers which buffered measurements have been written, so that each is only written once,
+/// however many times (and by however many adapters) its history is heard.
+///
+/// Measurements are keyed by sensor ID, boot count and sequence number. Unauthenticated sensors
+/// don't send a boot count, so a record from one of them can be mistaken for one with the same
+/// sequence number from before it reset, if the reset was within [`FORGET_AFTER`].
+///
+/// Records of authenticated histories which were accepted before are already left out by
+/// [`Authenticator::check`](crate::auth::Authenticator::check), for as long as the bridge runs.
+#[derive(Default)]
+pub struct Backfill {
+    seen: Mutex<HashMap<(u16, u16, u16), Instant>>,
+}
+
+impl Backfill {
+    /// Returns the records of `history` which haven't been returned before, each with the time
+    /// it was measured, worked out from its age and the time the history was received.
+    pub fn accept(&self, history: &History, boot_count: Option<u16>, received: DateTime<Local>)
+                  -> Vec<(Measurement, DateTime<Local>)> {
+        let now = Instant::now();
+        let mut seen = self.seen.lock().unwrap();
+        seen.retain(|_, first_seen| now.duration_since(*first_seen) < FORGET_AFTER);
+
+        history.measurements()
+            .filter(|(_, measurement)| {
+                let key = (measurement.id, boot_count.unwrap_or(0), measurement.sequence);
+                match seen.entry(key) {
+                    Entry::Occupied(_) => false,
+                    Entry::Vacant(entry) => {
+                        entry.insert(now);
+                        true
+                    }
+                }
+            })
+            .map(|(age_minutes, measurement)| (measurement, received - TimeDelta::minutes(age_minutes as i64)))
+            .collect()
+    }
+}
@@ -0,0 +1,139 @@
+//! Fitting temperature compensation coefficients from historical data.
+//!
+//! Real changes in soil moisture are slow, while the thermal drift of the probe oscillator
+//! follows the daily temperature cycle. So both temperature and frequency are detrended by
+//! subtracting a centered moving average (one day wide by default), and the compensation
+//! coefficient is the least-squares slope of the remaining frequency against temperature.
+
+use std::path::Path;
+use chrono::DateTime;
+use soil_sensor_common::compensation::TemperatureCompensation;
+use soil_sensor_common::units::MOISTURE_GATE_SECONDS;
+use thiserror::Error;
+use crate::config::SensorId;
+
+/// Fewer samples than this, and the fit is mostly noise.
+const MIN_SAMPLES: usize = 48;
+
+/// Older bridges wrote `TEMPERATURE_UNAVAILABLE` out as if it were a reading, which comes to far
+/// below absolute zero. Newer ones leave the temperature out instead.
+const MIN_CELSIUS: f64 = -273.15;
+
+#[derive(Debug, Error)]
+pub enum FitError {
+    #[error("failed to read input: {0}")]
+    Csv(#[from] csv::Error),
+    #[error("input has no \"{0}\" column")]
+    MissingColumn(&'static str),
+    #[error("only {0} usable samples, need at least {MIN_SAMPLES}")]
+    TooFewSamples(usize),
+    #[error("temperature does not vary within the window, so there is nothing to fit")]
+    NoTemperatureVariation,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Sample {
+    seconds: f64,
+    celsius: f64,
+    hz: f64,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Fit {
+    pub compensation: TemperatureCompensation,
+    pub samples: usize,
+    /// Fraction of the detrended frequency variance explained by temperature
+    pub r_squared: f64,
+}
+
+/// Fits compensation coefficients from a CSV file, such as an InfluxDB export of the
+/// `soil_moisture` measurement. Needs `time`, `temperature` and `moisture_level` columns, and an
+/// `id` column if `sensor` is given. Times are either RFC 3339, or integer nanoseconds since the
+/// epoch (InfluxDB's default). Rows without a temperature are skipped.
+pub fn fit_csv(path: &Path, sensor: Option<SensorId>, window_hours: u32) -> Result<Fit, FitError> {
+    let mut reader = csv::Reader::from_path(path)?;
+    let headers = reader.headers()?.clone();
+    let column = |name: &'static str| headers.iter().position(|h| h == name).ok_or(FitError::MissingColumn(name));
+    let time_col = column("time")?;
+    let temperature_col = column("temperature")?;
+    let moisture_col = column("moisture_level")?;
+    let id_col = match sensor {
+        Some(_) => Some(column("id")?),
+        None => None,
+    };
+
+    let mut samples = Vec::new();
+    for record in reader.records() {
+        let record = record?;
+        if let (Some(col), Some(sensor)) = (id_col, sensor) {
+            if record.get(col).and_then(|id| id.parse::<u16>().ok()) != Some(sensor.0) {
+                continue;
+            }
+        }
+        let seconds = record.get(time_col).and_then(parse_time);
+        let celsius = record.get(temperature_col).and_then(|t| t.parse::<f64>().ok())
+            .filter(|celsius| *celsius >= MIN_CELSIUS);
+        let count = record.get(moisture_col).and_then(|m| m.parse::<f64>().ok());
+        if let (Some(seconds), Some(celsius), Some(count)) = (seconds, celsius, count) {
+            samples.push(Sample { seconds, celsius, hz: count / MOISTURE_GATE_SECONDS as f64 });
+        }
+    }
+
+    fit(samples, window_hours as f64 * 3600.0)
+}
+
+fn parse_time(time: &str) -> Option<f64> {
+    if let Ok(nanoseconds) = time.parse::<i64>() {
+        return Some(nanoseconds as f64 / 1e9);
+    }
+    DateTime::parse_from_rfc3339(time).ok()
+        .map(|time| time.timestamp() as f64 + time.timestamp_subsec_nanos() as f64 / 1e9)
+}
+
+fn fit(mut samples: Vec<Sample>, window_seconds: f64) -> Result<Fit, FitError> {
+    if samples.len() < MIN_SAMPLES {
+        return Err(FitError::TooFewSamples(samples.len()));
+    }
+    samples.sort_by(|a, b| a.seconds.total_cmp(&b.seconds));
+
+    // Centered moving average, with a sliding window over the sorted samples
+    let (mut start, mut end) = (0, 0);
+    let (mut sum_celsius, mut sum_hz) = (0.0, 0.0);
+    let mut residuals = Vec::with_capacity(samples.len());
+    for sample in &samples {
+        while end < samples.len() && samples[end].seconds <= sample.seconds + window_seconds / 2.0 {
+            sum_celsius += samples[end].celsius;
+            sum_hz += samples[end].hz;
+            end += 1;
+        }
+        while samples[start].seconds < sample.seconds - window_seconds / 2.0 {
+            sum_celsius -= samples[start].celsius;
+            sum_hz -= samples[start].hz;
+            start += 1;
+        }
+        let n = (end - start) as f64;
+        residuals.push((sample.celsius - sum_celsius / n, sample.hz - sum_hz / n));
+    }
+
+    let sxx: f64 = residuals.iter().map(|(t, _)| t * t).sum();
+    let sxy: f64 = residuals.iter().map(|(t, f)| t * f).sum();
+    let syy: f64 = residuals.iter().map(|(_, f)| f * f).sum();
+    if sxx <= f64::EPSILON {
+        return Err(FitError::NoTemperatureVariation);
+    }
+    let slope = sxy / sxx;
+    let r_squared = if syy > 0.0 { (sxy * sxy) / (sxx * syy) } else { 0.0 };
+
+    // Using the mean temperature as the reference keeps the average correction close to zero,
+    // so existing calibrations stay roughly valid.
+    let reference = samples.iter().map(|s| s.celsius).sum::<f64>() / samples.len() as f64;
+
+    Ok(Fit {
+        compensation: TemperatureCompensation {
+            reference_celsius: reference as f32,
+            hz_per_celsius: slope as f32,
+        },
+        samples: samples.len(),
+        r_squared,
+    })
+}
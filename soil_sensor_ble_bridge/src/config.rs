@@ -0,0 +1,1156 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use bluer::Address;
+use influxdb::Client;
+use log::LevelFilter;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde::de::Error as _;
+use soil_sensor_common::calibration::{Calibration, CalibrationError, CalibrationPoint, Curve};
+use soil_sensor_common::compensation::TemperatureCompensation;
+use soil_sensor_common::crypto::{Key, KEY_LEN};
+use soil_sensor_common::web::InfluxDBMeasurement;
+use soil_sensor_common::Measurement;
+use thiserror::Error;
+use crate::registry::Registry;
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("failed to read config file: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("failed to parse config file: {0}")]
+    Parse(#[from] toml::de::Error),
+    #[error("invalid calibration for sensor {0}: {1}")]
+    Calibration(SensorId, CalibrationError),
+    #[error("calibration for sensor {0} has both points and a polynomial")]
+    CalibrationCurve(SensorId),
+    #[error("sensor registry {}: {1}", .0.display())]
+    Registry(PathBuf, String),
+    #[error("sensor {0} has a calibration both in the config file and in the sensor registry")]
+    CalibrationConflict(SensorId),
+    /// A value which parsed, but doesn't make sense. `at` is the path to it, e.g. `sinks.influx.url`.
+    #[error("{at}: {reason}")]
+    Invalid { at: String, reason: String },
+}
+
+impl ConfigError {
+    pub fn invalid(at: impl Into<String>, reason: impl Into<String>) -> Self {
+        Self::Invalid { at: at.into(), reason: reason.into() }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default)]
+    pub auth: AuthConfig,
+    /// Moisture calibration for each sensor, by sensor ID
+    #[serde(default)]
+    pub calibration: HashMap<SensorId, CalibrationConfig>,
+    /// Temperature compensation for each sensor, by sensor ID, applied before calibration.
+    /// Coefficients can be fitted with the `fit-compensation` subcommand.
+    #[serde(default)]
+    pub compensation: HashMap<SensorId, TemperatureCompensation>,
+    /// Where measurements are written, by name. With none, they are only logged.
+    #[serde(default)]
+    pub sinks: BTreeMap<String, SinkConfig>,
+    #[serde(default)]
+    pub spool: SpoolConfig,
+    #[serde(default)]
+    pub bluetooth: BluetoothConfig,
+    #[serde(default)]
+    pub filter: FilterConfig,
+    #[serde(default)]
+    pub logging: LoggingConfig,
+    #[serde(default)]
+    pub http: HttpConfig,
+    #[serde(default)]
+    pub history: HistoryConfig,
+    #[serde(default)]
+    pub registry: RegistryConfig,
+    /// Loaded from `registry.path`
+    #[serde(skip)]
+    pub sensors: Registry,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SinkConfig {
+    /// InfluxDB 1.x, or 2.x through its v1 compatibility API
+    Influxdb(InfluxDbConfig),
+    /// InfluxDB 2.x or 3.x, through the native `/api/v2/write` API
+    Influxdb2(InfluxDb2Config),
+    /// An MQTT broker, with Home Assistant discovery
+    Mqtt(MqttConfig),
+    /// Local CSV or JSON lines files, rotated daily
+    File(FileConfig),
+}
+
+impl SinkConfig {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::Influxdb(_) => "influxdb",
+            Self::Influxdb2(_) => "influxdb2",
+            Self::Mqtt(_) => "mqtt",
+            Self::File(_) => "file",
+        }
+    }
+
+    pub fn retry(&self) -> &RetryPolicy {
+        match self {
+            Self::Influxdb(influxdb) => &influxdb.retry,
+            Self::Influxdb2(influxdb) => &influxdb.retry,
+            Self::Mqtt(mqtt) => &mqtt.retry,
+            Self::File(file) => &file.retry,
+        }
+    }
+
+    pub fn batch(&self) -> &BatchPolicy {
+        match self {
+            Self::Influxdb(influxdb) => &influxdb.batch,
+            Self::Influxdb2(influxdb) => &influxdb.batch,
+            Self::Mqtt(mqtt) => &mqtt.batch,
+            Self::File(file) => &file.batch,
+        }
+    }
+}
+
+/// Where measurements are kept while a sink is down. Each sink gets a directory of its own, and
+/// the limits apply to each one separately.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SpoolConfig {
+    /// Without a directory, measurements are dropped once a sink runs out of retries. Relative
+    /// to the config file.
+    pub dir: Option<PathBuf>,
+    #[serde(default = "default_spool_max_megabytes")]
+    pub max_megabytes: u64,
+    #[serde(default = "default_spool_max_age_hours")]
+    pub max_age_hours: u64,
+}
+
+fn default_spool_max_megabytes() -> u64 {
+    100
+}
+
+fn default_spool_max_age_hours() -> u64 {
+    30 * 24
+}
+
+impl Default for SpoolConfig {
+    fn default() -> Self {
+        Self {
+            dir: None,
+            max_megabytes: default_spool_max_megabytes(),
+            max_age_hours: default_spool_max_age_hours(),
+        }
+    }
+}
+
+/// How hard to try writing a batch to a sink before dropping it. The wait between attempts
+/// doubles every time, up to the maximum.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RetryPolicy {
+    #[serde(default = "default_attempts")]
+    pub attempts: u32,
+    #[serde(default = "default_initial_backoff_secs")]
+    pub initial_backoff_secs: u64,
+    #[serde(default = "default_max_backoff_secs")]
+    pub max_backoff_secs: u64,
+}
+
+fn default_attempts() -> u32 {
+    5
+}
+
+fn default_initial_backoff_secs() -> u64 {
+    1
+}
+
+fn default_max_backoff_secs() -> u64 {
+    60
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            attempts: default_attempts(),
+            initial_backoff_secs: default_initial_backoff_secs(),
+            max_backoff_secs: default_max_backoff_secs(),
+        }
+    }
+}
+
+impl RetryPolicy {
+    fn validate(&self, at: &str) -> Result<(), ConfigError> {
+        if self.attempts == 0 {
+            return Err(ConfigError::invalid(format!("{}.attempts", at), "must be at least 1"));
+        }
+        if self.initial_backoff_secs > self.max_backoff_secs {
+            return Err(ConfigError::invalid(at, "initial_backoff_secs is more than max_backoff_secs"));
+        }
+        Ok(())
+    }
+}
+
+/// How measurements are grouped into writes. A batch is written once it has `max_points`, or
+/// `flush_interval_secs` after its first measurement arrived, whichever comes first.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BatchPolicy {
+    #[serde(default = "default_max_points")]
+    pub max_points: usize,
+    #[serde(default = "default_flush_interval_secs")]
+    pub flush_interval_secs: u64,
+    /// Measurements waiting to be batched. Once it is full, new measurements for this sink are
+    /// dropped straight away, so that the sensors are never held up.
+    #[serde(default = "default_queue_len")]
+    pub queue_len: usize,
+}
+
+fn default_max_points() -> usize {
+    100
+}
+
+fn default_flush_interval_secs() -> u64 {
+    5
+}
+
+fn default_queue_len() -> usize {
+    1000
+}
+
+impl Default for BatchPolicy {
+    fn default() -> Self {
+        Self {
+            max_points: default_max_points(),
+            flush_interval_secs: default_flush_interval_secs(),
+            queue_len: default_queue_len(),
+        }
+    }
+}
+
+impl BatchPolicy {
+    fn validate(&self, at: &str) -> Result<(), ConfigError> {
+        if self.max_points == 0 {
+            return Err(ConfigError::invalid(format!("{}.max_points", at), "must be at least 1"));
+        }
+        if self.queue_len < self.max_points {
+            return Err(ConfigError::invalid(at, "queue_len is less than max_points"));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct InfluxDbConfig {
+    pub url: String,
+    pub database: String,
+    /// Name of the InfluxDB measurement that points are written to
+    #[serde(default = "default_influxdb_measurement")]
+    pub measurement: String,
+    /// API token. Mutually exclusive with `username` and `password`.
+    pub token: Option<Secret>,
+    pub username: Option<String>,
+    pub password: Option<Secret>,
+    #[serde(default)]
+    pub retry: RetryPolicy,
+    #[serde(default)]
+    pub batch: BatchPolicy,
+}
+
+fn default_influxdb_measurement() -> String {
+    "soil_moisture".to_string()
+}
+
+impl InfluxDbConfig {
+    pub fn client(&self) -> Client {
+        let client = Client::new(&self.url, &self.database);
+        match (&self.token, &self.username, &self.password) {
+            (Some(token), _, _) => client.with_token(token.value()),
+            (None, Some(username), Some(password)) => client.with_auth(username, password.value()),
+            _ => client,
+        }
+    }
+
+    fn validate(&self, at: &str) -> Result<(), ConfigError> {
+        if !(self.url.starts_with("http://") || self.url.starts_with("https://")) {
+            return Err(ConfigError::invalid(format!("{}.url", at), "must start with http:// or https://"));
+        }
+        if self.database.is_empty() {
+            return Err(ConfigError::invalid(format!("{}.database", at), "must not be empty"));
+        }
+        if self.measurement.is_empty() {
+            return Err(ConfigError::invalid(format!("{}.measurement", at), "must not be empty"));
+        }
+        match (&self.token, &self.username, &self.password) {
+            (Some(_), None, None) | (None, None, None) | (None, Some(_), Some(_)) => Ok(()),
+            (Some(_), _, _) => Err(ConfigError::invalid(at, "set either token, or username and password, not both")),
+            (None, _, _) => Err(ConfigError::invalid(at, "username and password must be set together")),
+        }
+    }
+
+    fn secrets_mut(&mut self) -> Vec<(&'static str, &mut Secret)> {
+        [("token", self.token.as_mut()), ("password", self.password.as_mut())]
+            .into_iter()
+            .filter_map(|(name, secret)| secret.map(|secret| (name, secret)))
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct InfluxDb2Config {
+    /// Base URL of the server, without `/api/v2/write`
+    pub url: String,
+    pub org: String,
+    pub bucket: String,
+    pub token: Secret,
+    #[serde(default = "default_influxdb_measurement")]
+    pub measurement: String,
+    #[serde(default)]
+    pub precision: Precision,
+    /// Compress request bodies
+    #[serde(default = "default_true")]
+    pub gzip: bool,
+    /// Values written as tags. Defaults to [`InfluxDBMeasurement::DEFAULT_TAGS`].
+    pub tags: Option<Vec<String>>,
+    /// Values written as fields. Defaults to every value that isn't a tag.
+    pub fields: Option<Vec<String>>,
+    /// Keys to write values under, by value name, for any that shouldn't keep their own name
+    #[serde(default)]
+    pub rename: BTreeMap<String, String>,
+    #[serde(default)]
+    pub retry: RetryPolicy,
+    #[serde(default)]
+    pub batch: BatchPolicy,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// Precision of the timestamps written
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Precision {
+    Ns,
+    Us,
+    Ms,
+    #[default]
+    S,
+}
+
+impl Precision {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Ns => "ns",
+            Self::Us => "us",
+            Self::Ms => "ms",
+            Self::S => "s",
+        }
+    }
+}
+
+impl InfluxDb2Config {
+    /// Whether a value is written as a tag.
+    pub fn is_tag(&self, name: &str) -> bool {
+        match &self.tags {
+            Some(tags) => tags.iter().any(|tag| tag == name),
+            None => InfluxDBMeasurement::DEFAULT_TAGS.contains(&name),
+        }
+    }
+
+    /// Whether a value is written as a field. Values which are neither are left out.
+    pub fn is_field(&self, name: &str) -> bool {
+        match &self.fields {
+            Some(fields) => fields.iter().any(|field| field == name),
+            None => !self.is_tag(name),
+        }
+    }
+
+    /// The key a value is written under.
+    pub fn key<'a>(&'a self, name: &'a str) -> &'a str {
+        self.rename.get(name).map_or(name, String::as_str)
+    }
+
+    fn validate(&self, at: &str) -> Result<(), ConfigError> {
+        if !(self.url.starts_with("http://") || self.url.starts_with("https://")) {
+            return Err(ConfigError::invalid(format!("{}.url", at), "must start with http:// or https://"));
+        }
+        for (field, value) in [("org", &self.org), ("bucket", &self.bucket), ("measurement", &self.measurement)] {
+            if value.is_empty() {
+                return Err(ConfigError::invalid(format!("{}.{}", at, field), "must not be empty"));
+            }
+        }
+
+        let known = |field: &str, name: &str| if InfluxDBMeasurement::value_names().any(|known| known == name) {
+            Ok(())
+        } else {
+            Err(ConfigError::invalid(format!("{}.{}", at, field), format!("unknown value \"{}\"", name)))
+        };
+        for tag in self.tags.iter().flatten() {
+            known("tags", tag)?;
+        }
+        for field in self.fields.iter().flatten() {
+            known("fields", field)?;
+            if self.is_tag(field) {
+                return Err(ConfigError::invalid(at, format!("\"{}\" is both a tag and a field", field)));
+            }
+        }
+        if !InfluxDBMeasurement::value_names().any(|name| self.is_field(name)) {
+            return Err(ConfigError::invalid(format!("{}.fields", at), "at least one value must be a field"));
+        }
+        for (name, key) in &self.rename {
+            known("rename", name)?;
+            if key.is_empty() {
+                return Err(ConfigError::invalid(format!("{}.rename.{}", at, name), "must not be empty"));
+            }
+        }
+        Ok(())
+    }
+
+    fn secrets_mut(&mut self) -> Vec<(&'static str, &mut Secret)> {
+        vec![("token", &mut self.token)]
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MqttConfig {
+    pub host: String,
+    #[serde(default = "default_mqtt_port")]
+    pub port: u16,
+    #[serde(default = "default_mqtt_client_id")]
+    pub client_id: String,
+    pub username: Option<String>,
+    pub password: Option<Secret>,
+    /// Readings are published to `<topic_prefix>/<sensor ID>/state`, backfilled ones to
+    /// `<topic_prefix>/<sensor ID>/backfill`, and the bridge's availability to
+    /// `<topic_prefix>/bridge/availability`.
+    #[serde(default = "default_mqtt_topic_prefix")]
+    pub topic_prefix: String,
+    #[serde(default = "default_mqtt_qos")]
+    pub qos: u8,
+    /// Publish Home Assistant discovery configs for every sensor
+    #[serde(default = "default_true")]
+    pub discovery: bool,
+    #[serde(default = "default_mqtt_discovery_prefix")]
+    pub discovery_prefix: String,
+    #[serde(default)]
+    pub retry: RetryPolicy,
+    #[serde(default)]
+    pub batch: BatchPolicy,
+}
+
+fn default_mqtt_port() -> u16 {
+    1883
+}
+
+fn default_mqtt_client_id() -> String {
+    "soil_sensor_bridge".to_string()
+}
+
+fn default_mqtt_topic_prefix() -> String {
+    "soil_sensors".to_string()
+}
+
+fn default_mqtt_qos() -> u8 {
+    1
+}
+
+fn default_mqtt_discovery_prefix() -> String {
+    "homeassistant".to_string()
+}
+
+impl MqttConfig {
+    fn validate(&self, at: &str) -> Result<(), ConfigError> {
+        for (field, value) in [("host", &self.host), ("client_id", &self.client_id)] {
+            if value.is_empty() {
+                return Err(ConfigError::invalid(format!("{}.{}", at, field), "must not be empty"));
+            }
+        }
+        // Wildcards, or a trailing slash, would make the topics built from these invalid
+        for (field, value) in [("topic_prefix", &self.topic_prefix), ("discovery_prefix", &self.discovery_prefix)] {
+            if value.is_empty() || value.ends_with('/') || value.contains(['+', '#']) {
+                return Err(ConfigError::invalid(format!("{}.{}", at, field),
+                    "must be a non-empty topic without wildcards or a trailing '/'"));
+            }
+        }
+        if self.qos > 2 {
+            return Err(ConfigError::invalid(format!("{}.qos", at), "must be 0, 1 or 2"));
+        }
+        if self.password.is_some() && self.username.is_none() {
+            return Err(ConfigError::invalid(at, "password is set without a username"));
+        }
+        Ok(())
+    }
+
+    fn secrets_mut(&mut self) -> Vec<(&'static str, &mut Secret)> {
+        self.password.as_mut().map(|password| ("password", password)).into_iter().collect()
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FileConfig {
+    /// Relative to the config file
+    pub dir: PathBuf,
+    #[serde(default)]
+    pub format: FileFormat,
+    /// Start a new file once the current one reaches this size. Files are always rotated daily.
+    pub max_megabytes: Option<u64>,
+    /// Compress rotated files with gzip
+    #[serde(default = "default_true")]
+    pub compress: bool,
+    /// Delete files from more than this many days ago. Without it, files are kept forever.
+    pub retention_days: Option<u32>,
+    #[serde(default)]
+    pub retry: RetryPolicy,
+    #[serde(default)]
+    pub batch: BatchPolicy,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FileFormat {
+    Csv,
+    /// One JSON object per line
+    #[default]
+    Jsonl,
+}
+
+impl FileFormat {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Csv => "csv",
+            Self::Jsonl => "jsonl",
+        }
+    }
+}
+
+impl FileConfig {
+    fn validate(&self, at: &str) -> Result<(), ConfigError> {
+        if self.dir.as_os_str().is_empty() {
+            return Err(ConfigError::invalid(format!("{}.dir", at), "must not be empty"));
+        }
+        if self.max_megabytes == Some(0) {
+            return Err(ConfigError::invalid(format!("{}.max_megabytes", at), "must be at least 1"));
+        }
+        if self.retention_days == Some(0) {
+            return Err(ConfigError::invalid(format!("{}.retention_days", at), "must be at least 1"));
+        }
+        Ok(())
+    }
+}
+
+/// A credential, given inline, read from an environment variable, or read from a file:
+///
+/// ```toml
+/// token = "inline"
+/// token = { env = "INFLUXDB_TOKEN" }
+/// token = { file = "/run/secrets/influxdb_token" }
+/// ```
+///
+/// Files are read relative to the config file, and trailing whitespace is trimmed.
+#[derive(Clone, Deserialize)]
+#[serde(from = "SecretSource")]
+pub struct Secret {
+    source: SecretSource,
+    value: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged, expecting = "expected a string, { env = \"VARIABLE\" } or { file = \"path\" }")]
+enum SecretSource {
+    Inline(String),
+    Env { env: String },
+    File { file: PathBuf },
+}
+
+impl From<SecretSource> for Secret {
+    fn from(source: SecretSource) -> Self {
+        let value = match &source {
+            SecretSource::Inline(value) => value.clone(),
+            _ => String::new(),
+        };
+        Self { source, value }
+    }
+}
+
+impl Secret {
+    pub fn value(&self) -> &str {
+        &self.value
+    }
+
+    fn resolve(&mut self, config_dir: &Path) -> Result<(), String> {
+        match &self.source {
+            SecretSource::Inline(_) => (),
+            SecretSource::Env { env } => {
+                self.value = std::env::var(env)
+                    .map_err(|e| format!("${}: {}", env, e))?;
+            },
+            SecretSource::File { file } => {
+                let path = config_dir.join(file);
+                let contents = std::fs::read_to_string(&path)
+                    .map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+                self.value = contents.trim_end().to_string();
+            },
+        }
+        if self.value.is_empty() {
+            return Err("must not be empty".to_string());
+        }
+        Ok(())
+    }
+
+    /// Where the secret came from, without giving it away.
+    pub fn describe(&self) -> String {
+        match &self.source {
+            SecretSource::Inline(_) => "inline".to_string(),
+            SecretSource::Env { env } => format!("from ${}", env),
+            SecretSource::File { file } => format!("from {}", file.display()),
+        }
+    }
+}
+
+// Keep secrets out of the logs
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Secret({})", self.describe())
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BluetoothConfig {
+    /// Names of the adapters to listen on, e.g. `["hci0"]`. Empty to use every adapter.
+    #[serde(default)]
+    pub adapters: Vec<String>,
+}
+
+/// The bridge's own HTTP server, which serves Prometheus metrics on `/metrics` and the state of
+/// every sensor on `/sensors`. Off unless `listen` is set.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HttpConfig {
+    /// Address and port to listen on, e.g. `"0.0.0.0:9184"`
+    pub listen: Option<SocketAddr>,
+    /// Bearer token which every API request must carry. The dashboard page itself is served
+    /// without it. Without a token, anyone who can reach the server can use it.
+    pub token: Option<Secret>,
+}
+
+/// Local SQLite history of every measurement, which the `history` subcommand reads. Off unless
+/// `path` is set.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HistoryConfig {
+    /// Relative to the config file
+    pub path: Option<PathBuf>,
+}
+
+/// Where the sensor registry is kept, which the `sensors` subcommand edits. Without it, sensors
+/// are only known by their IDs.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RegistryConfig {
+    /// Relative to the config file. Doesn't need to exist until a sensor is added.
+    pub path: Option<PathBuf>,
+}
+
+/// Which devices and sensors to listen to. Everything that passes every filter is accepted.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FilterConfig {
+    /// Devices whose name doesn't start with this are ignored.
+    #[serde(default = "default_name_prefix")]
+    pub name_prefix: String,
+    /// MAC addresses to listen to. Empty to listen to every device.
+    #[serde(default)]
+    pub addresses: Vec<MacAddress>,
+    /// Sensor IDs to accept. Empty to accept every sensor.
+    #[serde(default)]
+    pub sensors: Vec<SensorId>,
+    /// Sensor IDs to drop, e.g. a neighbour's.
+    #[serde(default)]
+    pub ignore_sensors: Vec<SensorId>,
+}
+
+fn default_name_prefix() -> String {
+    "BLE Soil Sensor".to_string()
+}
+
+impl Default for FilterConfig {
+    fn default() -> Self {
+        Self {
+            name_prefix: default_name_prefix(),
+            addresses: Vec::new(),
+            sensors: Vec::new(),
+            ignore_sensors: Vec::new(),
+        }
+    }
+}
+
+impl FilterConfig {
+    pub fn allows_device(&self, address: Address, name: &str) -> bool {
+        name.starts_with(&self.name_prefix)
+            && (self.addresses.is_empty() || self.addresses.iter().any(|a| a.0 == address))
+    }
+
+    pub fn allows_sensor(&self, id: u16) -> bool {
+        (self.sensors.is_empty() || self.sensors.contains(&SensorId(id)))
+            && !self.ignore_sensors.contains(&SensorId(id))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LoggingConfig {
+    /// One of off, error, warn, info, debug or trace. `RUST_LOG` takes precedence, if it is set.
+    #[serde(default = "default_log_level")]
+    pub level: LogLevel,
+    /// Levels for individual modules, e.g. `"soil_sensor_ble_bridge::auth" = "debug"`
+    #[serde(default)]
+    pub modules: BTreeMap<String, LogLevel>,
+}
+
+fn default_log_level() -> LogLevel {
+    LogLevel(LevelFilter::Info)
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        Self { level: default_log_level(), modules: BTreeMap::new() }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AuthConfig {
+    /// Accept payloads without an authentication tag, but only from sensors with no key below.
+    #[serde(default)]
+    pub allow_unauthenticated: bool,
+    /// Provisioned key for each sensor, e.g. `"0123" = "000102030405060708090a0b0c0d0e0f"`
+    #[serde(default)]
+    pub keys: HashMap<SensorId, HexKey>,
+}
+
+/// Calibration from probe frequency to volumetric water content. With neither `points` nor
+/// `polynomial`, the curve is a straight line between the `dry` and `wet` anchors.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CalibrationConfig {
+    pub dry: CalibrationPoint,
+    pub wet: CalibrationPoint,
+    /// Intermediate points of a piecewise-linear curve, ordered from dry to wet
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub points: Vec<CalibrationPoint>,
+    /// Polynomial coefficients, in increasing order of power
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub polynomial: Option<Vec<f32>>,
+}
+
+impl CalibrationConfig {
+    pub fn calibration(&self) -> Calibration<'_> {
+        let curve = match &self.polynomial {
+            Some(coefficients) => Curve::Polynomial(coefficients),
+            None if self.points.is_empty() => Curve::Linear,
+            None => Curve::PiecewiseLinear(&self.points),
+        };
+        Calibration { dry: self.dry, wet: self.wet, curve }
+    }
+
+    pub fn validate(&self, id: SensorId) -> Result<(), ConfigError> {
+        if self.polynomial.is_some() && !self.points.is_empty() {
+            return Err(ConfigError::CalibrationCurve(id));
+        }
+        self.calibration().validate().map_err(|e| ConfigError::Calibration(id, e))
+    }
+}
+
+impl Config {
+    /// Reads the config, fetches any secrets it refers to, and checks that it makes sense.
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        let contents = std::fs::read_to_string(path)?;
+        let mut config: Self = toml::from_str(&contents)?;
+        let config_dir = path.parent().unwrap_or(Path::new("."));
+        config.resolve_secrets(config_dir)?;
+        if let Some(dir) = &mut config.spool.dir {
+            *dir = config_dir.join(&*dir);
+        }
+        if let Some(path) = &mut config.history.path {
+            *path = config_dir.join(&*path);
+        }
+        for sink in config.sinks.values_mut() {
+            if let SinkConfig::File(file) = sink {
+                file.dir = config_dir.join(&file.dir);
+            }
+        }
+        if let Some(path) = &mut config.registry.path {
+            *path = config_dir.join(&*path);
+            config.sensors = Registry::load(path)?;
+        }
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn resolve_secrets(&mut self, config_dir: &Path) -> Result<(), ConfigError> {
+        for (name, sink) in &mut self.sinks {
+            let secrets = match sink {
+                SinkConfig::Influxdb(influxdb) => influxdb.secrets_mut(),
+                SinkConfig::Influxdb2(influxdb) => influxdb.secrets_mut(),
+                SinkConfig::Mqtt(mqtt) => mqtt.secrets_mut(),
+                SinkConfig::File(_) => Vec::new(),
+            };
+            for (field, secret) in secrets {
+                secret.resolve(config_dir)
+                    .map_err(|reason| ConfigError::invalid(format!("sinks.{}.{}", name, field), reason))?;
+            }
+        }
+        if let Some(token) = &mut self.http.token {
+            token.resolve(config_dir).map_err(|reason| ConfigError::invalid("http.token", reason))?;
+        }
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        for (name, sink) in &self.sinks {
+            let at = format!("sinks.{}", name);
+            // Names are used for spool directories, so keep them to safe characters
+            if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+                return Err(ConfigError::invalid(at, "sink names may only contain letters, digits, '_' and '-'"));
+            }
+            match sink {
+                SinkConfig::Influxdb(influxdb) => influxdb.validate(&at)?,
+                SinkConfig::Influxdb2(influxdb) => influxdb.validate(&at)?,
+                SinkConfig::Mqtt(mqtt) => mqtt.validate(&at)?,
+                SinkConfig::File(file) => file.validate(&at)?,
+            }
+            sink.retry().validate(&format!("{}.retry", at))?;
+            sink.batch().validate(&format!("{}.batch", at))?;
+        }
+        if self.spool.max_megabytes == 0 || self.spool.max_age_hours == 0 {
+            return Err(ConfigError::invalid("spool", "max_megabytes and max_age_hours must be at least 1"));
+        }
+        if let Some(id) = self.filter.sensors.iter().find(|id| self.filter.ignore_sensors.contains(id)) {
+            return Err(ConfigError::invalid("filter", format!("sensor {} is both accepted and ignored", id)));
+        }
+        if let Some(adapter) = self.bluetooth.adapters.iter().find(|a| a.is_empty()) {
+            return Err(ConfigError::invalid("bluetooth.adapters", format!("adapter name \"{}\" is empty", adapter)));
+        }
+
+        for (id, calibration) in &self.calibration {
+            calibration.validate(*id)?;
+            if self.sensors.calibration(*id).is_some() {
+                return Err(ConfigError::CalibrationConflict(*id));
+            }
+        }
+        Ok(())
+    }
+
+    /// Volumetric water content for a measurement, if its sensor has a calibration.
+    /// `None` if the sensor has no calibration, or flagged its moisture reading as invalid.
+    pub fn moisture_vwc(&self, measurement: &Measurement) -> Option<f32> {
+        if !measurement.status.moisture_valid() {
+            return None;
+        }
+        let id = SensorId(measurement.id);
+        let hz = match self.compensation.get(&id) {
+            Some(compensation) => measurement.compensated_moisture_hz(compensation),
+            None => measurement.moisture_hz(),
+        };
+        self.calibration.get(&id)
+            .or_else(|| self.sensors.calibration(id))
+            .map(|calibration| calibration.calibration().vwc(hz))
+    }
+}
+
+/// A sensor ID, written as 4 hex digits just like `SENSOR_ID` in the firmware.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct SensorId(pub u16);
+
+impl FromStr for SensorId {
+    type Err = String;
+
+    fn from_str(string: &str) -> Result<Self, Self::Err> {
+        if string.len() != 4 {
+            return Err(format!("sensor ID \"{}\" is not 4 hex digits", string));
+        }
+        u16::from_str_radix(string, 16)
+            .map(SensorId)
+            .map_err(|_| format!("sensor ID \"{}\" is not a valid hex number", string))
+    }
+}
+
+impl<'de> Deserialize<'de> for SensorId {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?.parse().map_err(D::Error::custom)
+    }
+}
+
+impl Serialize for SensorId {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl fmt::Display for SensorId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:04X}", self.0)
+    }
+}
+
+/// A 128-bit AES key, written as 32 hex digits just like `SENSOR_KEY` in the firmware.
+#[derive(Copy, Clone, Eq, PartialEq)]
+pub struct HexKey(pub Key);
+
+impl<'de> Deserialize<'de> for HexKey {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let string = String::deserialize(deserializer)?;
+        if string.len() != KEY_LEN * 2 || !string.is_ascii() {
+            return Err(D::Error::custom(format!("key is not {} hex digits", KEY_LEN * 2)));
+        }
+        let mut key: Key = [0; KEY_LEN];
+        for (i, byte) in key.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&string[2 * i..2 * i + 2], 16)
+                .map_err(|_| D::Error::custom("key is not a valid hex number"))?;
+        }
+        Ok(HexKey(key))
+    }
+}
+
+/// A MAC address, like `"AA:BB:CC:DD:EE:FF"`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct MacAddress(pub Address);
+
+impl<'de> Deserialize<'de> for MacAddress {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let string = String::deserialize(deserializer)?;
+        string.parse()
+            .map(MacAddress)
+            .map_err(|_| D::Error::custom(format!("\"{}\" is not a MAC address", string)))
+    }
+}
+
+impl Serialize for MacAddress {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.0.to_string().to_lowercase())
+    }
+}
+
+/// A log level, like `RUST_LOG` takes.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct LogLevel(pub LevelFilter);
+
+impl<'de> Deserialize<'de> for LogLevel {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let string = String::deserialize(deserializer)?;
+        string.parse()
+            .map(LogLevel)
+            .map_err(|_| D::Error::custom(format!("\"{}\" is not a log level", string)))
+    }
+}
+
+// Keep keys out of the logs
+impl fmt::Debug for HexKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "HexKey(..)")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use crate::testing::TemporaryDir;
+    use super::*;
+
+    const INFLUXDB2: &str = r#"
+        type = "influxdb2"
+        url = "http://localhost:8086"
+        org = "home"
+        bucket = "soil"
+        token = "inline-token"
+    "#;
+
+    fn load(dir: &TemporaryDir, contents: &str) -> Result<Config, ConfigError> {
+        let path = dir.join("config.toml");
+        fs::write(&path, contents).unwrap();
+        Config::load(&path)
+    }
+
+    /// Where a config which is expected to be invalid goes wrong.
+    fn invalid_at(contents: &str) -> String {
+        let dir = TemporaryDir::new("config");
+        match load(&dir, contents) {
+            Err(ConfigError::Invalid { at, .. }) => at,
+            result => panic!("expected an invalid config, got {:?} from:\n{}", result.map(|_| ()), contents),
+        }
+    }
+
+    #[test]
+    fn example_config_loads() {
+        let config = Config::load(Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/config.example.toml"))).unwrap();
+        assert!(config.sinks.is_empty());
+        assert!(!config.auth.allow_unauthenticated);
+        assert_eq!(config.spool.max_megabytes, 100);
+        assert_eq!(config.spool.max_age_hours, 720);
+        assert_eq!(config.filter.name_prefix, "BLE Soil Sensor");
+        assert!(config.http.listen.is_none());
+    }
+
+    #[test]
+    fn secrets_are_resolved() {
+        let dir = TemporaryDir::new("config");
+        fs::create_dir(dir.join("secrets")).unwrap();
+        fs::write(dir.join("secrets/password"), "from-file\n").unwrap();
+        std::env::set_var("SOIL_SENSOR_BRIDGE_TEST_MQTT_PASSWORD", "from-env");
+        let config = load(&dir, &format!(r#"
+            [sinks.influxdb2]
+            {}
+
+            [sinks.influxdb]
+            type = "influxdb"
+            url = "http://localhost:8086"
+            database = "soil"
+            username = "bridge"
+            password = {{ file = "secrets/password" }}
+
+            [sinks.mqtt]
+            type = "mqtt"
+            host = "localhost"
+            username = "bridge"
+            password = {{ env = "SOIL_SENSOR_BRIDGE_TEST_MQTT_PASSWORD" }}
+        "#, INFLUXDB2)).unwrap();
+
+        let SinkConfig::Influxdb2(influxdb2) = &config.sinks["influxdb2"] else { panic!() };
+        assert_eq!(influxdb2.token.value(), "inline-token");
+        assert_eq!(influxdb2.token.describe(), "inline");
+        let SinkConfig::Influxdb(influxdb) = &config.sinks["influxdb"] else { panic!() };
+        let password = influxdb.password.as_ref().unwrap();
+        assert_eq!(password.value(), "from-file");
+        assert_eq!(password.describe(), "from secrets/password");
+        let SinkConfig::Mqtt(mqtt) = &config.sinks["mqtt"] else { panic!() };
+        let password = mqtt.password.as_ref().unwrap();
+        assert_eq!(password.value(), "from-env");
+        // Only where it came from is logged
+        assert_eq!(format!("{:?}", password), "Secret(from $SOIL_SENSOR_BRIDGE_TEST_MQTT_PASSWORD)");
+    }
+
+    #[test]
+    fn missing_secrets_are_invalid() {
+        let sink = |token: &str| format!(r#"
+            [sinks.influx]
+            type = "influxdb2"
+            url = "http://localhost:8086"
+            org = "home"
+            bucket = "soil"
+            token = {}
+        "#, token);
+        assert_eq!(invalid_at(&sink(r#"{ env = "SOIL_SENSOR_BRIDGE_TEST_UNSET" }"#)), "sinks.influx.token");
+        assert_eq!(invalid_at(&sink(r#"{ file = "missing" }"#)), "sinks.influx.token");
+        assert_eq!(invalid_at(&sink(r#""""#)), "sinks.influx.token");
+        assert_eq!(invalid_at("[http]\ntoken = { file = \"missing\" }"), "http.token");
+
+        let dir = TemporaryDir::new("config");
+        fs::write(dir.join("blank"), " \n").unwrap();
+        match load(&dir, &sink(r#"{ file = "blank" }"#)) {
+            Err(ConfigError::Invalid { at, reason }) => {
+                assert_eq!(at, "sinks.influx.token");
+                assert_eq!(reason, "must not be empty");
+            },
+            result => panic!("expected an invalid config, got {:?}", result.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn relative_paths_are_resolved() {
+        let dir = TemporaryDir::new("config");
+        let config = load(&dir, r#"
+            [spool]
+            dir = "spool"
+
+            [history]
+            path = "history.db"
+
+            [sinks.files]
+            type = "file"
+            dir = "measurements"
+        "#).unwrap();
+        assert_eq!(config.spool.dir, Some(dir.join("spool")));
+        assert_eq!(config.history.path, Some(dir.join("history.db")));
+        let SinkConfig::File(file) = &config.sinks["files"] else { panic!() };
+        assert_eq!(file.dir, dir.join("measurements"));
+    }
+
+    #[test]
+    fn invalid_sinks() {
+        let influxdb2 = |extra: &str| format!("[sinks.influx]\n{}\n{}", INFLUXDB2, extra);
+        assert_eq!(invalid_at(&format!("[sinks.\"in flux\"]\n{}", INFLUXDB2)), "sinks.in flux");
+        assert_eq!(invalid_at(&influxdb2("tags = [\"colour\"]")), "sinks.influx.tags");
+        assert_eq!(invalid_at(&influxdb2("tags = [\"id\"]\nfields = [\"id\"]")), "sinks.influx");
+        assert_eq!(invalid_at(&influxdb2("fields = []")), "sinks.influx.fields");
+        assert_eq!(invalid_at(&influxdb2("rename = { id = \"\" }")), "sinks.influx.rename.id");
+        assert_eq!(invalid_at(&influxdb2("measurement = \"\"")), "sinks.influx.measurement");
+        assert_eq!(invalid_at(&format!("[sinks.influx]\n{}", INFLUXDB2.replace("http://", "ftp://"))), "sinks.influx.url");
+
+        assert_eq!(invalid_at(r#"
+            [sinks.influx]
+            type = "influxdb"
+            url = "http://localhost:8086"
+            database = "soil"
+            token = "token"
+            username = "bridge"
+            password = "password"
+        "#), "sinks.influx");
+        assert_eq!(invalid_at(r#"
+            [sinks.influx]
+            type = "influxdb"
+            url = "http://localhost:8086"
+            database = "soil"
+            username = "bridge"
+        "#), "sinks.influx");
+
+        let mqtt = |extra: &str| format!("[sinks.mqtt]\ntype = \"mqtt\"\nhost = \"localhost\"\n{}", extra);
+        assert_eq!(invalid_at(&mqtt("qos = 3")), "sinks.mqtt.qos");
+        assert_eq!(invalid_at(&mqtt("topic_prefix = \"soil/#\"")), "sinks.mqtt.topic_prefix");
+        assert_eq!(invalid_at(&mqtt("discovery_prefix = \"homeassistant/\"")), "sinks.mqtt.discovery_prefix");
+        assert_eq!(invalid_at(&mqtt("password = \"password\"")), "sinks.mqtt");
+
+        let file = |extra: &str| format!("[sinks.files]\ntype = \"file\"\ndir = \"measurements\"\n{}", extra);
+        assert_eq!(invalid_at(&file("max_megabytes = 0")), "sinks.files.max_megabytes");
+        assert_eq!(invalid_at(&file("retention_days = 0")), "sinks.files.retention_days");
+    }
+
+    #[test]
+    fn invalid_batch_and_retry_policies() {
+        let influxdb2 = |extra: &str| format!("[sinks.influx]\n{}\n{}", INFLUXDB2, extra);
+        assert_eq!(invalid_at(&influxdb2("batch = { max_points = 0 }")), "sinks.influx.batch.max_points");
+        assert_eq!(invalid_at(&influxdb2("batch = { max_points = 100, queue_len = 10 }")), "sinks.influx.batch");
+        assert_eq!(invalid_at(&influxdb2("retry = { attempts = 0 }")), "sinks.influx.retry.attempts");
+        assert_eq!(invalid_at(&influxdb2("retry = { initial_backoff_secs = 10, max_backoff_secs = 5 }")),
+            "sinks.influx.retry");
+        assert_eq!(invalid_at("[spool]\nmax_megabytes = 0"), "spool");
+    }
+
+    #[test]
+    fn invalid_auth_and_filters() {
+        let dir = TemporaryDir::new("config");
+        let keys = load(&dir, "[auth.keys]\n\"0123\" = \"000102030405060708090a0b0c0d0e0f\"").unwrap().auth.keys;
+        assert_eq!(keys[&SensorId(0x0123)].0[15], 0x0f);
+
+        for key in ["000102030405060708090a0b0c0d0e", "000102030405060708090a0b0c0d0e0g", "000102030405060708090a0b0c0d0é"] {
+            let result = load(&dir, &format!("[auth.keys]\n\"0123\" = \"{}\"", key));
+            assert!(matches!(result, Err(ConfigError::Parse(_))), "{} was accepted", key);
+        }
+        assert!(matches!(load(&dir, "[auth.keys]\n\"123\" = \"000102030405060708090a0b0c0d0e0f\""),
+            Err(ConfigError::Parse(_))));
+        assert!(matches!(load(&dir, "[auth]\nallow_unauthenticated = \"yes\""), Err(ConfigError::Parse(_))));
+
+        assert_eq!(invalid_at("[filter]\nsensors = [\"0123\"]\nignore_sensors = [\"0123\"]"), "filter");
+        assert_eq!(invalid_at("[bluetooth]\nadapters = [\"\"]"), "bluetooth.adapters");
+    }
+}
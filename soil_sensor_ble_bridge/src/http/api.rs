@@ -0,0 +1,122 @@
+//! JSON API for the current state of each sensor, and its history if there is a local store.
+//!
+//! - `GET /sensors`: every sensor heard since the bridge started, ordered by ID
+//! - `GET /sensors/{id}`: a single sensor
+//! - `GET /sensors/{id}/history?since=&until=&limit=`: its stored measurements, oldest first.
+//!   Times are RFC 3339, a date, or a time ago like `6h`, just like the `history` subcommand.
+
+use axum::extract::{Path, Query, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use soil_sensor_common::web::InfluxDBMeasurement;
+use crate::config::SensorId;
+use crate::stats::SensorStats;
+use crate::store::{self, Row};
+use super::AppState;
+
+/// Measurements returned by the history endpoint when no limit is given.
+const DEFAULT_HISTORY_LIMIT: u32 = 1000;
+
+#[derive(Debug, Serialize)]
+pub struct SensorState {
+    /// 4 hex digits, like `SENSOR_ID` in the firmware
+    pub id: String,
+    /// The device the newest measurement was heard through
+    pub mac_address: Option<String>,
+    /// When the newest measurement was taken
+    pub last_seen: Option<DateTime<Local>>,
+    /// Signal strength of the device, as heard by the bridge
+    pub rssi: Option<i16>,
+    pub loss_rate: f32,
+    pub received: u64,
+    pub missed: u64,
+    pub duplicates: u64,
+    pub out_of_order: u64,
+    pub backfilled: u64,
+    pub reboots: u64,
+    pub last_reboot: Option<DateTime<Local>>,
+    /// Flags raised by the newest live measurement
+    pub status: Vec<&'static str>,
+    pub latest: Option<InfluxDBMeasurement>,
+}
+
+impl SensorState {
+    fn new(state: &AppState, id: u16, sensor: SensorStats) -> Self {
+        Self {
+            id: format!("{:04X}", id),
+            mac_address: sensor.address.map(|addr| addr.to_string().to_lowercase()),
+            last_seen: sensor.latest.as_ref().map(|latest| latest.time),
+            rssi: sensor.address.and_then(|addr| state.stats.rssi(addr)),
+            loss_rate: sensor.loss_rate(),
+            received: sensor.received,
+            missed: sensor.missed,
+            duplicates: sensor.duplicates,
+            out_of_order: sensor.out_of_order,
+            backfilled: sensor.backfilled,
+            reboots: sensor.reboots,
+            last_reboot: sensor.last_reboot,
+            status: sensor.status.names().collect(),
+            latest: sensor.latest,
+        }
+    }
+}
+
+/// An error, as `{"error": "..."}`.
+pub struct ApiError(StatusCode, String);
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.0, Json(json!({ "error": self.1 }))).into_response()
+    }
+}
+
+pub async fn sensors(State(state): State<AppState>) -> Json<Vec<SensorState>> {
+    let sensors = state.stats.sensors().into_iter()
+        .map(|(id, sensor)| SensorState::new(&state, id, sensor))
+        .collect();
+    Json(sensors)
+}
+
+pub async fn sensor(State(state): State<AppState>, Path(id): Path<SensorId>) -> Result<Json<SensorState>, ApiError> {
+    let sensor = state.stats.sensor(id.0)
+        .ok_or_else(|| ApiError(StatusCode::NOT_FOUND, format!("sensor {} hasn't been heard from", id)))?;
+    Ok(Json(SensorState::new(&state, id.0, sensor)))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct HistoryParams {
+    since: Option<String>,
+    until: Option<String>,
+    limit: Option<u32>,
+}
+
+pub async fn history(
+    State(state): State<AppState>,
+    Path(id): Path<SensorId>,
+    Query(params): Query<HistoryParams>,
+) -> Result<Json<Vec<Row>>, ApiError> {
+    let Some(path) = state.history.clone() else {
+        return Err(ApiError(StatusCode::NOT_FOUND, "no history store is configured".to_string()));
+    };
+    let parse = |time: Option<String>| time
+        .map(|time| store::parse_time(&time))
+        .transpose()
+        .map_err(|e| ApiError(StatusCode::BAD_REQUEST, e));
+    let query = store::Query {
+        sensor_id: Some(id.0),
+        mac_address: None,
+        since: parse(params.since)?,
+        until: parse(params.until)?,
+        limit: Some(params.limit.unwrap_or(DEFAULT_HISTORY_LIMIT)),
+    };
+    // SQLite blocks, so keep it off the runtime's threads
+    tokio::task::spawn_blocking(move || store::query(&path, &query))
+        .await
+        .map_err(|e| ApiError(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .map(Json)
+        .map_err(|e| ApiError(StatusCode::INTERNAL_SERVER_ERROR, format!("failed to read history: {}", e)))
+}
@@ -0,0 +1,91 @@
+//! Every measurement as it arrives, as Server-Sent Events on `/live` or WebSocket text messages on
+//! `/live/ws`. Each one is the JSON of the measurement, just as it is logged. Both take an
+//! optional `sensor` parameter to only receive measurements from one sensor, e.g.
+//! `/live?sensor=0123`.
+//!
+//! Subscribers which fall too far behind skip the measurements they missed.
+
+use std::convert::Infallible;
+use axum::extract::{Query, State, WebSocketUpgrade};
+use axum::extract::ws::{Message, WebSocket};
+use axum::response::{IntoResponse, Response};
+use axum::response::sse::{Event, KeepAlive, Sse};
+use futures::Stream;
+use log::{debug, warn};
+use serde::Deserialize;
+use soil_sensor_common::web::InfluxDBMeasurement;
+use tokio::sync::broadcast::{self, error::RecvError};
+use crate::config::SensorId;
+use super::AppState;
+
+/// Measurements which can wait for the slowest subscriber.
+pub const BUFFER: usize = 100;
+
+#[derive(Debug, Deserialize)]
+pub struct LiveParams {
+    sensor: Option<SensorId>,
+}
+
+/// Waits for the next measurement from the sensor, or from any sensor. `None` once the bridge
+/// stops publishing.
+async fn next(receiver: &mut broadcast::Receiver<InfluxDBMeasurement>, sensor: Option<SensorId>) -> Option<InfluxDBMeasurement> {
+    loop {
+        match receiver.recv().await {
+            Ok(measurement) if sensor.is_none_or(|sensor| sensor.0 == measurement.id) => return Some(measurement),
+            Ok(_) => (),
+            Err(RecvError::Lagged(skipped)) => warn!("Live subscriber fell behind, skipping {} measurements", skipped),
+            Err(RecvError::Closed) => return None,
+        }
+    }
+}
+
+pub async fn events(
+    State(state): State<AppState>,
+    Query(params): Query<LiveParams>,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let receiver = state.live.subscribe();
+    let stream = futures::stream::unfold(receiver, move |mut receiver| async move {
+        let measurement = next(&mut receiver, params.sensor).await?;
+        let event = Event::default().json_data(&measurement).unwrap_or_else(|e| Event::default().comment(e.to_string()));
+        Some((Ok(event), receiver))
+    });
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
+
+pub async fn websocket(
+    State(state): State<AppState>,
+    Query(params): Query<LiveParams>,
+    upgrade: WebSocketUpgrade,
+) -> Response {
+    let receiver = state.live.subscribe();
+    upgrade.on_upgrade(move |socket| forward(socket, receiver, params.sensor)).into_response()
+}
+
+async fn forward(mut socket: WebSocket, mut receiver: broadcast::Receiver<InfluxDBMeasurement>, sensor: Option<SensorId>) {
+    loop {
+        tokio::select! {
+            measurement = next(&mut receiver, sensor) => {
+                let Some(measurement) = measurement else {
+                    break;
+                };
+                let json = match serde_json::to_string(&measurement) {
+                    Ok(json) => json,
+                    Err(e) => {
+                        warn!("Failed to serialize measurement: {}", e);
+                        continue;
+                    },
+                };
+                if socket.send(Message::Text(json.into())).await.is_err() {
+                    break;
+                }
+            },
+            // Clients have nothing to say, but reading notices when they go away. Pings are
+            // answered by axum.
+            message = socket.recv() => match message {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => (),
+            },
+        }
+    }
+    debug!("Live WebSocket subscriber went away");
+}
@@ -0,0 +1,147 @@
+//! Prometheus metrics, in the text exposition format.
+//!
+//! Per-sensor gauges come from the newest measurement of each sensor, and are labelled with its
+//! ID and the MAC address it was last heard from. Everything else is counters kept by the bridge.
+
+use std::fmt::{Display, Write};
+use axum::extract::State;
+use axum::http::header::CONTENT_TYPE;
+use axum::response::IntoResponse;
+use soil_sensor_common::units::MOISTURE_GATE_SECONDS;
+use soil_sensor_common::web::InfluxDBMeasurement;
+use crate::sink::{Health, SinkStatus};
+use crate::stats::{DeviceStats, SensorStats};
+use super::AppState;
+
+const CONTENT_TYPE_TEXT: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// Name, help text, and how to get the value of a metric from `T`.
+type Family<T, V> = (&'static str, &'static str, fn(&T) -> V);
+
+pub async fn metrics(State(state): State<AppState>) -> impl IntoResponse {
+    ([(CONTENT_TYPE, CONTENT_TYPE_TEXT)], render(&state))
+}
+
+fn render(state: &AppState) -> String {
+    let mut out = Exposition::default();
+    let sensors = state.stats.sensors();
+    let devices = state.stats.devices();
+
+    let latest: Vec<_> = sensors.iter()
+        .filter_map(|(id, sensor)| Some((format!("{:04X}", id), sensor.latest.as_ref()?, sensor.address)))
+        .collect();
+    let gauges: [Family<InfluxDBMeasurement, Option<f64>>; 7] = [
+        // moisture_level is the count over the gate window, which only happens to be 1 second
+        ("soil_sensor_moisture_hz", "Moisture probe frequency",
+            |m| Some(f64::from(m.moisture_level) / f64::from(MOISTURE_GATE_SECONDS))),
+        ("soil_sensor_moisture_vwc_percent", "Volumetric water content, for calibrated sensors",
+            |m| m.moisture_vwc.map(f64::from)),
+        ("soil_sensor_temperature_celsius", "Temperature",
+            |m| m.temperature.map(f64::from)),
+        ("soil_sensor_capacitor_volts", "Voltage of the sensor's storage capacitor",
+            |m| Some(m.capacitor_voltage.into())),
+        ("soil_sensor_sequence", "Sequence number of the newest measurement",
+            |m| Some(m.sequence.into())),
+        ("soil_sensor_last_seen_timestamp_seconds", "When the newest measurement was taken",
+            |m| Some(m.time.timestamp_millis() as f64 / 1000.0)),
+        ("soil_sensor_reported_rssi_dbm", "Signal strength of the bridge, as heard by the sensor",
+            |m| m.sensor_rssi.map(f64::from)),
+    ];
+    for (name, help, value) in gauges {
+        out.family(name, "gauge", help);
+        for (id, measurement, _) in &latest {
+            if let Some(value) = value(measurement) {
+                out.sample(name, &[("id", id), ("mac", &measurement.mac_address)], value);
+            }
+        }
+    }
+    out.family("soil_sensor_rssi_dbm", "gauge", "Signal strength of the sensor, as heard by the bridge");
+    for (id, measurement, address) in &latest {
+        let rssi = devices.iter().find(|(addr, _)| Some(*addr) == *address).and_then(|(_, device)| device.rssi);
+        if let Some(rssi) = rssi {
+            out.sample("soil_sensor_rssi_dbm", &[("id", id), ("mac", &measurement.mac_address)], rssi);
+        }
+    }
+
+    let counters: [Family<SensorStats, u64>; 6] = [
+        ("soil_sensor_received_total", "Measurements received, including backfilled ones", |s| s.received),
+        ("soil_sensor_missed_total", "Measurements which were skipped over, and never turned up", |s| s.missed),
+        ("soil_sensor_duplicates_total", "Measurements which had already been received", |s| s.duplicates),
+        ("soil_sensor_out_of_order_total", "Measurements which arrived after newer ones", |s| s.out_of_order),
+        ("soil_sensor_backfilled_total", "Measurements which were buffered by the sensor, and sent later", |s| s.backfilled),
+        ("soil_sensor_reboots_total", "Sensor resets seen", |s| s.reboots),
+    ];
+    for (name, help, value) in counters {
+        out.family(name, "counter", help);
+        for (id, sensor) in &sensors {
+            out.sample(name, &[("id", &format!("{:04X}", id))], value(sensor));
+        }
+    }
+
+    let counters: [Family<DeviceStats, u64>; 4] = [
+        ("soil_sensor_bridge_advertisements_total", "Advertisements with soil sensor data", |d| d.advertisements),
+        ("soil_sensor_bridge_decoded_total", "Payloads which decoded", |d| d.decoded),
+        ("soil_sensor_bridge_decode_errors_total", "Payloads which failed to decode", |d| d.decode_errors),
+        ("soil_sensor_bridge_rejected_total", "Payloads which failed authentication or replay checks", |d| d.rejected),
+    ];
+    for (name, help, value) in counters {
+        out.family(name, "counter", help);
+        for (addr, device) in &devices {
+            out.sample(name, &[("mac", &addr.to_string().to_lowercase())], value(device));
+        }
+    }
+
+    let sinks = state.sinks.health();
+    let counters: [Family<SinkStatus, u64>; 4] = [
+        ("soil_sensor_bridge_sink_written_total", "Measurements written to the sink", |s| s.written),
+        ("soil_sensor_bridge_sink_dropped_total", "Measurements which were given up on", |s| s.dropped),
+        ("soil_sensor_bridge_sink_failed_writes_total", "Writes to the sink which failed", |s| s.failed_writes),
+        ("soil_sensor_bridge_sink_spooled_total", "Measurements written to the spool", |s| s.spooled),
+    ];
+    for (name, help, value) in counters {
+        out.family(name, "counter", help);
+        for (sink, _, status) in &sinks {
+            out.sample(name, &[("sink", sink)], value(status));
+        }
+    }
+    out.family("soil_sensor_bridge_sink_backlog_measurements", "gauge", "Measurements waiting in the spool");
+    for (sink, _, status) in &sinks {
+        out.sample("soil_sensor_bridge_sink_backlog_measurements", &[("sink", sink)], status.backlog.measurements);
+    }
+    out.family("soil_sensor_bridge_sink_backlog_bytes", "gauge", "Size of the spool");
+    for (sink, _, status) in &sinks {
+        out.sample("soil_sensor_bridge_sink_backlog_bytes", &[("sink", sink)], status.backlog.bytes);
+    }
+    out.family("soil_sensor_bridge_sink_healthy", "gauge", "1 if the sink is healthy, 0.5 if degraded, 0 if not");
+    for (sink, health, _) in &sinks {
+        let value = match health {
+            Health::Healthy => 1.0,
+            Health::Degraded(_) => 0.5,
+            Health::Unhealthy(_) => 0.0,
+        };
+        out.sample("soil_sensor_bridge_sink_healthy", &[("sink", sink)], value);
+    }
+
+    out.0
+}
+
+#[derive(Default)]
+struct Exposition(String);
+
+impl Exposition {
+    fn family(&mut self, name: &str, kind: &str, help: &str) {
+        let _ = writeln!(self.0, "# HELP {} {}", name, help);
+        let _ = writeln!(self.0, "# TYPE {} {}", name, kind);
+    }
+
+    fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: impl Display) {
+        let labels: Vec<_> = labels.iter()
+            .map(|(label, value)| format!("{}=\"{}\"", label, escape(value)))
+            .collect();
+        let _ = writeln!(self.0, "{}{{{}}} {}", name, labels.join(","), value);
+    }
+}
+
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
@@ -0,0 +1,169 @@
+//! The bridge's HTTP server.
+
+mod api;
+mod live;
+mod metrics;
+
+use std::path::PathBuf;
+use std::sync::Arc;
+use axum::Router;
+use axum::extract::{Query, Request, State};
+use axum::http::StatusCode;
+use axum::http::header::{AUTHORIZATION, WWW_AUTHENTICATE};
+use axum::middleware::{self, Next};
+use axum::response::{Html, IntoResponse, Response};
+use axum::routing::get;
+use serde::Deserialize;
+use soil_sensor_common::web::InfluxDBMeasurement;
+use tokio::net::TcpListener;
+use tokio::sync::broadcast;
+use crate::sink::Dispatcher;
+use crate::stats::Stats;
+
+pub use self::live::BUFFER as LIVE_BUFFER;
+
+/// Everything the handlers read from.
+#[derive(Clone)]
+pub struct AppState {
+    pub stats: Stats,
+    pub sinks: Arc<Dispatcher>,
+    /// The local history store, if there is one
+    pub history: Option<PathBuf>,
+    /// Every measurement, as it is received
+    pub live: broadcast::Sender<InfluxDBMeasurement>,
+    /// Bearer token which requests must carry, if any
+    pub token: Option<Arc<str>>,
+}
+
+fn router(state: AppState) -> Router {
+    Router::new()
+        .route("/metrics", get(metrics::metrics))
+        .route("/sensors", get(api::sensors))
+        .route("/sensors/{id}", get(api::sensor))
+        .route("/sensors/{id}/history", get(api::history))
+        .route("/live", get(live::events))
+        .route("/live/ws", get(live::websocket))
+        .layer(middleware::from_fn_with_state(state.clone(), authenticate))
+        // The page itself holds no data, so it is served without the token, and asks for it
+        .route("/", get(dashboard))
+        .with_state(state)
+}
+
+/// A dashboard of every sensor, built on the API, with no dependencies outside the bridge.
+async fn dashboard() -> Html<&'static str> {
+    Html(include_str!("dashboard.html"))
+}
+
+/// Turns away requests without the configured bearer token. Browsers can't set headers on
+/// `EventSource` or `WebSocket` requests, so the token can also be given as an `access_token`
+/// query parameter.
+async fn authenticate(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    if let Some(token) = &state.token {
+        let given = request.headers().get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .map(str::to_string)
+            .or_else(|| Query::<TokenParams>::try_from_uri(request.uri()).ok()?.0.access_token);
+        let given = given.as_deref();
+        if !given.is_some_and(|given| constant_time_eq(given.as_bytes(), token.as_bytes())) {
+            return (StatusCode::UNAUTHORIZED, [(WWW_AUTHENTICATE, "Bearer")]).into_response();
+        }
+    }
+    next.run(request).await
+}
+
+#[derive(Deserialize)]
+struct TokenParams {
+    access_token: Option<String>,
+}
+
+/// Compares without returning early, so that response times don't give away how much of a
+/// guessed token was right.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |difference, (a, b)| difference | (a ^ b)) == 0
+}
+
+/// Serves requests until the listener fails.
+pub async fn serve(listener: TcpListener, state: AppState) -> std::io::Result<()> {
+    axum::serve(listener, router(state)).await
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::Client;
+    use super::*;
+
+    /// Starts a server on a free port, and returns its URL.
+    async fn start(token: Option<&str>) -> String {
+        let state = AppState {
+            stats: Stats::default(),
+            sinks: Arc::new(Dispatcher::new(Vec::new())),
+            history: None,
+            live: broadcast::channel(LIVE_BUFFER).0,
+            token: token.map(Arc::from),
+        };
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(serve(listener, state));
+        url
+    }
+
+    async fn get(url: &str, authorization: Option<&str>) -> StatusCode {
+        let mut request = Client::new().get(url);
+        if let Some(authorization) = authorization {
+            request = request.header(AUTHORIZATION.as_str(), authorization);
+        }
+        let status = request.send().await.unwrap().status().as_u16();
+        StatusCode::from_u16(status).unwrap()
+    }
+
+    #[tokio::test]
+    async fn api_requires_the_token() {
+        let url = start(Some("s3cret")).await;
+        for path in ["/metrics", "/sensors", "/sensors/0123", "/live"] {
+            let endpoint = format!("{}{}", url, path);
+            assert_eq!(get(&endpoint, None).await, StatusCode::UNAUTHORIZED, "{}", path);
+            assert_eq!(get(&endpoint, Some("Bearer wrong")).await, StatusCode::UNAUTHORIZED, "{}", path);
+            // A prefix of the token, or the token without the scheme, isn't enough
+            assert_eq!(get(&endpoint, Some("Bearer s3cre")).await, StatusCode::UNAUTHORIZED, "{}", path);
+            assert_eq!(get(&endpoint, Some("s3cret")).await, StatusCode::UNAUTHORIZED, "{}", path);
+            assert_eq!(get(&format!("{}?access_token=wrong", endpoint), None).await, StatusCode::UNAUTHORIZED, "{}", path);
+            assert_eq!(get(&format!("{}?access_token=", endpoint), None).await, StatusCode::UNAUTHORIZED, "{}", path);
+        }
+
+        let response = Client::new().get(format!("{}/sensors", url)).send().await.unwrap();
+        assert_eq!(response.headers()[WWW_AUTHENTICATE.as_str()], "Bearer");
+
+        assert_eq!(get(&format!("{}/metrics", url), Some("Bearer s3cret")).await, StatusCode::OK);
+        assert_eq!(get(&format!("{}/sensors", url), Some("Bearer s3cret")).await, StatusCode::OK);
+        assert_eq!(get(&format!("{}/sensors?access_token=s3cret", url), None).await, StatusCode::OK);
+        // Past authentication, to the handler, which knows no such sensor
+        assert_eq!(get(&format!("{}/sensors/0123?access_token=s3cret", url), None).await, StatusCode::NOT_FOUND);
+        // The header takes precedence over the query parameter
+        assert_eq!(get(&format!("{}/sensors?access_token=wrong", url), Some("Bearer s3cret")).await, StatusCode::OK);
+        assert_eq!(get(&format!("{}/sensors?access_token=s3cret", url), Some("Bearer wrong")).await, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn dashboard_is_served_without_the_token() {
+        let url = start(Some("s3cret")).await;
+        let response = Client::new().get(format!("{}/", url)).send().await.unwrap();
+        assert_eq!(response.status().as_u16(), 200);
+        assert!(response.text().await.unwrap().contains("<html"));
+    }
+
+    #[tokio::test]
+    async fn open_without_a_token() {
+        let url = start(None).await;
+        assert_eq!(get(&format!("{}/sensors", url), None).await, StatusCode::OK);
+        assert_eq!(get(&format!("{}/metrics", url), Some("Bearer anything")).await, StatusCode::OK);
+    }
+
+    #[test]
+    fn compares_tokens() {
+        assert!(constant_time_eq(b"s3cret", b"s3cret"));
+        assert!(!constant_time_eq(b"s3cret", b"s3creT"));
+        assert!(!constant_time_eq(b"s3cre", b"s3cret"));
+        assert!(!constant_time_eq(b"", b"s3cret"));
+    }
+}
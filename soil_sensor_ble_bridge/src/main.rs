@@ -1,54 +1,291 @@
-#![feature(try_blocks)]
+mod auth;
+mod backfill;
+mod compensation;
+mod config;
+mod http;
+mod registry;
+mod sink;
+mod stats;
+mod store;
+#[cfg(test)]
+mod testing;
 
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
 use bluer;
-use log::{debug, info, warn};
-use bluer::{AdapterEvent, Address, Device, DeviceEvent, DeviceProperty, DiscoveryFilter, DiscoveryTransport};
+use log::{debug, error, info, warn};
+use bluer::{AdapterEvent, Device, DeviceEvent, DeviceProperty, DiscoveryFilter, DiscoveryTransport};
 use futures::{pin_mut, StreamExt};
 use futures::future::select_all;
+use tokio::sync::broadcast;
 use tokio::task::JoinHandle;
-use soil_sensor_common::Measurement;
+use soil_sensor_common::{Body, Measurement, Payload, Status};
+use soil_sensor_common::sequence::SequenceEvent;
+use soil_sensor_common::telemetry::{Record, Telemetry};
 use soil_sensor_common::web::InfluxDBMeasurement;
-use influxdb::{Client, Error, WriteQuery, InfluxDbWriteable};
 use clap::{Parser, Subcommand};
+use stats::Stats;
+use auth::{Authenticator, Freshness};
+use backfill::Backfill;
+use chrono::{DateTime, Local, NaiveDate};
+use config::{Config, LoggingConfig, MacAddress, SensorId, SinkConfig};
+use registry::{Attribute, Registry, SensorRef};
+use sink::Dispatcher;
+use store::Store;
 
 #[derive(Debug, Parser)]
 #[command(author, version, about, long_about = None)]
 struct Args {
+    /// Path to a TOML config file
+    #[arg(long, global = true, env = "SOIL_SENSOR_BRIDGE_CONFIG")]
+    config: Option<PathBuf>,
+
     #[command(subcommand)]
     cmd: Commands
 }
 
-#[derive(Subcommand, Debug, Clone, PartialEq, Eq)]
+#[derive(Subcommand, Debug, Clone, PartialEq)]
 enum Commands {
     Test,
-    Run
+    Run,
+    /// Load the config file, including any secrets it refers to, and report any problems
+    CheckConfig,
+    /// Fit temperature compensation coefficients from historical data in a CSV file
+    FitCompensation {
+        /// CSV file with time, temperature and moisture_level columns, e.g. an InfluxDB export
+        input: PathBuf,
+        /// Only use rows for this sensor ID (needs an id column)
+        #[arg(long)]
+        sensor: Option<SensorId>,
+        /// Width of the moving average used to separate slow moisture changes from daily drift
+        #[arg(long, default_value_t = 24)]
+        window_hours: u32,
+    },
+    /// Show measurements from the local history store
+    History {
+        /// Only show this sensor
+        #[arg(long)]
+        sensor: Option<SensorId>,
+        /// Only show measurements received from this device
+        #[arg(long)]
+        mac: Option<bluer::Address>,
+        /// Start of the time range: an RFC 3339 time, a date, or a time ago like 30m, 6h or 7d
+        #[arg(long, value_parser = store::parse_time)]
+        since: Option<DateTime<Local>>,
+        /// End of the time range, in the same forms as --since
+        #[arg(long, value_parser = store::parse_time)]
+        until: Option<DateTime<Local>>,
+        /// Show at most this many measurements, the newest ones. 0 to show all of them.
+        #[arg(long, default_value_t = 100)]
+        limit: u32,
+        #[arg(long, value_enum, default_value_t = HistoryFormat::Table)]
+        format: HistoryFormat,
+        /// Database to read, instead of the one in the config file
+        #[arg(long)]
+        db: Option<PathBuf>,
+    },
+    /// Manage the sensor registry: names, locations and other attributes attached to every
+    /// measurement
+    ///
+    /// A running bridge only picks up changes to the registry when it is restarted.
+    Sensors {
+        /// Registry file to use, instead of the one in the config file
+        #[arg(long, global = true)]
+        registry: Option<PathBuf>,
+        #[command(subcommand)]
+        cmd: SensorsCommand,
+    },
+}
+
+#[derive(Subcommand, Debug, Clone, PartialEq)]
+enum SensorsCommand {
+    /// List registered sensors
+    List {
+        /// Print JSON instead of a table
+        #[arg(long)]
+        json: bool,
+    },
+    /// Register a sensor, or update one which is already registered. Attributes which aren't
+    /// given are left as they were, unless they are cleared.
+    Add {
+        /// 4 hex digits, like SENSOR_ID in the firmware
+        id: SensorId,
+        name: String,
+        /// The device the sensor is heard through
+        #[arg(long)]
+        mac: Option<bluer::Address>,
+        #[arg(long)]
+        zone: Option<String>,
+        #[arg(long)]
+        plant: Option<String>,
+        #[arg(long, allow_negative_numbers = true)]
+        latitude: Option<f64>,
+        #[arg(long, allow_negative_numbers = true)]
+        longitude: Option<f64>,
+        /// Date the sensor was installed, like 2024-04-12
+        #[arg(long)]
+        installed: Option<NaiveDate>,
+        /// Remove an attribute, before setting any that are given. Can be repeated.
+        #[arg(long, value_enum, value_name = "ATTRIBUTE")]
+        clear: Vec<Attribute>,
+    },
+    /// Change the name of a registered sensor
+    Rename {
+        /// Sensor ID, or the MAC address it was registered with
+        sensor: SensorRef,
+        name: String,
+    },
+    /// Remove a sensor from the registry
+    Remove {
+        /// Sensor ID, or the MAC address it was registered with
+        sensor: SensorRef,
+    },
+}
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum HistoryFormat {
+    Table,
+    Csv,
+    Json,
 }
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() {
-    pretty_env_logger::init();
-
     let args = Args::parse();
 
-    if args.cmd == Commands::Test {
-        let fake_meas = Measurement {
-            id: 0x0123,
-            moisture_frequency: 6666,
-            temperature: 25 * 4,
-            capacitor_voltage: 4500,
-            sequence: 1
-        };
-        handle_measurement(fake_meas, bluer::Address([0, 1, 2, 3, 4, 5])).await;
+    let config = match &args.config {
+        Some(path) => Config::load(path),
+        None => Ok(Config::default()),
+    };
+    if args.cmd == Commands::CheckConfig {
+        check_config(args.config.as_deref(), config);
         return;
     }
 
+    let config = match config {
+        Ok(config) => {
+            init_logging(&config.logging);
+            config
+        },
+        Err(e) => {
+            init_logging(&LoggingConfig::default());
+            // A config path must have been given for loading to fail
+            error!("{}: {}", args.config.unwrap_or_default().display(), e);
+            std::process::exit(1);
+        }
+    };
+    if config.sinks.is_empty() {
+        warn!("No sinks configured. Measurements will only be logged.");
+    }
+    let config = Arc::new(config);
+
+    match &args.cmd {
+        Commands::Test => {
+            let fake_meas = Measurement {
+                id: 0x0123,
+                moisture_frequency: 6666,
+                temperature: 25 * 4,
+                capacitor_voltage: 4500,
+                sequence: 1,
+                status: Status::FIRST_AFTER_RESET,
+            };
+            let sinks = open_sinks(&config);
+            sinks.dispatch(InfluxDBMeasurement::new_now(&fake_meas, &[0, 1, 2, 3, 4, 5]));
+            sinks.close().await;
+            return;
+        },
+        Commands::FitCompensation { input, sensor, window_hours } => {
+            fit_compensation(input, *sensor, *window_hours);
+            return;
+        },
+        Commands::History { sensor, mac, since, until, limit, format, db } => {
+            let Some(path) = db.as_ref().or(config.history.path.as_ref()) else {
+                error!("No history store. Set history.path in the config file, or pass --db.");
+                std::process::exit(1);
+            };
+            let query = store::Query {
+                sensor_id: sensor.map(|sensor| sensor.0),
+                mac_address: mac.map(|mac| mac.to_string().to_lowercase()),
+                since: *since,
+                until: *until,
+                limit: (*limit > 0).then_some(*limit),
+            };
+            match store::query(path, &query) {
+                Ok(rows) => print_history(&rows, *format),
+                Err(e) => {
+                    error!("Failed to read history from {}: {}", path.display(), e);
+                    std::process::exit(1);
+                }
+            }
+            return;
+        },
+        Commands::Sensors { registry, cmd } => {
+            let Some(path) = registry.as_ref().or(config.registry.path.as_ref()) else {
+                error!("No sensor registry. Set registry.path in the config file, or pass --registry.");
+                std::process::exit(1);
+            };
+            manage_sensors(path, cmd);
+            return;
+        },
+        Commands::Run | Commands::CheckConfig => (),
+    }
+
+    let context = Context {
+        stats: Stats::default(),
+        auth: Arc::new(Authenticator::new(&config.auth)),
+        backfill: Arc::new(Backfill::default()),
+        config: config.clone(),
+        sinks: Arc::new(open_sinks(&config)),
+        store: config.history.path.as_ref().map(|path| match Store::open(path) {
+            Ok(store) => Arc::new(store),
+            Err(e) => {
+                error!("Failed to open history store {}: {}", path.display(), e);
+                std::process::exit(1);
+            }
+        }),
+        live: broadcast::channel(http::LIVE_BUFFER).0,
+    };
+    if context.auth.key_count() == 0 && !context.auth.allows_unauthenticated() {
+        warn!("No sensor keys configured, and unauthenticated sensors are not allowed. Every sensor will be rejected.");
+    }
+    tokio::spawn(report_stats(context.stats.clone(), context.sinks.clone()));
+    if let Some(listen) = config.http.listen {
+        let listener = match tokio::net::TcpListener::bind(listen).await {
+            Ok(listener) => listener,
+            Err(e) => {
+                error!("Failed to listen for HTTP on {}: {}", listen, e);
+                std::process::exit(1);
+            }
+        };
+        info!("Serving HTTP on {}", listen);
+        let state = http::AppState {
+            stats: context.stats.clone(),
+            sinks: context.sinks.clone(),
+            history: config.history.path.clone(),
+            live: context.live.clone(),
+            token: config.http.token.as_ref().map(|token| Arc::from(token.value())),
+        };
+        tokio::spawn(async move {
+            if let Err(e) = http::serve(listener, state).await {
+                error!("HTTP server failed: {}", e);
+            }
+        });
+    }
+
     let session = bluer::Session::new().await.unwrap();
-    let adapter_names = session.adapter_names().await.unwrap();
+    let mut adapter_names = session.adapter_names().await.unwrap();
+    if !config.bluetooth.adapters.is_empty() {
+        for missing in config.bluetooth.adapters.iter().filter(|name| !adapter_names.contains(name)) {
+            error!("Bluetooth adapter {} not found. Available adapters: {}", missing, adapter_names.join(", "));
+        }
+        adapter_names.retain(|name| config.bluetooth.adapters.contains(name));
+    }
     let mut adapter_tasks: Vec<JoinHandle<bluer::Result<()>>> = adapter_names
         .iter()
         .filter_map(|adapter_name|{
             if let Ok(adapter) = session.adapter(adapter_name) {
-                Some(tokio::spawn(listen_adapter(adapter)))
+                Some(tokio::spawn(listen_adapter(adapter, context.clone())))
             } else {
                 warn!("Failed to create adapter {}", adapter_name);
                 None
@@ -65,7 +302,20 @@ async fn main() {
     info!("All adapter listener tasks wrapped up. Exiting gracefully.");
 }
 
-async fn listen_adapter(adapter: bluer::Adapter) -> bluer::Result<()> {
+/// State shared between every adapter and device task.
+#[derive(Clone)]
+pub struct Context {
+    pub stats: Stats,
+    pub auth: Arc<Authenticator>,
+    pub backfill: Arc<Backfill>,
+    pub config: Arc<Config>,
+    pub sinks: Arc<Dispatcher>,
+    pub store: Option<Arc<Store>>,
+    /// Every measurement, for live subscribers
+    pub live: broadcast::Sender<InfluxDBMeasurement>,
+}
+
+async fn listen_adapter(adapter: bluer::Adapter, context: Context) -> bluer::Result<()> {
     debug!("Discovering devices using Bluetooth adapter {}\n", adapter.name());
     adapter.set_powered(true).await?;
 
@@ -85,14 +335,14 @@ async fn listen_adapter(adapter: bluer::Adapter) -> bluer::Result<()> {
         if let Some(AdapterEvent::DeviceAdded(addr)) = event {
             debug!("Device added: {addr}");
             let device = adapter.device(addr)?;
-            tokio::spawn(watch_device(device));
+            tokio::spawn(watch_device(device, context.clone()));
         } else {
             debug!("Device Event: {:?}", event);
         }
     }
 }
 
-pub async fn watch_device(device: Device) -> bluer::Result<()> {
+pub async fn watch_device(device: Device, context: Context) -> bluer::Result<()> {
     let events = device.events().await?;
     pin_mut!(events);
 
@@ -104,34 +354,95 @@ pub async fn watch_device(device: Device) -> bluer::Result<()> {
         }
     };
 
-    if !name.starts_with("BLE Soil Sensor") {
-        debug!("Device \"{}\" is not a soil sensor.", name);
+    if !context.config.filter.allows_device(device.address(), &name) {
+        debug!("Device \"{}\" ({}) is not a soil sensor, or is filtered out.", name, device.address());
         return Ok(())
     }
 
-    let mut last_meas: Option<Measurement> = None;
-
+    if let Ok(Some(rssi)) = device.rssi().await {
+        context.stats.record_rssi(device.address(), rssi);
+    }
     while let Some(event) = events.next().await {
-        if let DeviceEvent::PropertyChanged(DeviceProperty::ManufacturerData(data)) = event {
-            let result: Result<(), String> = try {
-                let id = u16::from_be_bytes(soil_sensor_common::COMPANY_ID_CODE);
-                let bytes = data.get(&id).ok_or(format!("Data {:?} has no key {}", data, id))?;
-                let bytes = soil_sensor_common::Serialized::try_from(
-                    bytes.as_slice())
-                    .or(Err(format!("Error converting {:?} to Serialized", bytes)))?;
-                let measurement = Measurement::from_bytes(bytes);
-
-                if Some(measurement) != last_meas {
-                    tokio::spawn(handle_measurement(measurement, device.address()));
-                } else {
-                    debug!("Duplicate measurement: {:?}", measurement);
+        if let DeviceEvent::PropertyChanged(DeviceProperty::Rssi(rssi)) = event {
+            context.stats.record_rssi(device.address(), rssi);
+        } else if let DeviceEvent::PropertyChanged(DeviceProperty::ManufacturerData(data)) = event {
+            let id = u16::from_be_bytes(soil_sensor_common::COMPANY_ID_CODE);
+            let Some(bytes) = data.get(&id) else {
+                debug!("Manufacturer data from {} has no key {}: {:?}", device.address(), id, data);
+                continue;
+            };
+            context.stats.record_advertisement(device.address());
+
+            let payload = match Payload::decode(bytes.as_slice()) {
+                Ok(payload) => payload,
+                Err(e) => {
+                    let count = context.stats.record_decode_error(device.address(), e);
+                    warn!("Failed to decode payload from {} ({} failures so far): {}", device.address(), count, e);
+                    continue;
+                }
+            };
+            context.stats.record_decoded(device.address());
+            debug!("Payload header from {}: {:?}", device.address(), payload.header);
+            if !context.config.filter.allows_sensor(payload.body.id()) {
+                debug!("Ignoring sensor {:04X} from {}", payload.body.id(), device.address());
+                continue;
+            }
+
+            let (measurement, telemetry) = match context.auth.check(&payload) {
+                Ok((Body::Measurement(measurement), Freshness::Fresh)) => (measurement, None),
+                Ok((Body::Telemetry(telemetry), Freshness::Fresh)) => (telemetry.measurement, Some(telemetry)),
+                Ok((Body::History(history), Freshness::Fresh)) => {
+                    let received = Local::now();
+                    let boot_count = payload.auth.map(|auth| auth.boot_count);
+                    let backfilled = context.backfill.accept(&history, boot_count, received);
+                    if !backfilled.is_empty() {
+                        info!("Backfilling {} buffered measurements from sensor {:04X}", backfilled.len(), history.id);
+                        context.stats.record_backfilled(history.id, backfilled.len());
+                    }
+                    for (measurement, time) in backfilled {
+                        let meas = InfluxDBMeasurement::new(&measurement, &device.address().0, time)
+                            .with_moisture_vwc(context.config.moisture_vwc(&measurement));
+                        publish(&context, &device, meas, true, bytes).await;
+                    }
+                    continue;
+                },
+                Ok((body, Freshness::Duplicate)) => {
+                    debug!("Duplicate authenticated payload: {:?}", body);
+                    continue;
+                },
+                Err(e) => {
+                    let count = context.stats.record_rejection(device.address());
+                    warn!("Rejected payload from {} (sensor {:04X}, {} rejections so far): {}",
+                        device.address(), payload.body.id(), count, e);
+                    continue;
                 }
-                last_meas = Some(measurement);
-                ()
             };
 
-            debug!("Received new Manufacturer data from {}: {:?}, Result: {:?}",
-                device.address(), data, result);
+            let boot_count = payload.auth.map(|auth| auth.boot_count);
+            let (sequence_event, loss_rate) = context.stats.record_sequence(&measurement, boot_count);
+            match sequence_event {
+                SequenceEvent::Duplicate | SequenceEvent::Stale => {
+                    debug!("{:?} measurement: {:?}", sequence_event, measurement);
+                    continue;
+                },
+                SequenceEvent::Reboot { missed } =>
+                    info!("Sensor {:04X} has rebooted, and {} measurements since then were missed", measurement.id, missed),
+                SequenceEvent::Gap { missed } | SequenceEvent::Wraparound { missed } if missed > 0 =>
+                    info!("Missed {} measurements from sensor {:04X} ({:.1}% loss so far)", missed, measurement.id, loss_rate * 100.0),
+                _ => (),
+            }
+
+            let (raised, cleared) = context.stats.record_status(measurement.id, measurement.status);
+            report_status(measurement.id, raised, cleared);
+
+            let mut meas = InfluxDBMeasurement::new_now(&measurement, &device.address().0)
+                .with_moisture_vwc(context.config.moisture_vwc(&measurement))
+                .with_sequence_event(sequence_event, loss_rate);
+            if let Some(telemetry) = telemetry {
+                report_telemetry(&telemetry);
+                meas = meas.with_telemetry(&telemetry);
+            }
+            publish(&context, &device, meas, false, bytes).await;
         }
     }
 
@@ -140,22 +451,354 @@ pub async fn watch_device(device: Device) -> bluer::Result<()> {
     Ok(())
 }
 
-pub async fn handle_measurement(meas: Measurement, addr: Address) {
-    let meas = InfluxDBMeasurement::new_now(&meas, &addr.0);
+/// Attaches the sensor's registry attributes to a measurement, and hands it to everything that
+/// wants it: the stats, the history store, live subscribers and the sinks.
+async fn publish(context: &Context, device: &Device, mut measurement: InfluxDBMeasurement, backfilled: bool, raw: &[u8]) {
+    if let Some(info) = context.config.sensors.info(measurement.id) {
+        measurement = measurement.with_sensor_info(&info);
+    }
+    let rssi = context.stats.rssi(device.address());
+    if !backfilled {
+        measurement = measurement.with_rssi(rssi);
+    }
+    context.stats.record_measurement(device.address(), &measurement);
+    if let Some(store) = &context.store {
+        store.record(store::Row::new(&measurement, device.adapter_name(), rssi, backfilled, raw));
+    }
+    // Only fails if nobody is subscribed
+    let _ = context.live.send(measurement.clone());
+    context.sinks.dispatch(measurement);
+}
+
+fn print_history(rows: &[store::Row], format: HistoryFormat) {
+    match format {
+        HistoryFormat::Json => match serde_json::to_string_pretty(rows) {
+            Ok(json) => println!("{}", json),
+            Err(e) => error!("Failed to write JSON: {}", e),
+        },
+        HistoryFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(std::io::stdout());
+            for row in rows {
+                if let Err(e) = writer.serialize(row) {
+                    error!("Failed to write CSV: {}", e);
+                    return;
+                }
+            }
+            let _ = writer.flush();
+        },
+        HistoryFormat::Table => {
+            let header = ["time", "sensor", "mac", "adapter", "rssi", "moisture", "vwc", "temp", "volts", "seq", "status", "raw"];
+            let table: Vec<[String; 12]> = rows.iter().map(|row| [
+                row.time.format("%Y-%m-%d %H:%M:%S").to_string() + if row.backfilled { "*" } else { "" },
+                format!("{:04X}", row.sensor_id),
+                row.mac_address.clone(),
+                row.adapter.clone(),
+                row.rssi.map(|rssi| rssi.to_string()).unwrap_or_default(),
+                row.moisture_level.to_string(),
+                row.moisture_vwc.map(|vwc| format!("{:.1}", vwc)).unwrap_or_default(),
+                row.temperature.map(|celsius| format!("{:.2}", celsius)).unwrap_or_default(),
+                format!("{:.2}", row.capacitor_voltage),
+                row.sequence.to_string(),
+                Status(row.status).names().collect::<Vec<_>>().join(","),
+                store::hex(&row.raw),
+            ]).collect();
+            print_table(&header, &table);
+            if rows.iter().any(|row| row.backfilled) {
+                println!("* buffered by the sensor, and received later");
+            }
+        },
+    }
+}
+
+/// Prints rows under a header, with every column as wide as its widest cell.
+fn print_table<const N: usize>(header: &[&str; N], rows: &[[String; N]]) {
+    let mut widths = header.map(|cell| cell.chars().count());
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    let print = |cells: &mut dyn Iterator<Item = &str>| {
+        let line: Vec<_> = cells.zip(widths).map(|(cell, width)| format!("{:width$}", cell, width = width)).collect();
+        println!("{}", line.join("  ").trim_end());
+    };
+    print(&mut header.iter().copied());
+    for row in rows {
+        print(&mut row.iter().map(String::as_str));
+    }
+}
+
+fn manage_sensors(path: &std::path::Path, cmd: &SensorsCommand) {
+    let mut registry = match Registry::load(path) {
+        Ok(registry) => registry,
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    let find = |registry: &Registry, sensor: SensorRef| registry.find(sensor).unwrap_or_else(|| {
+        match sensor {
+            SensorRef::Id(id) => error!("Sensor {} isn't registered", id),
+            SensorRef::Mac(address) => error!("No sensor is registered with MAC address {}", address.to_string().to_lowercase()),
+        }
+        std::process::exit(1);
+    });
+
+    let done = match cmd {
+        SensorsCommand::List { json: true } => {
+            match serde_json::to_string_pretty(&registry.sensors) {
+                Ok(json) => println!("{}", json),
+                Err(e) => error!("Failed to write JSON: {}", e),
+            }
+            return;
+        },
+        SensorsCommand::List { json: false } => {
+            let header = ["id", "name", "mac", "zone", "plant", "location", "installed", "calibrated"];
+            let rows: Vec<[String; 8]> = registry.sensors.iter().map(|(id, entry)| [
+                id.to_string(),
+                entry.name.clone(),
+                entry.mac_address.map(|mac| mac.0.to_string().to_lowercase()).unwrap_or_default(),
+                entry.zone.clone().unwrap_or_default(),
+                entry.plant.clone().unwrap_or_default(),
+                match (entry.latitude, entry.longitude) {
+                    (Some(latitude), Some(longitude)) => format!("{}, {}", latitude, longitude),
+                    _ => String::new(),
+                },
+                entry.installed.map(|date| date.to_string()).unwrap_or_default(),
+                if entry.calibration.is_some() { "yes" } else { "" }.to_string(),
+            ]).collect();
+            print_table(&header, &rows);
+            return;
+        },
+        SensorsCommand::Add { id, name, mac, zone, plant, latitude, longitude, installed, clear } => {
+            let existed = registry.sensors.contains_key(id);
+            let entry = registry.sensors.entry(*id).or_default();
+            for attribute in clear {
+                entry.clear(*attribute);
+            }
+            entry.name = name.clone();
+            entry.mac_address = mac.map(MacAddress).or(entry.mac_address);
+            entry.zone = zone.clone().or(entry.zone.take());
+            entry.plant = plant.clone().or(entry.plant.take());
+            entry.latitude = latitude.or(entry.latitude);
+            entry.longitude = longitude.or(entry.longitude);
+            entry.installed = installed.or(entry.installed);
+            format!("{} sensor {} ({})", if existed { "Updated" } else { "Added" }, id, name)
+        },
+        SensorsCommand::Rename { sensor, name } => {
+            let id = find(&registry, *sensor);
+            let entry = registry.sensors.get_mut(&id).expect("found sensors are registered");
+            let old_name = std::mem::replace(&mut entry.name, name.clone());
+            format!("Renamed sensor {} from {} to {}", id, old_name, name)
+        },
+        SensorsCommand::Remove { sensor } => {
+            let id = find(&registry, *sensor);
+            let entry = registry.sensors.remove(&id).expect("found sensors are registered");
+            format!("Removed sensor {} ({})", id, entry.name)
+        },
+    };
+
+    if let Err(e) = registry.validate() {
+        error!("{}", e);
+        std::process::exit(1);
+    }
+    if let Err(e) = registry.save(path) {
+        error!("Failed to save sensor registry {}: {}", path.display(), e);
+        std::process::exit(1);
+    }
+    println!("{}", done);
+    println!("Restart the bridge for the change to take effect.");
+}
+
+/// Prints fitted coefficients as a config snippet, ready to paste into the config file.
+fn fit_compensation(input: &std::path::Path, sensor: Option<SensorId>, window_hours: u32) {
+    match compensation::fit_csv(input, sensor, window_hours) {
+        Ok(fit) => {
+            println!("# Fitted from {} samples, R^2 = {:.3}", fit.samples, fit.r_squared);
+            println!("[compensation.{}]", sensor.map(|s| s.to_string()).unwrap_or("XXXX".to_string()));
+            println!("reference_celsius = {:?}", fit.compensation.reference_celsius);
+            println!("hz_per_celsius = {:?}", fit.compensation.hz_per_celsius);
+        },
+        Err(e) => {
+            error!("{}: {}", input.display(), e);
+            std::process::exit(1);
+        }
+    }
+}
 
-    let json = serde_json::to_string_pretty(&meas).unwrap_or("error".to_string());
+/// Logs an alert for every status flag which a sensor has just raised, and a note when a fault
+/// clears up again.
+fn report_status(id: u16, raised: Status, cleared: Status) {
+    if raised.contains(Status::PROBE_FAULT) {
+        warn!("Sensor {:04X} counted no pulses from its probe. Is the probe connected?", id);
+    }
+    if raised.contains(Status::LOW_ENERGY) {
+        warn!("Sensor {:04X} didn't have enough energy to power its probe, so moisture wasn't measured", id);
+    }
+    if raised.contains(Status::TEMPERATURE_STALE) {
+        info!("Sensor {:04X} had no new temperature reading, and sent an old one", id);
+    }
+    if raised.contains(Status::FIRST_AFTER_RESET) {
+        info!("Sensor {:04X} has just reset", id);
+    }
+    for (flag, name) in Status::NAMES {
+        if cleared.contains(flag) && flag != Status::FIRST_AFTER_RESET {
+            info!("Sensor {:04X} no longer reports {}", id, name);
+        }
+    }
+}
 
-    info!("Cool new measurement: {}", json);
+/// Logs the extension records which aren't written along with the measurement. Sensors keep
+/// sending their reset reason, so it is only logged with the first measurement after a reset.
+fn report_telemetry(telemetry: &Telemetry) {
+    let id = telemetry.measurement.id;
+    let first_after_reset = telemetry.measurement.status.contains(Status::FIRST_AFTER_RESET);
+    for record in telemetry.extensions() {
+        match record {
+            Record::ResetReason(reason) if first_after_reset =>
+                info!("Sensor {:04X} last reset because of: {}", id, reason),
+            Record::Unknown { kind, value } =>
+                debug!("Skipping unknown telemetry record {:#04x} from sensor {:04X}: {:02x?}", kind, id, value),
+            _ => (),
+        }
+    }
+}
 
-    let client = Client::new("https://influxdb.pyrite.io", "soil_sensors")
-        .with_token("oPpVK9r31dpjEGuDBefoTw14oLIyhgXChpzoPZ1hEa3oCYzr6NTVD0K19QpkSDx5VYAVsHBN5_5VRCrD__19mg==");
+/// Periodically log decode statistics for every device that has sent a bad payload, and the
+/// health of every sink.
+async fn report_stats(stats: Stats, sinks: Arc<Dispatcher>) {
+    let mut interval = tokio::time::interval(Duration::from_secs(60 * 60));
+    loop {
+        interval.tick().await;
+        stats.log_summary();
+        sinks.log_summary();
+    }
+}
 
-    let query = meas.into_query("soil_moisture");
-    let result = client.query(query).await;
+/// Exits if a sink's spool can't be opened, rather than carrying on without it.
+fn open_sinks(config: &Config) -> Dispatcher {
+    match Dispatcher::from_config(config) {
+        Ok(sinks) => sinks,
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+    }
+}
 
-    match result {
-        Ok(msg) => info!("InfluxDB Result: {}", msg),
-        Err(e) => warn!("InfluxDB Error: {}", e)
+/// Sets up logging from the config. `RUST_LOG` overrides it, if it is set.
+fn init_logging(logging: &LoggingConfig) {
+    let mut builder = pretty_env_logger::formatted_builder();
+    builder.filter_level(logging.level.0);
+    for (module, level) in &logging.modules {
+        builder.filter_module(module, level.0);
+    }
+    if let Ok(filters) = std::env::var("RUST_LOG") {
+        builder.parse_filters(&filters);
     }
+    builder.init();
+}
+
+/// Prints a summary of the config if it loaded, or the reason it didn't, and exits with an error
+/// in that case.
+fn check_config(path: Option<&std::path::Path>, config: Result<Config, config::ConfigError>) {
+    let Some(path) = path else {
+        eprintln!("No config file given. Pass one with --config, or set SOIL_SENSOR_BRIDGE_CONFIG.");
+        std::process::exit(1);
+    };
+    let config = match config {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}: {}", path.display(), e);
+            std::process::exit(1);
+        }
+    };
 
-}
\ No newline at end of file
+    println!("{}: OK", path.display());
+    if config.sinks.is_empty() {
+        println!("  No sinks. Measurements will only be logged.");
+    }
+    for (name, sink) in &config.sinks {
+        match sink {
+            SinkConfig::Influxdb(influxdb) => {
+                let credentials = match (&influxdb.token, &influxdb.username, &influxdb.password) {
+                    (Some(token), _, _) => format!("token {}", token.describe()),
+                    (None, Some(username), Some(password)) =>
+                        format!("user {}, password {}", username, password.describe()),
+                    _ => "no credentials".to_string(),
+                };
+                println!("  Sink {} ({}): {}, database {}, {}",
+                    name, sink.kind(), influxdb.url, influxdb.database, credentials);
+            },
+            SinkConfig::Mqtt(mqtt) => {
+                let discovery = if mqtt.discovery {
+                    format!("discovery under {}", mqtt.discovery_prefix)
+                } else {
+                    "no discovery".to_string()
+                };
+                println!("  Sink {} ({}): {}:{}, topics under {}, QoS {}, {}{}",
+                    name, sink.kind(), mqtt.host, mqtt.port, mqtt.topic_prefix, mqtt.qos, discovery,
+                    mqtt.username.as_ref().map(|username| format!(", user {}", username)).unwrap_or_default());
+            },
+            SinkConfig::Influxdb2(influxdb) => {
+                println!("  Sink {} ({}): {}, org {}, bucket {}, token {}, precision {}{}",
+                    name, sink.kind(), influxdb.url, influxdb.org, influxdb.bucket, influxdb.token.describe(),
+                    influxdb.precision.as_str(), if influxdb.gzip { ", gzip" } else { "" });
+            },
+            SinkConfig::File(file) => {
+                let size = file.max_megabytes.map(|megabytes| format!(" or every {} MB", megabytes)).unwrap_or_default();
+                let retention = file.retention_days
+                    .map(|days| format!("kept for {} days", days))
+                    .unwrap_or_else(|| "kept forever".to_string());
+                println!("  Sink {} ({}): {} files in {}, rotated daily{}, {}{}",
+                    name, sink.kind(), file.format.as_str(), file.dir.display(), size, retention,
+                    if file.compress { ", gzip" } else { "" });
+            },
+        }
+        let retry = sink.retry();
+        println!("    {} attempts, backing off from {}s to {}s",
+            retry.attempts, retry.initial_backoff_secs, retry.max_backoff_secs);
+        let batch = sink.batch();
+        println!("    Batches of up to {} points, flushed every {}s, {} queued at most",
+            batch.max_points, batch.flush_interval_secs, batch.queue_len);
+    }
+    match &config.spool.dir {
+        Some(dir) => println!("  Spooling to {}, up to {} MB and {} hours per sink",
+            dir.display(), config.spool.max_megabytes, config.spool.max_age_hours),
+        None => println!("  No spool. Measurements are dropped once a sink runs out of attempts."),
+    }
+    match &config.registry.path {
+        Some(path) => println!("  Sensor registry {}: {} sensors", path.display(), config.sensors.sensors.len()),
+        None => println!("  No sensor registry."),
+    }
+    match &config.history.path {
+        Some(path) => println!("  Recording history to {}", path.display()),
+        None => println!("  No history store."),
+    }
+    match config.http.listen {
+        Some(listen) => println!("  Serving HTTP on {}, {}", listen, match &config.http.token {
+            Some(token) => format!("token {}", token.describe()),
+            None => "no token".to_string(),
+        }),
+        None => println!("  No HTTP server."),
+    }
+    let adapters = if config.bluetooth.adapters.is_empty() {
+        "all".to_string()
+    } else {
+        config.bluetooth.adapters.join(", ")
+    };
+    println!("  Adapters: {}", adapters);
+    let filter = &config.filter;
+    let list = |ids: &[SensorId]| ids.iter().map(|id| id.to_string()).collect::<Vec<_>>().join(", ");
+    println!("  Devices named \"{}...\"{}", filter.name_prefix,
+        if filter.addresses.is_empty() { String::new() } else { format!(", {} addresses", filter.addresses.len()) });
+    if !filter.sensors.is_empty() {
+        println!("  Only sensors: {}", list(&filter.sensors));
+    }
+    if !filter.ignore_sensors.is_empty() {
+        println!("  Ignored sensors: {}", list(&filter.ignore_sensors));
+    }
+    println!("  Sensor keys: {}, unauthenticated sensors {}",
+        config.auth.keys.len(), if config.auth.allow_unauthenticated { "allowed" } else { "rejected" });
+    println!("  Calibrations: {}, temperature compensations: {}", config.calibration.len(), config.compensation.len());
+}
@@ -0,0 +1,309 @@
+//! Friendly names, locations and other attributes of each sensor, which are attached to every
+//! measurement it sends. They are kept in a TOML file of their own, so that the `sensors`
+//! subcommand can rewrite it without touching the config file:
+//!
+//! ```toml
+//! [sensors.0123]
+//! name = "Tomatoes, bed 3"
+//! mac_address = "c4:7e:21:0a:b3:5f"
+//! zone = "Greenhouse 1"
+//! plant = "Tomato"
+//! latitude = 52.0907
+//! longitude = 5.1214
+//! installed = "2024-04-12"
+//!
+//! [sensors.0123.calibration]
+//! dry = { hz = 7200.0, vwc = 0.0 }
+//! wet = { hz = 4100.0, vwc = 45.0 }
+//! ```
+//!
+//! The bridge reads the registry once, when it starts, so it needs restarting after any change.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use bluer::Address;
+use chrono::NaiveDate;
+use serde::{Deserialize, Serialize};
+use soil_sensor_common::web::SensorInfo;
+use crate::config::{CalibrationConfig, ConfigError, MacAddress, SensorId};
+
+const HEADER: &str = "# Sensor registry. Edited by `soil_sensor_ble_bridge sensors`, but it can be edited by hand too.\n\n";
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Registry {
+    #[serde(default)]
+    pub sensors: BTreeMap<SensorId, SensorEntry>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SensorEntry {
+    pub name: String,
+    /// The device the sensor is usually heard through, so that it can be found by MAC address
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mac_address: Option<MacAddress>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub zone: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub plant: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub latitude: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub longitude: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub installed: Option<NaiveDate>,
+    /// A sensor can be calibrated here or in the config file, but not in both
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub calibration: Option<CalibrationConfig>,
+}
+
+/// An optional attribute of a sensor, for `sensors add --clear`.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Attribute {
+    Mac,
+    Zone,
+    Plant,
+    Latitude,
+    Longitude,
+    Installed,
+    Calibration,
+}
+
+impl SensorEntry {
+    pub fn clear(&mut self, attribute: Attribute) {
+        match attribute {
+            Attribute::Mac => self.mac_address = None,
+            Attribute::Zone => self.zone = None,
+            Attribute::Plant => self.plant = None,
+            Attribute::Latitude => self.latitude = None,
+            Attribute::Longitude => self.longitude = None,
+            Attribute::Installed => self.installed = None,
+            Attribute::Calibration => self.calibration = None,
+        }
+    }
+
+    pub fn info(&self) -> SensorInfo {
+        SensorInfo {
+            name: Some(self.name.clone()),
+            zone: self.zone.clone(),
+            plant: self.plant.clone(),
+            latitude: self.latitude,
+            longitude: self.longitude,
+            installed: self.installed,
+        }
+    }
+}
+
+/// A sensor, given by its ID or by the MAC address it was registered with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SensorRef {
+    Id(SensorId),
+    Mac(Address),
+}
+
+impl FromStr for SensorRef {
+    type Err = String;
+
+    fn from_str(string: &str) -> Result<Self, Self::Err> {
+        if let Ok(id) = string.parse() {
+            return Ok(Self::Id(id));
+        }
+        string.parse()
+            .map(Self::Mac)
+            .map_err(|_| format!("\"{}\" is neither a 4 digit sensor ID nor a MAC address", string))
+    }
+}
+
+impl Registry {
+    /// Reads the registry, or starts an empty one if the file doesn't exist yet.
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(ConfigError::Registry(path.to_path_buf(), e.to_string())),
+        };
+        let registry: Self = toml::from_str(&contents)
+            .map_err(|e| ConfigError::Registry(path.to_path_buf(), e.to_string()))?;
+        registry.validate()?;
+        Ok(registry)
+    }
+
+    /// Writes the registry to a temporary file, then renames it into place, so that a crash never
+    /// leaves a half-written one behind.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let contents = toml::to_string_pretty(self).map_err(io::Error::other)?;
+        let mut temporary_path = path.as_os_str().to_owned();
+        temporary_path.push(".tmp");
+        let temporary_path = PathBuf::from(temporary_path);
+        fs::write(&temporary_path, HEADER.to_string() + &contents)?;
+        fs::rename(&temporary_path, path)
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        for (id, entry) in &self.sensors {
+            let at = |field: &str| format!("sensors.{}.{}", id, field);
+            if entry.name.trim().is_empty() {
+                return Err(ConfigError::invalid(at("name"), "must not be empty"));
+            }
+            if entry.latitude.is_some_and(|latitude| !(-90.0..=90.0).contains(&latitude)) {
+                return Err(ConfigError::invalid(at("latitude"), "must be between -90 and 90"));
+            }
+            if entry.longitude.is_some_and(|longitude| !(-180.0..=180.0).contains(&longitude)) {
+                return Err(ConfigError::invalid(at("longitude"), "must be between -180 and 180"));
+            }
+            if let Some(calibration) = &entry.calibration {
+                calibration.validate(*id)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Finds a registered sensor by ID or MAC address.
+    pub fn find(&self, sensor: SensorRef) -> Option<SensorId> {
+        match sensor {
+            SensorRef::Id(id) => self.sensors.contains_key(&id).then_some(id),
+            SensorRef::Mac(address) => self.sensors.iter()
+                .find(|(_, entry)| entry.mac_address.is_some_and(|mac| mac.0 == address))
+                .map(|(id, _)| *id),
+        }
+    }
+
+    pub fn info(&self, id: u16) -> Option<SensorInfo> {
+        self.sensors.get(&SensorId(id)).map(SensorEntry::info)
+    }
+
+    pub fn calibration(&self, id: SensorId) -> Option<&CalibrationConfig> {
+        self.sensors.get(&id).and_then(|entry| entry.calibration.as_ref())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::TemporaryDir;
+    use super::*;
+
+    /// The example from the module docs.
+    const EXAMPLE: &str = r#"
+        [sensors.0123]
+        name = "Tomatoes, bed 3"
+        mac_address = "c4:7e:21:0a:b3:5f"
+        zone = "Greenhouse 1"
+        plant = "Tomato"
+        latitude = 52.0907
+        longitude = 5.1214
+        installed = "2024-04-12"
+
+        [sensors.0123.calibration]
+        dry = { hz = 7200.0, vwc = 0.0 }
+        wet = { hz = 4100.0, vwc = 45.0 }
+    "#;
+
+    const MAC: Address = Address([0xc4, 0x7e, 0x21, 0x0a, 0xb3, 0x5f]);
+
+    fn registry(toml: &str) -> Registry {
+        toml::from_str(toml).unwrap()
+    }
+
+    fn entry(name: &str) -> SensorEntry {
+        SensorEntry { name: name.to_string(), ..Default::default() }
+    }
+
+    #[test]
+    fn save_and_load_round_trip() {
+        let dir = TemporaryDir::new("registry");
+        let path = dir.join("sensors.toml");
+        let mut original = registry(EXAMPLE);
+        original.sensors.insert(SensorId(0xbeef), entry("Lawn"));
+        original.save(&path).unwrap();
+
+        let contents = fs::read_to_string(&path).unwrap();
+        assert!(contents.starts_with(HEADER));
+        // Missing attributes are left out, rather than written empty
+        assert!(!contents.contains("[sensors.BEEF.calibration]"));
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1, "temporary file left behind");
+
+        let loaded = Registry::load(&path).unwrap();
+        assert_eq!(toml::to_string(&loaded).unwrap(), toml::to_string(&original).unwrap());
+        let entry = &loaded.sensors[&SensorId(0x0123)];
+        assert_eq!(entry.name, "Tomatoes, bed 3");
+        assert_eq!(entry.mac_address, Some(MacAddress(MAC)));
+        assert_eq!(entry.installed, NaiveDate::from_ymd_opt(2024, 4, 12));
+        assert!(loaded.calibration(SensorId(0x0123)).is_some());
+        assert!(loaded.calibration(SensorId(0xbeef)).is_none());
+    }
+
+    #[test]
+    fn load_starts_empty_without_a_file() {
+        let dir = TemporaryDir::new("registry");
+        assert!(Registry::load(&dir.join("sensors.toml")).unwrap().sensors.is_empty());
+    }
+
+    #[test]
+    fn load_rejects_invalid_registries() {
+        let dir = TemporaryDir::new("registry");
+        let path = dir.join("sensors.toml");
+        fs::write(&path, "[sensors.0123]\nname = \"\"\n").unwrap();
+        assert!(matches!(Registry::load(&path), Err(ConfigError::Invalid { .. })));
+        fs::write(&path, "[sensors.0123]\nname = \"Lawn\"\ncolour = \"green\"\n").unwrap();
+        assert!(matches!(Registry::load(&path), Err(ConfigError::Registry(..))));
+    }
+
+    #[test]
+    fn sensor_ref_parsing() {
+        assert_eq!("0123".parse(), Ok(SensorRef::Id(SensorId(0x0123))));
+        assert_eq!("BEEF".parse(), Ok(SensorRef::Id(SensorId(0xbeef))));
+        assert_eq!("c4:7e:21:0a:b3:5f".parse(), Ok(SensorRef::Mac(MAC)));
+        assert_eq!("C4:7E:21:0A:B3:5F".parse(), Ok(SensorRef::Mac(MAC)));
+        for invalid in ["", "123", "01234", "wxyz", "c4:7e:21:0a:b3"] {
+            assert!(invalid.parse::<SensorRef>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn find_by_id_or_mac() {
+        let registry = registry(EXAMPLE);
+        assert_eq!(registry.find(SensorRef::Id(SensorId(0x0123))), Some(SensorId(0x0123)));
+        assert_eq!(registry.find(SensorRef::Id(SensorId(0x0124))), None);
+        assert_eq!(registry.find(SensorRef::Mac(MAC)), Some(SensorId(0x0123)));
+        assert_eq!(registry.find(SensorRef::Mac(Address([0; 6]))), None);
+    }
+
+    #[test]
+    fn validate() {
+        assert!(registry(EXAMPLE).validate().is_ok());
+
+        let invalid = |at: &str, change: fn(&mut SensorEntry)| {
+            let mut registry = registry(EXAMPLE);
+            change(registry.sensors.get_mut(&SensorId(0x0123)).unwrap());
+            match registry.validate() {
+                Err(ConfigError::Invalid { at: actual, .. }) => assert_eq!(actual, at),
+                result => panic!("expected {} to be invalid, got {:?}", at, result),
+            }
+        };
+        invalid("sensors.0123.name", |entry| entry.name = " ".to_string());
+        invalid("sensors.0123.latitude", |entry| entry.latitude = Some(90.5));
+        invalid("sensors.0123.longitude", |entry| entry.longitude = Some(-180.5));
+
+        let mut registry = registry(EXAMPLE);
+        let calibration = registry.sensors.get_mut(&SensorId(0x0123)).unwrap().calibration.as_mut().unwrap();
+        calibration.polynomial = Some(vec![0.0, 1.0]);
+        calibration.points = vec![calibration.dry];
+        assert!(matches!(registry.validate(), Err(ConfigError::CalibrationCurve(SensorId(0x0123)))));
+    }
+
+    #[test]
+    fn clear_attributes() {
+        let mut entry = registry(EXAMPLE).sensors.remove(&SensorId(0x0123)).unwrap();
+        for attribute in [Attribute::Mac, Attribute::Zone, Attribute::Plant, Attribute::Latitude,
+            Attribute::Longitude, Attribute::Installed, Attribute::Calibration] {
+            entry.clear(attribute);
+        }
+        assert_eq!(entry.info(), SensorInfo { name: Some("Tomatoes, bed 3".to_string()), ..Default::default() });
+        assert!(entry.mac_address.is_none());
+        assert!(entry.calibration.is_none());
+    }
+}
@@ -0,0 +1,432 @@
+//! Appends every measurement to local files, for sites with no network connection.
+//!
+//! Files are named `measurements-<date>.<n>.<csv|jsonl>`. A new one is started every day, and
+//! whenever the current one reaches `max_megabytes`. Files that are no longer being written to
+//! are compressed to `.gz`, and deleted once they are older than `retention_days`.
+//!
+//! Every file starts with a line naming the schema and its version, which changes whenever a
+//! column is renamed or removed or its meaning changes:
+//!
+//! - CSV: `# soil_sensor_bridge measurements v2`, followed by a header row
+//! - JSON lines: `{"schema":"soil_sensor_bridge measurements","version":2}`
+//!
+//! Version 2 added rssi, after sensor_rssi, and the sensor registry's columns: sensor_name, zone,
+//! plant, latitude, longitude and installed.
+//!
+//! After that, every line is one measurement, with the same fields as the JSON that is logged:
+//! [`InfluxDBMeasurement`] as serialized by serde. Missing values are empty in CSV, and `null`
+//! in JSON.
+
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use chrono::{Local, NaiveDate};
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use log::{info, warn};
+use soil_sensor_common::web::InfluxDBMeasurement;
+use crate::config::{FileConfig, FileFormat};
+use super::{Sink, SinkError, SinkFuture};
+
+const SCHEMA: &str = "soil_sensor_bridge measurements";
+const SCHEMA_VERSION: u32 = 2;
+
+const FILE_PREFIX: &str = "measurements-";
+
+/// The file being appended to.
+struct Current {
+    file: File,
+    date: NaiveDate,
+    index: u32,
+    bytes: u64,
+    /// Whether anything has been written after the version line, i.e. the CSV header row.
+    has_rows: bool,
+}
+
+pub struct FileSink {
+    config: FileConfig,
+    current: Mutex<Option<Current>>,
+}
+
+impl FileSink {
+    /// Files are only opened on the first write, so that problems with the directory are
+    /// retried like any other failed write.
+    pub fn new(config: &FileConfig) -> Self {
+        Self { config: config.clone(), current: Mutex::new(None) }
+    }
+
+    fn version_line(&self) -> String {
+        match self.config.format {
+            FileFormat::Csv => format!("# {} v{}\n", SCHEMA, SCHEMA_VERSION),
+            FileFormat::Jsonl => format!("{{\"schema\":\"{}\",\"version\":{}}}\n", SCHEMA, SCHEMA_VERSION),
+        }
+    }
+
+    fn file_name(&self, date: NaiveDate, index: u32) -> String {
+        format!("{}{}.{}.{}", FILE_PREFIX, date.format("%Y-%m-%d"), index, self.config.format.as_str())
+    }
+
+    /// Date and index of a file written by this sink, and whether it has been compressed.
+    fn parse_name(&self, name: &str) -> Option<(NaiveDate, u32, bool)> {
+        let name = name.strip_prefix(FILE_PREFIX)?;
+        let (name, compressed) = match name.strip_suffix(".gz") {
+            Some(name) => (name, true),
+            None => (name, false),
+        };
+        let name = name.strip_suffix(self.config.format.as_str())?.strip_suffix('.')?;
+        let (date, index) = name.rsplit_once('.')?;
+        Some((NaiveDate::parse_from_str(date, "%Y-%m-%d").ok()?, index.parse().ok()?, compressed))
+    }
+
+    fn expired(&self, date: NaiveDate, today: NaiveDate) -> bool {
+        self.config.retention_days
+            .is_some_and(|days| today.signed_duration_since(date).num_days() > i64::from(days))
+    }
+
+    /// Picks the file to write to today: the newest one, if it is still being written to and has
+    /// the current schema, or else a new one. Along the way, deletes expired files, and returns
+    /// any others which still need compressing.
+    ///
+    /// After rotating for size, `rotated` is the index of the file which filled up, so that it
+    /// isn't picked again.
+    fn start(&self, today: NaiveDate, rotated: Option<u32>) -> io::Result<(Current, Vec<PathBuf>)> {
+        fs::create_dir_all(&self.config.dir)?;
+        let mut uncompressed = Vec::new();
+        let mut latest: Option<(u32, bool)> = None;
+        for entry in fs::read_dir(&self.config.dir)? {
+            let path = entry?.path();
+            let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+                continue;
+            };
+            // Left behind by a crash while compressing
+            if name.starts_with(FILE_PREFIX) && name.ends_with(".gz.tmp") {
+                fs::remove_file(&path)?;
+                continue;
+            }
+            let Some((date, index, compressed)) = self.parse_name(name) else {
+                continue;
+            };
+            if self.expired(date, today) {
+                info!("Deleting expired measurement file {}", path.display());
+                fs::remove_file(&path)?;
+                continue;
+            }
+            if date == today && latest.is_none_or(|(latest, _)| index >= latest) {
+                // Both exist if a crash came between compressing a file and removing it
+                let compressed = compressed || latest.is_some_and(|(latest, was)| latest == index && was);
+                latest = Some((index, compressed));
+            }
+            if !compressed {
+                uncompressed.push((date, index, path));
+            }
+        }
+
+        let reopen = latest
+            .filter(|(index, compressed)| !compressed && rotated.is_none_or(|rotated| *index > rotated))
+            .map(|(index, _)| index);
+        let reopened = match reopen {
+            Some(index) => self.reopen(today, index)?,
+            None => None,
+        };
+        let current = match reopened {
+            Some(current) => current,
+            None => {
+                let index = latest.map_or(0, |(index, _)| index + 1);
+                self.create(today, index)?
+            },
+        };
+        let finished = uncompressed.into_iter()
+            .filter(|(date, index, _)| (*date, *index) != (current.date, current.index))
+            .map(|(_, _, path)| path)
+            .collect();
+        Ok((current, finished))
+    }
+
+    /// Reopens a file to append to it, unless it was written with a different schema.
+    fn reopen(&self, date: NaiveDate, index: u32) -> io::Result<Option<Current>> {
+        let path = self.config.dir.join(self.file_name(date, index));
+        let mut first_line = String::new();
+        BufReader::new(File::open(&path)?).read_line(&mut first_line)?;
+        if first_line != self.version_line() {
+            info!("Starting a new file, since {} has a different schema", path.display());
+            return Ok(None);
+        }
+        let file = File::options().append(true).open(&path)?;
+        let bytes = file.metadata()?.len();
+        Ok(Some(Current { file, date, index, bytes, has_rows: bytes > first_line.len() as u64 }))
+    }
+
+    fn create(&self, date: NaiveDate, index: u32) -> io::Result<Current> {
+        let path = self.config.dir.join(self.file_name(date, index));
+        let mut file = File::options().create_new(true).append(true).open(&path)?;
+        let version_line = self.version_line();
+        file.write_all(version_line.as_bytes())?;
+        info!("Writing measurements to {}", path.display());
+        Ok(Current { file, date, index, bytes: version_line.len() as u64, has_rows: false })
+    }
+
+    fn encode(&self, batch: &[InfluxDBMeasurement], header: bool) -> Result<Vec<u8>, SinkError> {
+        match self.config.format {
+            FileFormat::Csv => {
+                let mut writer = csv::WriterBuilder::new().has_headers(header).from_writer(Vec::new());
+                for measurement in batch {
+                    writer.serialize(measurement)?;
+                }
+                writer.into_inner().map_err(|e| SinkError::File(e.into_error()))
+            },
+            FileFormat::Jsonl => {
+                let mut lines = Vec::new();
+                for measurement in batch {
+                    serde_json::to_writer(&mut lines, measurement).map_err(SinkError::Serialize)?;
+                    lines.push(b'\n');
+                }
+                Ok(lines)
+            },
+        }
+    }
+
+    /// Appends the batch to today's file, rotating first if necessary. Returns files which need
+    /// compressing.
+    fn write(&self, batch: &[InfluxDBMeasurement]) -> Result<Vec<PathBuf>, SinkError> {
+        let today = Local::now().date_naive();
+        let max_bytes = self.config.max_megabytes.map(|megabytes| megabytes * 1024 * 1024);
+        let mut current = self.current.lock().unwrap();
+
+        let rotated = match &*current {
+            Some(file) if file.date != today => Some(None),
+            Some(file) if max_bytes.is_some_and(|max| file.bytes >= max) => Some(Some(file.index)),
+            Some(_) => None,
+            None => Some(None),
+        };
+        let mut finished = Vec::new();
+        if let Some(rotated) = rotated {
+            let (file, uncompressed) = self.start(today, rotated).map_err(SinkError::File)?;
+            *current = Some(file);
+            finished = uncompressed;
+        }
+        let file = current.as_mut().expect("a file was just opened");
+
+        let data = self.encode(batch, !file.has_rows)?;
+        if let Err(e) = file.file.write_all(&data) {
+            // Cut off whatever part of the batch was written, so that the retry doesn't follow a
+            // partial line
+            if let Err(e) = file.file.set_len(file.bytes) {
+                warn!("Failed to truncate a partial write: {}", e);
+            }
+            return Err(SinkError::File(e));
+        }
+        file.bytes += data.len() as u64;
+        file.has_rows |= !batch.is_empty();
+        Ok(finished)
+    }
+}
+
+impl Sink for FileSink {
+    fn write_batch<'a>(&'a self, batch: &'a [InfluxDBMeasurement]) -> SinkFuture<'a, Result<(), SinkError>> {
+        Box::pin(async move {
+            let finished = self.write(batch)?;
+            if self.config.compress && !finished.is_empty() {
+                let result = tokio::task::spawn_blocking(move || {
+                    for path in finished {
+                        if let Err(e) = compress(&path) {
+                            warn!("Failed to compress {}: {}", path.display(), e);
+                        }
+                    }
+                }).await;
+                if let Err(e) = result {
+                    warn!("Compressing measurement files failed: {}", e);
+                }
+            }
+            Ok(())
+        })
+    }
+
+    fn flush(&self) -> SinkFuture<'_, Result<(), SinkError>> {
+        Box::pin(async move {
+            if let Some(file) = &*self.current.lock().unwrap() {
+                file.file.sync_data().map_err(SinkError::File)?;
+            }
+            Ok(())
+        })
+    }
+}
+
+/// Compresses a file to `<path>.gz`, then removes it. The compressed file is written under a
+/// temporary name first, so that a crash never leaves a truncated one behind.
+fn compress(path: &Path) -> io::Result<()> {
+    let mut compressed_path = path.as_os_str().to_owned();
+    compressed_path.push(".gz");
+    let compressed_path = PathBuf::from(compressed_path);
+    let temporary_path = compressed_path.with_extension("gz.tmp");
+
+    let mut encoder = GzEncoder::new(File::create(&temporary_path)?, Compression::default());
+    io::copy(&mut File::open(path)?, &mut encoder)?;
+    encoder.finish()?.sync_all()?;
+    fs::rename(&temporary_path, &compressed_path)?;
+    fs::remove_file(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+    use chrono::{Days, TimeZone};
+    use flate2::read::GzDecoder;
+    use soil_sensor_common::{Measurement, Status};
+    use crate::testing::TemporaryDir;
+    use super::*;
+
+    fn config(dir: &TemporaryDir, extra: &str) -> FileConfig {
+        toml::from_str(&format!("dir = {:?}\n{}", dir.path(), extra)).unwrap()
+    }
+
+    fn measurement(sequence: u16) -> InfluxDBMeasurement {
+        let measurement = Measurement {
+            id: 0x1234,
+            moisture_frequency: 1234,
+            temperature: 86,
+            capacitor_voltage: 0,
+            sequence,
+            status: Status::empty(),
+        };
+        InfluxDBMeasurement::new(&measurement, &[0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0xff], Local.timestamp_opt(1_700_000_000, 0).unwrap())
+            .with_rssi(Some(-60))
+    }
+
+    fn batch(sequences: std::ops::Range<u16>) -> Vec<InfluxDBMeasurement> {
+        sequences.map(measurement).collect()
+    }
+
+    /// Names of the files in the directory, in order.
+    fn files(dir: &TemporaryDir) -> Vec<String> {
+        let mut names: Vec<_> = fs::read_dir(dir.path()).unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        names
+    }
+
+    fn name(date: NaiveDate, index: u32, extension: &str) -> String {
+        format!("measurements-{}.{}.{}", date.format("%Y-%m-%d"), index, extension)
+    }
+
+    fn read_gzip(path: &Path) -> String {
+        let mut contents = String::new();
+        GzDecoder::new(File::open(path).unwrap()).read_to_string(&mut contents).unwrap();
+        contents
+    }
+
+    #[tokio::test]
+    async fn csv_header_once_per_file() {
+        let dir = TemporaryDir::new("files");
+        let today = Local::now().date_naive();
+        let sink = FileSink::new(&config(&dir, "format = \"csv\""));
+        sink.write_batch(&batch(0..2)).await.unwrap();
+        sink.write_batch(&[]).await.unwrap();
+        sink.write_batch(&batch(2..3)).await.unwrap();
+        drop(sink);
+        // Picks up where it left off after a restart
+        let sink = FileSink::new(&config(&dir, "format = \"csv\""));
+        sink.write_batch(&batch(3..4)).await.unwrap();
+
+        assert_eq!(files(&dir), [name(today, 0, "csv")]);
+        let contents = fs::read_to_string(dir.join(name(today, 0, "csv"))).unwrap();
+        let lines: Vec<_> = contents.lines().collect();
+        assert_eq!(lines[0], "# soil_sensor_bridge measurements v2");
+        assert!(lines[1].starts_with("id,mac_address,moisture_level,"), "{}", lines[1]);
+        assert!(lines[1].contains(",sensor_rssi,rssi,sensor_name,"), "{}", lines[1]);
+        assert_eq!(lines.iter().filter(|line| line.starts_with("id,")).count(), 1);
+        assert_eq!(lines.len(), 6);
+
+        let mut reader = csv::ReaderBuilder::new().comment(Some(b'#')).from_reader(contents.as_bytes());
+        let rows: Vec<InfluxDBMeasurement> = reader.deserialize().collect::<Result<_, _>>().unwrap();
+        assert_eq!(rows, batch(0..4));
+    }
+
+    #[tokio::test]
+    async fn jsonl_starts_with_the_schema() {
+        let dir = TemporaryDir::new("files");
+        let today = Local::now().date_naive();
+        let sink = FileSink::new(&config(&dir, ""));
+        sink.write_batch(&batch(0..2)).await.unwrap();
+
+        let contents = fs::read_to_string(dir.join(name(today, 0, "jsonl"))).unwrap();
+        let mut lines = contents.lines();
+        assert_eq!(lines.next(), Some(r#"{"schema":"soil_sensor_bridge measurements","version":2}"#));
+        let rows: Vec<InfluxDBMeasurement> = lines.map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(rows, batch(0..2));
+    }
+
+    #[tokio::test]
+    async fn rotates_by_size_and_compresses() {
+        let dir = TemporaryDir::new("files");
+        let today = Local::now().date_naive();
+        let sink = FileSink::new(&config(&dir, "format = \"csv\"\nmax_megabytes = 1"));
+        // Comfortably over a megabyte, but written to a single file, since it was empty
+        let big = batch(0..20_000);
+        sink.write_batch(&big).await.unwrap();
+        assert_eq!(files(&dir), [name(today, 0, "csv")]);
+        assert!(fs::metadata(dir.join(name(today, 0, "csv"))).unwrap().len() > 1024 * 1024);
+        sink.write_batch(&batch(0..1)).await.unwrap();
+        assert_eq!(files(&dir), [name(today, 0, "csv.gz"), name(today, 1, "csv")]);
+
+        let rotated = read_gzip(&dir.join(name(today, 0, "csv.gz")));
+        assert_eq!(rotated.lines().count(), 2 + big.len());
+        // The new file has a header of its own
+        let current = fs::read_to_string(dir.join(name(today, 1, "csv"))).unwrap();
+        assert_eq!(current.lines().count(), 3);
+        assert!(current.lines().nth(1).unwrap().starts_with("id,"));
+
+        // After a restart, the full file isn't appended to again
+        drop(sink);
+        let sink = FileSink::new(&config(&dir, "format = \"csv\"\nmax_megabytes = 1"));
+        sink.write_batch(&batch(1..2)).await.unwrap();
+        assert_eq!(fs::read_to_string(dir.join(name(today, 1, "csv"))).unwrap().lines().count(), 4);
+    }
+
+    #[tokio::test]
+    async fn rotates_daily_and_prunes_old_files() {
+        let dir = TemporaryDir::new("files");
+        let today = Local::now().date_naive();
+        let yesterday = today - Days::new(1);
+        let last_week = today - Days::new(7);
+        let last_month = today - Days::new(31);
+        let version_line = "{\"schema\":\"soil_sensor_bridge measurements\",\"version\":2}\n";
+        fs::write(dir.join(name(yesterday, 3, "jsonl")), version_line).unwrap();
+        fs::write(dir.join(name(last_week, 0, "jsonl.gz")), "").unwrap();
+        fs::write(dir.join(name(last_month, 0, "jsonl.gz")), "").unwrap();
+        fs::write(dir.join(name(last_month, 1, "jsonl")), version_line).unwrap();
+        // Another format, or something else altogether, is left alone
+        fs::write(dir.join(name(last_month, 0, "csv")), "").unwrap();
+        fs::write(dir.join("notes.txt"), "").unwrap();
+        // Left behind by a crash while compressing
+        fs::write(dir.join(name(yesterday, 2, "jsonl.gz.tmp")), "").unwrap();
+
+        let sink = FileSink::new(&config(&dir, "retention_days = 7"));
+        sink.write_batch(&batch(0..1)).await.unwrap();
+        assert_eq!(files(&dir), {
+            let mut expected = vec![
+                name(last_month, 0, "csv"),
+                name(last_week, 0, "jsonl.gz"),
+                name(yesterday, 3, "jsonl.gz"),
+                name(today, 0, "jsonl"),
+                "notes.txt".to_string(),
+            ];
+            expected.sort();
+            expected
+        });
+        assert_eq!(read_gzip(&dir.join(name(yesterday, 3, "jsonl.gz"))), version_line);
+    }
+
+    #[tokio::test]
+    async fn new_file_for_a_new_schema() {
+        let dir = TemporaryDir::new("files");
+        let today = Local::now().date_naive();
+        fs::write(dir.join(name(today, 0, "csv")), "# soil_sensor_bridge measurements v1\nid\n1\n").unwrap();
+
+        let sink = FileSink::new(&config(&dir, "format = \"csv\"\ncompress = false"));
+        sink.write_batch(&batch(0..1)).await.unwrap();
+        assert_eq!(files(&dir), [name(today, 0, "csv"), name(today, 1, "csv")]);
+        assert_eq!(fs::read_to_string(dir.join(name(today, 0, "csv"))).unwrap(), "# soil_sensor_bridge measurements v1\nid\n1\n");
+    }
+}
@@ -0,0 +1,30 @@
+use influxdb::{Client, InfluxDbWriteable};
+use soil_sensor_common::web::InfluxDBMeasurement;
+use crate::config::InfluxDbConfig;
+use super::{Sink, SinkError, SinkFuture};
+
+/// Writes to InfluxDB 1.x, or 2.x through its v1 compatibility API. The client, and its
+/// connection pool, last as long as the sink, and each batch is sent as a single line protocol
+/// request.
+pub struct InfluxDbSink {
+    client: Client,
+    measurement: String,
+}
+
+impl InfluxDbSink {
+    pub fn new(config: &InfluxDbConfig) -> Self {
+        Self { client: config.client(), measurement: config.measurement.clone() }
+    }
+}
+
+impl Sink for InfluxDbSink {
+    fn write_batch<'a>(&'a self, batch: &'a [InfluxDBMeasurement]) -> SinkFuture<'a, Result<(), SinkError>> {
+        Box::pin(async move {
+            let queries: Vec<_> = batch.iter()
+                .map(|measurement| measurement.clone().into_query(&self.measurement))
+                .collect();
+            self.client.query(queries).await?;
+            Ok(())
+        })
+    }
+}
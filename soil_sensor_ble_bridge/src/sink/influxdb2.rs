@@ -0,0 +1,283 @@
+use std::io::Write;
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use reqwest::Client;
+use reqwest::header::{AUTHORIZATION, CONTENT_ENCODING, CONTENT_TYPE};
+use soil_sensor_common::web::{InfluxDBMeasurement, Value};
+use crate::config::{InfluxDb2Config, Precision};
+use super::{Sink, SinkError, SinkFuture};
+
+/// Writes to InfluxDB 2.x or 3.x through `/api/v2/write`. The line protocol is built here rather
+/// than by the `influxdb` crate, so that the measurement name, tags and fields can be mapped.
+pub struct InfluxDb2Sink {
+    client: Client,
+    write_url: String,
+    config: InfluxDb2Config,
+}
+
+impl InfluxDb2Sink {
+    pub fn new(config: &InfluxDb2Config) -> Self {
+        Self {
+            client: Client::new(),
+            write_url: format!("{}/api/v2/write", config.url.trim_end_matches('/')),
+            config: config.clone(),
+        }
+    }
+
+    /// A measurement as a line of line protocol, or `None` if it has no fields to write.
+    fn line(&self, measurement: &InfluxDBMeasurement) -> Option<String> {
+        let values = measurement.values();
+
+        let mut tags: Vec<_> = values.iter()
+            .filter(|(name, _)| self.config.is_tag(name))
+            .map(|(name, value)| (self.config.key(name), tag_value(value)))
+            .filter(|(_, value)| !value.is_empty())
+            .collect();
+        // InfluxDB recommends sorting tags by key, as that is how it stores them
+        tags.sort();
+
+        let fields: Vec<_> = values.iter()
+            .filter(|(name, _)| self.config.is_field(name))
+            .filter_map(|(name, value)| Some((self.config.key(name), field_value(value)?)))
+            .collect();
+        if fields.is_empty() {
+            return None;
+        }
+
+        let mut line = escape(&self.config.measurement, &[',', ' ']);
+        for (key, value) in tags {
+            line += &format!(",{}={}", escape(key, &[',', '=', ' ']), escape(&value, &[',', '=', ' ']));
+        }
+        let fields: Vec<_> = fields.into_iter()
+            .map(|(key, value)| format!("{}={}", escape(key, &[',', '=', ' ']), value))
+            .collect();
+        line += &format!(" {} {}", fields.join(","), self.timestamp(measurement));
+        Some(line)
+    }
+
+    fn timestamp(&self, measurement: &InfluxDBMeasurement) -> i64 {
+        let time = measurement.time;
+        match self.config.precision {
+            Precision::Ns => time.timestamp_nanos_opt().unwrap_or(i64::MAX),
+            Precision::Us => time.timestamp_micros(),
+            Precision::Ms => time.timestamp_millis(),
+            Precision::S => time.timestamp(),
+        }
+    }
+}
+
+impl Sink for InfluxDb2Sink {
+    fn write_batch<'a>(&'a self, batch: &'a [InfluxDBMeasurement]) -> SinkFuture<'a, Result<(), SinkError>> {
+        Box::pin(async move {
+            let lines: Vec<_> = batch.iter().filter_map(|measurement| self.line(measurement)).collect();
+            if lines.is_empty() {
+                return Ok(());
+            }
+            let body = lines.join("\n").into_bytes();
+
+            let mut request = self.client.post(&self.write_url)
+                .query(&[
+                    ("org", self.config.org.as_str()),
+                    ("bucket", self.config.bucket.as_str()),
+                    ("precision", self.config.precision.as_str()),
+                ])
+                .header(AUTHORIZATION, format!("Token {}", self.config.token.value()))
+                .header(CONTENT_TYPE, "text/plain; charset=utf-8");
+            request = if self.config.gzip {
+                let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(&body).map_err(SinkError::Compress)?;
+                request.header(CONTENT_ENCODING, "gzip").body(encoder.finish().map_err(SinkError::Compress)?)
+            } else {
+                request.body(body)
+            };
+
+            let response = request.send().await?;
+            let status = response.status();
+            if !status.is_success() {
+                // InfluxDB explains what went wrong in the body, usually as JSON with a `message`
+                let message = response.text().await.unwrap_or_default();
+                return Err(SinkError::Rejected { status: status.as_u16(), message });
+            }
+            Ok(())
+        })
+    }
+}
+
+fn escape(s: &str, special: &[char]) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        if special.contains(&c) || c == '\\' {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+fn tag_value(value: &Value) -> String {
+    match value {
+        Value::Boolean(b) => b.to_string(),
+        Value::Integer(i) => i.to_string(),
+        Value::UnsignedInteger(u) => u.to_string(),
+        Value::Float(f) => f.to_string(),
+        Value::Text(s) => s.clone(),
+    }
+}
+
+/// A value formatted as a field, or `None` if line protocol can't represent it.
+fn field_value(value: &Value) -> Option<String> {
+    Some(match value {
+        Value::Boolean(b) => b.to_string(),
+        Value::Integer(i) => format!("{}i", i),
+        // Signed, like the `influxdb` crate writes them, since a field can't change type once
+        // written, and not every server accepts `u`
+        Value::UnsignedInteger(u) => format!("{}i", u),
+        Value::Float(f) if f.is_finite() => f.to_string(),
+        Value::Float(_) => return None,
+        Value::Text(s) => format!("\"{}\"", escape(s, &['"'])),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::io::Read;
+    use axum::Router;
+    use axum::body::Bytes;
+    use axum::extract::Query;
+    use axum::http::{HeaderMap, StatusCode};
+    use axum::routing::post;
+    use chrono::{Local, TimeZone};
+    use flate2::read::GzDecoder;
+    use soil_sensor_common::{Measurement, Status};
+    use tokio::net::TcpListener;
+    use tokio::sync::mpsc;
+    use super::*;
+
+    /// What the fake server received.
+    struct Received {
+        query: HashMap<String, String>,
+        headers: HeaderMap,
+        body: Vec<u8>,
+    }
+
+    /// Starts a server which answers every write with `status`, and returns its URL.
+    async fn serve(status: StatusCode) -> (String, mpsc::UnboundedReceiver<Received>) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let app = Router::new().route("/api/v2/write", post(
+            move |Query(query): Query<HashMap<String, String>>, headers: HeaderMap, body: Bytes| {
+                let sender = sender.clone();
+                async move {
+                    sender.send(Received { query, headers, body: body.to_vec() }).unwrap();
+                    (status, r#"{"code":"invalid","message":"unable to parse points"}"#)
+                }
+            }));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        (url, receiver)
+    }
+
+    fn config(url: &str, extra: &str) -> InfluxDb2Config {
+        toml::from_str(&format!(
+            "url = \"{}\"\norg = \"my org\"\nbucket = \"soil\"\ntoken = \"secret\"\n{}", url, extra,
+        )).unwrap()
+    }
+
+    fn measurement(seconds: i64) -> InfluxDBMeasurement {
+        let measurement = Measurement {
+            id: 0x1234,
+            moisture_frequency: 1234,
+            temperature: 86,
+            capacitor_voltage: 0,
+            sequence: 7,
+            status: Status::empty(),
+        };
+        let mut measurement = InfluxDBMeasurement::new(
+            &measurement, &[0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0xff], Local.timestamp_opt(seconds, 0).unwrap());
+        measurement.sensor_name = Some("Bed 1, north".to_string());
+        measurement.zone = Some("a=b".to_string());
+        measurement.firmware_version = Some(r#"1.2.3 "beta""#.to_string());
+        measurement.sensor_rssi = Some(-70);
+        measurement.reboot = Some(false);
+        measurement
+    }
+
+    /// Only a few values, renamed, so that the whole line can be checked.
+    const MAPPING: &str = r#"
+        measurement = "soil moisture,raw"
+        tags = ["id", "sensor_name", "zone"]
+        fields = ["moisture_level", "temperature", "reboot", "firmware_version", "sensor_rssi"]
+        rename = { sensor_name = "name", moisture_level = "moisture" }
+    "#;
+
+    #[tokio::test]
+    async fn writes_compressed_line_protocol() {
+        let (url, mut received) = serve(StatusCode::NO_CONTENT).await;
+        let sink = InfluxDb2Sink::new(&config(&url, MAPPING));
+        sink.write_batch(&[measurement(1_700_000_000)]).await.unwrap();
+
+        let request = received.recv().await.unwrap();
+        assert_eq!(request.query.get("org").map(String::as_str), Some("my org"));
+        assert_eq!(request.query.get("bucket").map(String::as_str), Some("soil"));
+        assert_eq!(request.query.get("precision").map(String::as_str), Some("s"));
+        assert_eq!(request.headers[AUTHORIZATION], "Token secret");
+        assert_eq!(request.headers[CONTENT_ENCODING], "gzip");
+
+        let mut body = String::new();
+        GzDecoder::new(&request.body[..]).read_to_string(&mut body).unwrap();
+        assert_eq!(body, concat!(
+            r"soil\ moisture\,raw,id=4660,name=Bed\ 1\,\ north,zone=a\=b ",
+            r#"moisture=1234i,temperature=21.5,reboot=false,firmware_version="1.2.3 \"beta\"",sensor_rssi=-70i "#,
+            "1700000000",
+        ));
+    }
+
+    #[tokio::test]
+    async fn writes_every_measurement_in_the_batch() {
+        let (url, mut received) = serve(StatusCode::NO_CONTENT).await;
+        let sink = InfluxDb2Sink::new(&config(&url, "gzip = false\nprecision = \"ms\""));
+        let mut unrepresentable = measurement(1_700_000_060);
+        unrepresentable.temperature = Some(f32::NAN);
+        sink.write_batch(&[measurement(1_700_000_000), unrepresentable]).await.unwrap();
+
+        let request = received.recv().await.unwrap();
+        assert_eq!(request.query.get("precision").map(String::as_str), Some("ms"));
+        assert!(!request.headers.contains_key(CONTENT_ENCODING));
+        let body = String::from_utf8(request.body).unwrap();
+        let lines: Vec<_> = body.lines().collect();
+        assert_eq!(lines.len(), 2);
+        // Without any mapping, the default tags, and everything else as fields
+        assert!(lines[0].starts_with(concat!(
+            r"soil_moisture,id=4660,mac_address=aa:bb:cc:dd:ee:ff,moisture_valid=true,",
+            r"sensor_name=Bed\ 1\,\ north,zone=a\=b moisture_level=1234i,",
+        )), "{}", lines[0]);
+        assert!(lines[0].contains(",temperature=21.5,"), "{}", lines[0]);
+        assert!(lines[0].ends_with(" 1700000000000"), "{}", lines[0]);
+        // Line protocol has no way to write NaN, so the field is left out
+        assert!(!lines[1].contains("temperature="), "{}", lines[1]);
+        assert!(lines[1].ends_with(" 1700000060000"), "{}", lines[1]);
+    }
+
+    #[tokio::test]
+    async fn rejected_writes() {
+        let (url, _received) = serve(StatusCode::BAD_REQUEST).await;
+        let sink = InfluxDb2Sink::new(&config(&url, ""));
+        match sink.write_batch(&[measurement(1_700_000_000)]).await {
+            Err(e @ SinkError::Rejected { status: 400, .. }) => {
+                assert!(e.to_string().contains("unable to parse points"));
+                assert!(e.is_permanent());
+            },
+            result => panic!("unexpected result {:?}", result),
+        }
+
+        // Worth keeping until the server can take it, or the sink's setup is fixed
+        for status in [StatusCode::TOO_MANY_REQUESTS, StatusCode::UNAUTHORIZED, StatusCode::NOT_FOUND, StatusCode::PAYLOAD_TOO_LARGE] {
+            let (url, _received) = serve(status).await;
+            let sink = InfluxDb2Sink::new(&config(&url, ""));
+            let e = sink.write_batch(&[measurement(1_700_000_000)]).await.unwrap_err();
+            assert!(!e.is_permanent(), "{}", status);
+            assert_eq!(e.is_misconfigured(), status != StatusCode::TOO_MANY_REQUESTS, "{}", status);
+        }
+    }
+}
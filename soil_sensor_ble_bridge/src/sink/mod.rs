@@ -0,0 +1,523 @@
+//! Backends that measurements are written to, and the dispatcher which fans every measurement
+//! out to all of them.
+//!
+//! Each sink gets its own queue and worker task, so a slow or broken backend only holds up its
+//! own writes. The worker collects measurements into batches according to the sink's
+//! [`BatchPolicy`], and retries each batch according to its [`RetryPolicy`] before giving up on
+//! it. Queues are bounded: when one is full, [`Dispatcher::dispatch`] drops the measurement for
+//! that sink rather than holding up the sensor it came from.
+//!
+//! Batches which the server finds malformed (see [`SinkError::is_permanent`]) are dropped
+//! straight away. Ones turned away because of the sink's setup, like a bad token, are kept and
+//! retried like any other failure, but the sink is reported as unhealthy until a write succeeds.
+//! If a spool directory is configured, batches which are given up on are written to the sink's
+//! [`Spool`] instead of being dropped. While anything is spooled, new batches join the back of
+//! the spool, and the oldest one is retried with backoff, so that everything reaches the sink in
+//! the order it arrived.
+//!
+//! To add a backend, implement [`Sink`], add a variant to [`SinkConfig`], and build it in
+//! [`build`].
+
+mod file;
+mod influxdb;
+mod influxdb2;
+mod mqtt;
+mod spool;
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use chrono::{DateTime, Local};
+use log::{debug, error, info, warn};
+use thiserror::Error;
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+use soil_sensor_common::web::InfluxDBMeasurement;
+use crate::config::{BatchPolicy, Config, RetryPolicy, SinkConfig};
+
+pub use self::file::FileSink;
+pub use self::influxdb::InfluxDbSink;
+pub use self::influxdb2::InfluxDb2Sink;
+pub use self::mqtt::MqttSink;
+pub use self::spool::{Backlog, Spool};
+
+pub type SinkFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+#[derive(Debug, Error)]
+pub enum SinkError {
+    #[error("InfluxDB: {0}")]
+    Influxdb(#[from] ::influxdb::Error),
+    #[error("HTTP: {0}")]
+    Http(#[from] reqwest::Error),
+    #[error("failed to compress request: {0}")]
+    Compress(std::io::Error),
+    /// The server answered, but didn't accept the write.
+    #[error("server returned {status}: {message}")]
+    Rejected { status: u16, message: String },
+    #[error("MQTT: {0}")]
+    Mqtt(#[from] rumqttc::ClientError),
+    #[error("not connected")]
+    Disconnected,
+    #[error("failed to serialize measurement: {0}")]
+    Serialize(serde_json::Error),
+    #[error("failed to write CSV: {0}")]
+    Csv(#[from] csv::Error),
+    #[error("file: {0}")]
+    File(std::io::Error),
+}
+
+impl SinkError {
+    /// Whether the server found the batch itself malformed, so that writing it again can't
+    /// succeed. Such batches are dropped rather than retried or spooled, so that they don't hold
+    /// up everything behind them.
+    pub fn is_permanent(&self) -> bool {
+        matches!(self, Self::Rejected { status: 400 | 422, .. })
+    }
+
+    /// Whether the server turned the write away because of how the sink is set up, e.g. an
+    /// expired token, a bucket which doesn't exist, or batches bigger than the server accepts.
+    /// The batch is kept, to be written once someone has fixed that.
+    pub fn is_misconfigured(&self) -> bool {
+        matches!(self,
+            Self::Rejected { status: 401 | 403 | 404 | 413, .. }
+            | Self::Influxdb(::influxdb::Error::AuthenticationError | ::influxdb::Error::AuthorizationError))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Health {
+    Healthy,
+    /// Writes are failing, but are still being retried.
+    Degraded(String),
+    /// Measurements have been dropped since the last successful write, or writes are being
+    /// turned away until the sink's setup is fixed.
+    Unhealthy(String),
+}
+
+pub trait Sink: Send + Sync {
+    /// Writes every measurement in the batch, or fails. A failed batch may be retried, so
+    /// backends should tolerate some measurements being written twice.
+    fn write_batch<'a>(&'a self, batch: &'a [InfluxDBMeasurement]) -> SinkFuture<'a, Result<(), SinkError>>;
+
+    /// Writes out anything the sink is holding on to itself.
+    fn flush(&self) -> SinkFuture<'_, Result<(), SinkError>> {
+        Box::pin(async { Ok(()) })
+    }
+
+    /// The sink's own view of its health, e.g. whether it is connected. Failed writes are
+    /// tracked by the dispatcher.
+    fn health(&self) -> Health {
+        Health::Healthy
+    }
+}
+
+/// Builds the sink described by a config entry.
+pub fn build(config: &SinkConfig) -> Arc<dyn Sink> {
+    match config {
+        SinkConfig::Influxdb(influxdb) => Arc::new(InfluxDbSink::new(influxdb)),
+        SinkConfig::Influxdb2(influxdb) => Arc::new(InfluxDb2Sink::new(influxdb)),
+        SinkConfig::Mqtt(mqtt) => Arc::new(MqttSink::new(mqtt)),
+        SinkConfig::File(file) => Arc::new(FileSink::new(file)),
+    }
+}
+
+/// Delivery counters for a single sink.
+#[derive(Debug, Default, Clone)]
+pub struct SinkStatus {
+    pub written: u64,
+    /// Measurements which were given up on, didn't fit in the queue, or were pushed out of the
+    /// spool by its limits.
+    pub dropped: u64,
+    pub failed_writes: u64,
+    /// Measurements which have been written to the spool, including ones since replayed.
+    pub spooled: u64,
+    /// What is in the spool right now.
+    pub backlog: Backlog,
+    consecutive_failures: u32,
+    /// Whether a batch has been dropped since the last successful write.
+    gave_up: bool,
+    /// Whether the last failed write was turned away because of the sink's setup, and there
+    /// hasn't been a successful write since.
+    misconfigured: bool,
+    pub last_success: Option<DateTime<Local>>,
+    pub last_error: Option<String>,
+}
+
+/// A sink, along with everything its worker needs.
+pub struct SinkSetup {
+    pub name: String,
+    pub sink: Arc<dyn Sink>,
+    pub retry: RetryPolicy,
+    pub batch: BatchPolicy,
+    pub spool: Option<Spool>,
+}
+
+struct Worker {
+    name: String,
+    sink: Arc<dyn Sink>,
+    queue: mpsc::Sender<InfluxDBMeasurement>,
+    status: Arc<Mutex<SinkStatus>>,
+    task: JoinHandle<()>,
+}
+
+/// Fans measurements out to every configured sink.
+pub struct Dispatcher {
+    workers: Vec<Worker>,
+}
+
+impl Dispatcher {
+    /// Builds every configured sink, and opens its spool. Must be called from within the tokio
+    /// runtime.
+    pub fn from_config(config: &Config) -> std::io::Result<Self> {
+        let mut sinks = Vec::new();
+        for (name, sink) in &config.sinks {
+            let spool = match &config.spool.dir {
+                Some(dir) => {
+                    let dir = dir.join(name);
+                    let spool = Spool::open(dir.clone(), &config.spool).map_err(|e|
+                        std::io::Error::new(e.kind(), format!("failed to open spool {}: {}", dir.display(), e)))?;
+                    Some(spool)
+                },
+                None => None,
+            };
+            sinks.push(SinkSetup {
+                name: name.clone(),
+                sink: build(sink),
+                retry: sink.retry().clone(),
+                batch: sink.batch().clone(),
+                spool,
+            });
+        }
+        Ok(Self::new(sinks))
+    }
+
+    /// Starts a worker for each sink. Must be called from within the tokio runtime.
+    pub fn new(sinks: Vec<SinkSetup>) -> Self {
+        let workers = sinks.into_iter().map(|SinkSetup { name, sink, retry, batch, spool }| {
+            let (queue, receiver) = mpsc::channel(batch.queue_len);
+            let status = SinkStatus { backlog: spool.as_ref().map(Spool::backlog).unwrap_or_default(), ..Default::default() };
+            let status = Arc::new(Mutex::new(status));
+            let state = WorkerState { name: name.clone(), sink: sink.clone(), retry, batch, status: status.clone(), spool };
+            let task = tokio::spawn(state.run(receiver));
+            Worker { name, sink, queue, status, task }
+        }).collect();
+        Self { workers }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.workers.is_empty()
+    }
+
+    /// Queues a measurement for every sink, without waiting for it to be written. If a sink's
+    /// queue is full, the measurement is dropped for that sink.
+    pub fn dispatch(&self, measurement: InfluxDBMeasurement) {
+        let json = serde_json::to_string_pretty(&measurement).unwrap_or("error".to_string());
+        info!("Cool new measurement: {}", json);
+
+        for worker in &self.workers {
+            if let Err(e) = worker.queue.try_send(measurement.clone()) {
+                match e {
+                    TrySendError::Full(_) => warn!("Sink {} is too far behind, dropping a measurement", worker.name),
+                    TrySendError::Closed(_) => error!("Sink {} worker has stopped, dropping a measurement", worker.name),
+                }
+                worker.status.lock().unwrap().dropped += 1;
+            }
+        }
+    }
+
+    /// The health of every sink, by name, along with its counters.
+    pub fn health(&self) -> Vec<(String, Health, SinkStatus)> {
+        self.workers.iter().map(|worker| {
+            let status = worker.status.lock().unwrap().clone();
+            let error = || status.last_error.clone().unwrap_or_default();
+            let health = if status.gave_up || status.misconfigured {
+                Health::Unhealthy(error())
+            } else if status.consecutive_failures > 0 {
+                Health::Degraded(error())
+            } else if status.backlog.measurements > 0 {
+                Health::Degraded(format!("{} measurements spooled", status.backlog.measurements))
+            } else {
+                worker.sink.health()
+            };
+            (worker.name.clone(), health, status)
+        }).collect()
+    }
+
+    pub fn log_summary(&self) {
+        for (name, health, status) in self.health() {
+            let backlog = &status.backlog;
+            let oldest = backlog.oldest
+                .map(|t| format!(", oldest from {}", DateTime::<Local>::from(t).to_rfc3339()))
+                .unwrap_or_default();
+            info!("Sink {}: {:?}, {} written, {} dropped, {} failed writes, {} waiting in spool ({} bytes{}){}",
+                name, health, status.written, status.dropped, status.failed_writes,
+                backlog.measurements, backlog.bytes, oldest,
+                status.last_success.map(|t| format!(", last success at {}", t.to_rfc3339())).unwrap_or_default());
+        }
+    }
+
+    /// Writes out everything that is still queued, then flushes every sink. Anything that can't
+    /// be written stays in the spool, if there is one, until the bridge starts again.
+    pub async fn close(self) {
+        for worker in self.workers {
+            drop(worker.queue);
+            if let Err(e) = worker.task.await {
+                error!("Sink {} worker failed: {}", worker.name, e);
+            }
+            if let Err(e) = worker.sink.flush().await {
+                error!("Failed to flush sink {}: {}", worker.name, e);
+            }
+        }
+    }
+}
+
+enum Event {
+    Batch(Vec<InfluxDBMeasurement>),
+    /// Time to retry the oldest spooled batch.
+    Replay,
+    /// The dispatcher is closing.
+    Closed,
+}
+
+struct WorkerState {
+    name: String,
+    sink: Arc<dyn Sink>,
+    retry: RetryPolicy,
+    batch: BatchPolicy,
+    status: Arc<Mutex<SinkStatus>>,
+    spool: Option<Spool>,
+}
+
+impl WorkerState {
+    async fn run(mut self, mut queue: mpsc::Receiver<InfluxDBMeasurement>) {
+        let initial_backoff = Duration::from_secs(self.retry.initial_backoff_secs);
+        let max_backoff = Duration::from_secs(self.retry.max_backoff_secs);
+        let mut replay_backoff = initial_backoff;
+        let mut next_replay = Instant::now();
+        let flush_interval = Duration::from_secs(self.batch.flush_interval_secs);
+
+        loop {
+            self.expire();
+            let has_backlog = self.spool.as_ref().is_some_and(|spool| !spool.is_empty());
+            let event = if has_backlog {
+                tokio::select! {
+                    // Everything goes to the spool anyway, so don't wait for a full batch
+                    batch = recv_batch(&mut queue, self.batch.max_points, Duration::ZERO) =>
+                        batch.map_or(Event::Closed, Event::Batch),
+                    _ = tokio::time::sleep_until(next_replay) => Event::Replay,
+                }
+            } else {
+                recv_batch(&mut queue, self.batch.max_points, flush_interval).await
+                    .map_or(Event::Closed, Event::Batch)
+            };
+
+            match event {
+                Event::Closed => {
+                    // One last go at the spool, without waiting between attempts
+                    while self.spool.as_ref().is_some_and(|spool| !spool.is_empty()) && self.replay().await {}
+                    break;
+                },
+                // Join the back of the spool, to keep everything in order
+                Event::Batch(batch) if has_backlog => self.spool_batch(&batch),
+                Event::Batch(batch) => {
+                    if let Err(e) = self.deliver(&batch).await {
+                        self.give_up(&batch, e);
+                    }
+                },
+                Event::Replay => {
+                    if self.replay().await {
+                        replay_backoff = initial_backoff;
+                        next_replay = Instant::now();
+                    } else {
+                        next_replay = Instant::now() + replay_backoff;
+                        replay_backoff = (replay_backoff * 2).min(max_backoff);
+                    }
+                },
+            }
+        }
+    }
+
+    /// Tries to write a batch, retrying according to the policy.
+    async fn deliver(&self, batch: &[InfluxDBMeasurement]) -> Result<(), SinkError> {
+        let mut backoff = Duration::from_secs(self.retry.initial_backoff_secs);
+        let mut attempt = 1;
+        loop {
+            let result = self.sink.write_batch(batch).await;
+            match result {
+                Ok(()) => {
+                    self.record_success(batch.len());
+                    return Ok(());
+                },
+                Err(e) => {
+                    self.record_failure(&e);
+                    if attempt == self.retry.attempts || e.is_permanent() {
+                        return Err(e);
+                    }
+                    warn!("Sink {}: write failed (attempt {} of {}), retrying in {:?}: {}",
+                        self.name, attempt, self.retry.attempts, backoff, e);
+                }
+            }
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(Duration::from_secs(self.retry.max_backoff_secs));
+            attempt += 1;
+        }
+    }
+
+    /// Spools a batch which ran out of retries, or drops it if there is no spool or it can never
+    /// be written.
+    fn give_up(&mut self, batch: &[InfluxDBMeasurement], e: SinkError) {
+        if e.is_permanent() {
+            self.drop_rejected(batch.len(), &e);
+        } else if self.spool.is_some() {
+            warn!("Sink {}: spooling {} measurements after {} attempts: {}", self.name, batch.len(), self.retry.attempts, e);
+            self.spool_batch(batch);
+        } else {
+            error!("Sink {}: dropping {} measurements after {} attempts: {}", self.name, batch.len(), self.retry.attempts, e);
+            let mut status = self.status.lock().unwrap();
+            status.dropped += batch.len() as u64;
+            status.gave_up = true;
+        }
+    }
+
+    fn drop_rejected(&self, measurements: usize, e: &SinkError) {
+        error!("Sink {}: dropping {} measurements which were rejected: {}", self.name, measurements, e);
+        let mut status = self.status.lock().unwrap();
+        status.dropped += measurements as u64;
+        status.gave_up = true;
+    }
+
+    fn spool_batch(&mut self, batch: &[InfluxDBMeasurement]) {
+        let Some(spool) = &mut self.spool else {
+            return;
+        };
+        let mut status = self.status.lock().unwrap();
+        match spool.push(batch) {
+            Ok(evicted) => {
+                status.spooled += batch.len() as u64;
+                if evicted > 0 {
+                    warn!("Sink {}: spool is full, dropped the {} oldest measurements", self.name, evicted);
+                    status.dropped += evicted as u64;
+                    status.gave_up = true;
+                }
+            },
+            Err(e) => {
+                error!("Sink {}: failed to spool {} measurements, dropping them: {}", self.name, batch.len(), e);
+                status.dropped += batch.len() as u64;
+                status.gave_up = true;
+            },
+        }
+        status.backlog = spool.backlog();
+    }
+
+    /// Makes one attempt at writing the oldest spooled batch. Returns `false` if the sink is
+    /// still failing, and it is worth waiting before trying again.
+    async fn replay(&mut self) -> bool {
+        let Some(spool) = &mut self.spool else {
+            return true;
+        };
+        let batch = match spool.oldest() {
+            Ok(Some(batch)) => batch,
+            Ok(None) => return true,
+            Err(e) => {
+                error!("Sink {}: failed to read from spool {}, dropping the batch: {}", self.name, spool.dir().display(), e);
+                let dropped = spool.pop().unwrap_or(0);
+                let mut status = self.status.lock().unwrap();
+                status.dropped += dropped as u64;
+                status.backlog = spool.backlog();
+                return true;
+            },
+        };
+
+        match self.sink.write_batch(&batch).await {
+            Ok(()) => self.record_success(batch.len()),
+            Err(e) => {
+                self.record_failure(&e);
+                if !e.is_permanent() {
+                    debug!("Sink {}: replaying spooled batch failed: {}", self.name, e);
+                    return false;
+                }
+                // Retrying it would only hold up everything behind it
+                self.drop_rejected(batch.len(), &e);
+            },
+        }
+
+        let Some(spool) = &mut self.spool else {
+            return true;
+        };
+        if let Err(e) = spool.pop() {
+            // It will be written again, which sinks have to tolerate anyway
+            error!("Sink {}: failed to remove replayed batch from spool {}: {}", self.name, spool.dir().display(), e);
+            return false;
+        }
+        self.status.lock().unwrap().backlog = spool.backlog();
+        if spool.is_empty() {
+            info!("Sink {}: caught up on everything in the spool", self.name);
+        }
+        true
+    }
+
+    /// Drops spooled batches which are past the age limit.
+    fn expire(&mut self) {
+        let Some(spool) = &mut self.spool else {
+            return;
+        };
+        let mut status = self.status.lock().unwrap();
+        match spool.expire() {
+            Ok(0) => (),
+            Ok(expired) => {
+                warn!("Sink {}: dropped {} spooled measurements which were too old", self.name, expired);
+                status.dropped += expired as u64;
+                status.gave_up = true;
+            },
+            Err(e) => error!("Sink {}: failed to expire old spooled measurements: {}", self.name, e),
+        }
+        status.backlog = spool.backlog();
+    }
+
+    fn record_success(&self, written: usize) {
+        let mut status = self.status.lock().unwrap();
+        status.written += written as u64;
+        status.consecutive_failures = 0;
+        status.gave_up = false;
+        status.misconfigured = false;
+        status.last_success = Some(Local::now());
+    }
+
+    fn record_failure(&self, e: &SinkError) {
+        let mut status = self.status.lock().unwrap();
+        status.failed_writes += 1;
+        status.consecutive_failures += 1;
+        status.misconfigured = e.is_misconfigured();
+        status.last_error = Some(e.to_string());
+    }
+}
+
+/// Waits for a measurement, then collects more until there are `max_points`, or
+/// `flush_interval` has passed. Returns `None` once the queue is closed and empty.
+///
+/// Only cancel safe with a zero `flush_interval`, since measurements already collected are lost
+/// if the future is dropped while waiting for more.
+async fn recv_batch(
+    queue: &mut mpsc::Receiver<InfluxDBMeasurement>,
+    max_points: usize,
+    flush_interval: Duration,
+) -> Option<Vec<InfluxDBMeasurement>> {
+    let mut batch = vec![queue.recv().await?];
+    let deadline = Instant::now() + flush_interval;
+    while batch.len() < max_points {
+        let measurement = if flush_interval.is_zero() {
+            queue.try_recv().ok()
+        } else {
+            // Closed or timed out, either way the batch is done
+            tokio::time::timeout_at(deadline, queue.recv()).await.ok().flatten()
+        };
+        match measurement {
+            Some(measurement) => batch.push(measurement),
+            None => break,
+        }
+    }
+    Some(batch)
+}
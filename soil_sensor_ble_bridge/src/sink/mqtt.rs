@@ -0,0 +1,407 @@
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Duration;
+use log::{debug, info, warn};
+use rumqttc::{AsyncClient, Event, EventLoop, LastWill, MqttOptions, Outgoing, Packet, QoS};
+use serde_json::json;
+use soil_sensor_common::Status;
+use soil_sensor_common::web::InfluxDBMeasurement;
+use crate::config::{MqttConfig, Secret};
+use super::{Health, Sink, SinkError, SinkFuture};
+
+/// Requests which can wait for the event loop before `publish` blocks.
+const REQUEST_QUEUE_LEN: usize = 100;
+
+const KEEP_ALIVE: Duration = Duration::from_secs(30);
+
+/// How long to wait before reconnecting after the connection drops.
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// How long [`MqttSink::flush`] waits for queued publishes to complete.
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+
+const ONLINE: &str = "online";
+const OFFLINE: &str = "offline";
+
+/// State shared with the task which drives the connection.
+#[derive(Default)]
+struct Connection {
+    connected: AtomicBool,
+    /// Sensors whose discovery configs have been published since the last connect.
+    announced: Mutex<HashSet<u16>>,
+    /// Publishes handed to the client, and publishes which the broker has acknowledged (or, at
+    /// QoS 0, which have been sent).
+    requested: AtomicU64,
+    completed: AtomicU64,
+    last_error: Mutex<Option<String>>,
+}
+
+/// Publishes every reading as JSON to `<topic_prefix>/<sensor ID>/state`, along with Home
+/// Assistant discovery configs for each sensor. Readings backfilled from a sensor's history go to
+/// `<topic_prefix>/<sensor ID>/backfill` instead, since they are older than the newest state. The bridge's availability is published, retained,
+/// to `<topic_prefix>/bridge/availability`, and the broker marks it offline if the connection
+/// drops.
+pub struct MqttSink {
+    client: AsyncClient,
+    config: MqttConfig,
+    qos: QoS,
+    connection: Arc<Connection>,
+}
+
+impl MqttSink {
+    /// Starts connecting in the background. Must be called from within the tokio runtime.
+    pub fn new(config: &MqttConfig) -> Self {
+        let qos = rumqttc::qos(config.qos).unwrap_or(QoS::AtLeastOnce);
+        let mut options = MqttOptions::new(&config.client_id, &config.host, config.port);
+        options.set_keep_alive(KEEP_ALIVE);
+        options.set_last_will(LastWill::new(availability_topic(config), OFFLINE, qos, true));
+        if let Some(username) = &config.username {
+            options.set_credentials(username, config.password.as_ref().map_or("", Secret::value));
+        }
+
+        let (client, event_loop) = AsyncClient::new(options, REQUEST_QUEUE_LEN);
+        let connection = Arc::new(Connection::default());
+        tokio::spawn(drive(event_loop, client.clone(), config.clone(), qos, connection.clone()));
+        Self { client, config: config.clone(), qos, connection }
+    }
+
+    async fn publish(&self, topic: String, retain: bool, payload: Vec<u8>) -> Result<(), SinkError> {
+        self.client.publish(topic, self.qos, retain, payload).await?;
+        self.connection.requested.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Publishes Home Assistant discovery configs for a sensor, unless that has already been done
+    /// since the last connect.
+    async fn announce(&self, measurement: &InfluxDBMeasurement) -> Result<(), SinkError> {
+        if !self.config.discovery || self.connection.announced.lock().unwrap().contains(&measurement.id) {
+            return Ok(());
+        }
+        for (object, config) in discovery_configs(&self.config, measurement) {
+            let topic = format!("{}/sensor/{}/{}/config", self.config.discovery_prefix, device_id(measurement.id), object);
+            self.publish(topic, true, config.to_string().into_bytes()).await?;
+        }
+        self.connection.announced.lock().unwrap().insert(measurement.id);
+        Ok(())
+    }
+}
+
+impl Sink for MqttSink {
+    fn write_batch<'a>(&'a self, batch: &'a [InfluxDBMeasurement]) -> SinkFuture<'a, Result<(), SinkError>> {
+        Box::pin(async move {
+            // The client would queue publishes while disconnected, but then the dispatcher
+            // couldn't retry or spool them
+            if !self.connection.connected.load(Ordering::Relaxed) {
+                return Err(SinkError::Disconnected);
+            }
+            for measurement in batch {
+                self.announce(measurement).await?;
+                let payload = serde_json::to_vec(measurement).map_err(SinkError::Serialize)?;
+                let topic = if measurement.status.contains(Status::BUFFERED) {
+                    backfill_topic(&self.config, measurement.id)
+                } else {
+                    state_topic(&self.config, measurement.id)
+                };
+                self.publish(topic, false, payload).await?;
+            }
+            Ok(())
+        })
+    }
+
+    fn flush(&self) -> SinkFuture<'_, Result<(), SinkError>> {
+        Box::pin(async move {
+            let connection = &self.connection;
+            let wait = async {
+                while connection.completed.load(Ordering::Relaxed) < connection.requested.load(Ordering::Relaxed) {
+                    tokio::time::sleep(Duration::from_millis(50)).await;
+                }
+            };
+            if tokio::time::timeout(FLUSH_TIMEOUT, wait).await.is_err() {
+                warn!("Timed out waiting for MQTT publishes to complete");
+            }
+            Ok(())
+        })
+    }
+
+    fn health(&self) -> Health {
+        if self.connection.connected.load(Ordering::Relaxed) {
+            Health::Healthy
+        } else {
+            let error = self.connection.last_error.lock().unwrap().clone();
+            Health::Unhealthy(error.unwrap_or_else(|| "not connected".to_string()))
+        }
+    }
+}
+
+/// Polls the event loop for as long as the client exists, which is what actually sends and
+/// receives packets, and reconnects whenever the connection drops.
+async fn drive(mut event_loop: EventLoop, client: AsyncClient, config: MqttConfig, qos: QoS, connection: Arc<Connection>) {
+    loop {
+        match event_loop.poll().await {
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                info!("Connected to MQTT broker {}:{}", config.host, config.port);
+                connection.connected.store(true, Ordering::Relaxed);
+                // Discovery configs are retained, but re-publish them in case the broker lost them
+                connection.announced.lock().unwrap().clear();
+                if let Err(e) = client.try_publish(availability_topic(&config), qos, true, ONLINE) {
+                    warn!("Failed to publish MQTT availability: {}", e);
+                } else {
+                    connection.requested.fetch_add(1, Ordering::Relaxed);
+                }
+            },
+            Ok(Event::Outgoing(Outgoing::Publish(_))) if qos == QoS::AtMostOnce => {
+                connection.completed.fetch_add(1, Ordering::Relaxed);
+            },
+            Ok(Event::Incoming(Packet::PubAck(_))) if qos == QoS::AtLeastOnce => {
+                connection.completed.fetch_add(1, Ordering::Relaxed);
+            },
+            Ok(Event::Incoming(Packet::PubComp(_))) if qos == QoS::ExactlyOnce => {
+                connection.completed.fetch_add(1, Ordering::Relaxed);
+            },
+            Ok(event) => debug!("MQTT: {:?}", event),
+            Err(e) => {
+                if connection.connected.swap(false, Ordering::Relaxed) {
+                    warn!("Lost connection to MQTT broker {}:{}: {}", config.host, config.port, e);
+                } else {
+                    debug!("Failed to connect to MQTT broker {}:{}: {}", config.host, config.port, e);
+                }
+                *connection.last_error.lock().unwrap() = Some(e.to_string());
+                tokio::time::sleep(RECONNECT_DELAY).await;
+            },
+        }
+    }
+}
+
+fn availability_topic(config: &MqttConfig) -> String {
+    format!("{}/bridge/availability", config.topic_prefix)
+}
+
+fn state_topic(config: &MqttConfig, id: u16) -> String {
+    format!("{}/{:04X}/state", config.topic_prefix, id)
+}
+
+fn backfill_topic(config: &MqttConfig, id: u16) -> String {
+    format!("{}/{:04X}/backfill", config.topic_prefix, id)
+}
+
+fn device_id(id: u16) -> String {
+    format!("soil_sensor_{:04x}", id)
+}
+
+/// Discovery configs for each entity of a sensor, by object ID. Moisture is reported as
+/// volumetric water content if the sensor is calibrated, or as the raw frequency if not.
+fn discovery_configs(config: &MqttConfig, measurement: &InfluxDBMeasurement) -> Vec<(&'static str, serde_json::Value)> {
+    let moisture = if measurement.moisture_vwc.is_some() {
+        ("moisture", "Moisture", "moisture_vwc", "%", "moisture", None)
+    } else {
+        ("moisture", "Moisture frequency", "moisture_level", "Hz", "frequency", None)
+    };
+    let entities = [
+        moisture,
+        ("temperature", "Temperature", "temperature", "°C", "temperature", None),
+        ("capacitor_voltage", "Capacitor voltage", "capacitor_voltage", "V", "voltage", Some("diagnostic")),
+        ("signal_strength", "Signal strength", "rssi", "dBm", "signal_strength", Some("diagnostic")),
+    ];
+
+    let device_id = device_id(measurement.id);
+    let name = measurement.sensor_name.clone().unwrap_or_else(|| format!("Soil sensor {:04X}", measurement.id));
+    let mut device = json!({
+        "identifiers": [device_id],
+        "connections": [["mac", measurement.mac_address]],
+        "name": name,
+        "model": "BLE Soil Moisture Sensor",
+    });
+    if let Some(firmware_version) = &measurement.firmware_version {
+        device["sw_version"] = json!(firmware_version);
+    }
+    if let Some(zone) = &measurement.zone {
+        device["suggested_area"] = json!(zone);
+    }
+
+    entities.into_iter().map(|(object, name, value, unit, device_class, category)| {
+        let mut entity = json!({
+            "name": name,
+            "unique_id": format!("{}_{}", device_id, object),
+            "state_topic": state_topic(config, measurement.id),
+            "value_template": format!("{{{{ value_json.{} }}}}", value),
+            "unit_of_measurement": unit,
+            "device_class": device_class,
+            "state_class": "measurement",
+            "availability_topic": availability_topic(config),
+            "device": device,
+        });
+        if let Some(category) = category {
+            entity["entity_category"] = json!(category);
+        }
+        (object, entity)
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::BytesMut;
+    use chrono::{Local, TimeZone};
+    use rumqttc::mqttbytes::{self, v4};
+    use rumqttc::{ConnAck, ConnectReturnCode, PubAck, Publish};
+    use soil_sensor_common::{Measurement, Status};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use tokio::sync::mpsc;
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// Accepts a single client, acknowledges everything it sends, and hands over every packet.
+    async fn broker() -> (u16, mpsc::UnboundedReceiver<v4::Packet>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (sender, receiver) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut incoming = BytesMut::new();
+            loop {
+                let packet = match v4::read(&mut incoming, 1024 * 1024) {
+                    Ok(packet) => packet,
+                    Err(mqttbytes::Error::InsufficientBytes(_)) => {
+                        if stream.read_buf(&mut incoming).await.unwrap() == 0 {
+                            return;
+                        }
+                        continue;
+                    },
+                    Err(e) => panic!("broker received a malformed packet: {:?}", e),
+                };
+                let mut reply = BytesMut::new();
+                let written = match &packet {
+                    v4::Packet::Connect(_) => ConnAck::new(ConnectReturnCode::Success, false).write(&mut reply),
+                    v4::Packet::Publish(publish) if publish.qos == QoS::AtLeastOnce =>
+                        PubAck::new(publish.pkid).write(&mut reply),
+                    v4::Packet::PingReq => v4::PingResp.write(&mut reply),
+                    _ => Ok(0),
+                };
+                written.unwrap();
+                stream.write_all(&reply).await.unwrap();
+                if sender.send(packet).is_err() {
+                    return;
+                }
+            }
+        });
+        (port, receiver)
+    }
+
+    async fn next_publish(packets: &mut mpsc::UnboundedReceiver<v4::Packet>) -> Publish {
+        loop {
+            match tokio::time::timeout(TIMEOUT, packets.recv()).await.unwrap().unwrap() {
+                v4::Packet::Publish(publish) => return publish,
+                _ => continue,
+            }
+        }
+    }
+
+    fn config(port: u16) -> MqttConfig {
+        toml::from_str(&format!("host = \"127.0.0.1\"\nport = {}\ntopic_prefix = \"garden\"", port)).unwrap()
+    }
+
+    fn measurement() -> InfluxDBMeasurement {
+        let measurement = Measurement {
+            id: 0x12ab,
+            moisture_frequency: 1234,
+            temperature: 86,
+            capacitor_voltage: 0,
+            sequence: 7,
+            status: Status::empty(),
+        };
+        InfluxDBMeasurement::new(&measurement, &[0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0xff], Local.timestamp_opt(1_700_000_000, 0).unwrap())
+            .with_rssi(Some(-60))
+    }
+
+    #[tokio::test]
+    async fn publishes_availability_with_a_last_will() {
+        let (port, mut packets) = broker().await;
+        let _sink = MqttSink::new(&config(port));
+
+        let v4::Packet::Connect(connect) = tokio::time::timeout(TIMEOUT, packets.recv()).await.unwrap().unwrap() else {
+            panic!("expected CONNECT first");
+        };
+        let will = connect.last_will.unwrap();
+        assert_eq!(will.topic, "garden/bridge/availability");
+        assert_eq!(&will.message[..], OFFLINE.as_bytes());
+        assert!(will.retain);
+
+        let online = next_publish(&mut packets).await;
+        assert_eq!(online.topic, "garden/bridge/availability");
+        assert_eq!(&online.payload[..], ONLINE.as_bytes());
+        assert!(online.retain);
+    }
+
+    #[tokio::test]
+    async fn announces_each_sensor_once_then_publishes_state() {
+        let (port, mut packets) = broker().await;
+        let sink = MqttSink::new(&config(port));
+        // Connected once the availability has been published
+        next_publish(&mut packets).await;
+        assert_eq!(sink.health(), Health::Healthy);
+
+        sink.write_batch(&[measurement()]).await.unwrap();
+        let mut discovery = Vec::new();
+        for _ in 0..4 {
+            let config = next_publish(&mut packets).await;
+            assert!(config.retain, "{} is not retained", config.topic);
+            let value: serde_json::Value = serde_json::from_slice(&config.payload).unwrap();
+            assert_eq!(value["state_topic"], "garden/12AB/state");
+            assert_eq!(value["availability_topic"], "garden/bridge/availability");
+            assert_eq!(value["device"]["identifiers"][0], "soil_sensor_12ab");
+            discovery.push((config.topic, value));
+        }
+        let topics: Vec<_> = discovery.iter().map(|(topic, _)| topic.as_str()).collect();
+        assert_eq!(topics, [
+            "homeassistant/sensor/soil_sensor_12ab/moisture/config",
+            "homeassistant/sensor/soil_sensor_12ab/temperature/config",
+            "homeassistant/sensor/soil_sensor_12ab/capacitor_voltage/config",
+            "homeassistant/sensor/soil_sensor_12ab/signal_strength/config",
+        ]);
+        assert_eq!(discovery[3].1["value_template"], "{{ value_json.rssi }}");
+
+        let state = next_publish(&mut packets).await;
+        assert_eq!(state.topic, "garden/12AB/state");
+        assert!(!state.retain);
+        let value: serde_json::Value = serde_json::from_slice(&state.payload).unwrap();
+        assert_eq!(value["rssi"], -60);
+        assert_eq!(value["moisture_level"], 1234);
+
+        // Already announced on this connection
+        sink.write_batch(&[measurement()]).await.unwrap();
+        assert_eq!(next_publish(&mut packets).await.topic, "garden/12AB/state");
+    }
+
+    #[tokio::test]
+    async fn backfilled_readings_leave_the_state_alone() {
+        let (port, mut packets) = broker().await;
+        let mut config = config(port);
+        config.discovery = false;
+        let sink = MqttSink::new(&config);
+        next_publish(&mut packets).await;
+
+        let mut backfilled = measurement();
+        backfilled.sequence = 3;
+        backfilled.status = Status::BUFFERED;
+        backfilled.rssi = None;
+        sink.write_batch(&[measurement(), backfilled]).await.unwrap();
+        let state = next_publish(&mut packets).await;
+        assert_eq!(state.topic, "garden/12AB/state");
+        let backfill = next_publish(&mut packets).await;
+        assert_eq!(backfill.topic, "garden/12AB/backfill");
+        assert!(!backfill.retain);
+        let value: serde_json::Value = serde_json::from_slice(&backfill.payload).unwrap();
+        assert_eq!(value["sequence"], 3);
+    }
+
+    #[tokio::test]
+    async fn refuses_writes_while_disconnected() {
+        // Nothing is listening once the listener is dropped
+        let port = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap().port();
+        let sink = MqttSink::new(&config(port));
+        assert!(matches!(sink.write_batch(&[measurement()]).await, Err(SinkError::Disconnected)));
+        assert!(matches!(sink.health(), Health::Unhealthy(_)));
+    }
+}
@@ -0,0 +1,310 @@
+//! On-disk queue of batches that a sink failed to write, so that they survive an outage, and
+//! restarts of the bridge.
+//!
+//! Each batch is a segment file of JSON lines, named by a counter so that they sort in the order
+//! they were written. Segments are written to a temporary file first, then renamed, so that a
+//! crash never leaves a half-written one behind.
+
+use std::collections::VecDeque;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use log::{info, warn};
+use soil_sensor_common::web::InfluxDBMeasurement;
+use crate::config::SpoolConfig;
+
+const SEGMENT_EXTENSION: &str = "jsonl";
+
+struct Segment {
+    id: u64,
+    measurements: usize,
+    bytes: u64,
+    written: SystemTime,
+}
+
+/// How much is waiting in a spool.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Backlog {
+    pub batches: usize,
+    pub measurements: usize,
+    pub bytes: u64,
+    /// When the oldest batch was spooled.
+    pub oldest: Option<SystemTime>,
+}
+
+pub struct Spool {
+    dir: PathBuf,
+    max_bytes: u64,
+    max_age: Duration,
+    segments: VecDeque<Segment>,
+    next_id: u64,
+    /// Totals over `segments`, kept up to date so that checking the limits is cheap
+    measurements: usize,
+    bytes: u64,
+}
+
+impl Spool {
+    /// Opens the spool in `dir`, creating it if necessary, and picks up any segments left from
+    /// before a restart.
+    pub fn open(dir: PathBuf, config: &SpoolConfig) -> io::Result<Self> {
+        fs::create_dir_all(&dir)?;
+        let mut segments = Vec::new();
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            match path.extension().and_then(|e| e.to_str()) {
+                Some(SEGMENT_EXTENSION) => (),
+                // Left behind by a crash while writing, so it was never part of the spool
+                Some("tmp") => {
+                    fs::remove_file(&path)?;
+                    continue;
+                },
+                _ => {
+                    warn!("Ignoring unexpected file in spool: {}", path.display());
+                    continue;
+                },
+            }
+            let Some(id) = path.file_stem().and_then(|s| s.to_str()).and_then(|s| s.parse().ok()) else {
+                warn!("Ignoring unexpected file in spool: {}", path.display());
+                continue;
+            };
+            let metadata = fs::metadata(&path)?;
+            let measurements = BufReader::new(fs::File::open(&path)?).lines().count();
+            segments.push(Segment { id, measurements, bytes: metadata.len(), written: metadata.modified()? });
+        }
+        segments.sort_by_key(|segment| segment.id);
+        let next_id = segments.last().map_or(0, |segment| segment.id + 1);
+        let measurements = segments.iter().map(|segment| segment.measurements).sum();
+        let bytes = segments.iter().map(|segment| segment.bytes).sum();
+
+        let spool = Self {
+            dir,
+            max_bytes: config.max_megabytes * 1024 * 1024,
+            max_age: Duration::from_secs(config.max_age_hours * 60 * 60),
+            segments: segments.into(),
+            next_id,
+            measurements,
+            bytes,
+        };
+        if !spool.is_empty() {
+            let backlog = spool.backlog();
+            info!("Spool {} has {} measurements waiting from before the restart",
+                spool.dir.display(), backlog.measurements);
+        }
+        Ok(spool)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.segments.is_empty()
+    }
+
+    pub fn backlog(&self) -> Backlog {
+        Backlog {
+            batches: self.segments.len(),
+            measurements: self.measurements,
+            bytes: self.bytes,
+            oldest: self.segments.front().map(|segment| segment.written),
+        }
+    }
+
+    fn path(&self, id: u64) -> PathBuf {
+        self.dir.join(format!("{:020}.{}", id, SEGMENT_EXTENSION))
+    }
+
+    /// Adds a batch to the back of the queue. Returns the number of older measurements which
+    /// were dropped to stay under the size limit.
+    pub fn push(&mut self, batch: &[InfluxDBMeasurement]) -> io::Result<usize> {
+        let mut contents = Vec::new();
+        for measurement in batch {
+            serde_json::to_writer(&mut contents, measurement)?;
+            contents.push(b'\n');
+        }
+
+        let id = self.next_id;
+        let path = self.path(id);
+        let temporary = path.with_extension("tmp");
+        let mut file = fs::File::create(&temporary)?;
+        file.write_all(&contents)?;
+        file.sync_all()?;
+        fs::rename(&temporary, &path)?;
+        self.next_id += 1;
+        self.measurements += batch.len();
+        self.bytes += contents.len() as u64;
+        self.segments.push_back(Segment {
+            id,
+            measurements: batch.len(),
+            bytes: contents.len() as u64,
+            written: SystemTime::now(),
+        });
+
+        let mut dropped = 0;
+        while self.bytes > self.max_bytes && self.segments.len() > 1 {
+            dropped += self.pop()?;
+        }
+        Ok(dropped)
+    }
+
+    /// Reads the oldest batch, without removing it. Lines which don't parse are skipped.
+    pub fn oldest(&self) -> io::Result<Option<Vec<InfluxDBMeasurement>>> {
+        let Some(segment) = self.segments.front() else {
+            return Ok(None);
+        };
+        let path = self.path(segment.id);
+        let mut batch = Vec::with_capacity(segment.measurements);
+        for (i, line) in BufReader::new(fs::File::open(&path)?).lines().enumerate() {
+            match serde_json::from_str(&line?) {
+                Ok(measurement) => batch.push(measurement),
+                Err(e) => warn!("Skipping line {} of {}: {}", i + 1, path.display(), e),
+            }
+        }
+        Ok(Some(batch))
+    }
+
+    /// Removes the oldest batch, and returns how many measurements were in it.
+    pub fn pop(&mut self) -> io::Result<usize> {
+        let Some(segment) = self.segments.front() else {
+            return Ok(0);
+        };
+        fs::remove_file(self.path(segment.id))?;
+        let Some(segment) = self.segments.pop_front() else {
+            return Ok(0);
+        };
+        self.measurements -= segment.measurements;
+        self.bytes -= segment.bytes;
+        Ok(segment.measurements)
+    }
+
+    /// Drops batches which have been waiting longer than the age limit, and returns how many
+    /// measurements were in them.
+    pub fn expire(&mut self) -> io::Result<usize> {
+        let mut dropped = 0;
+        while let Some(segment) = self.segments.front() {
+            let age = segment.written.elapsed().unwrap_or_default();
+            if age <= self.max_age {
+                break;
+            }
+            dropped += self.pop()?;
+        }
+        Ok(dropped)
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{Local, TimeZone};
+    use soil_sensor_common::{Measurement, Status};
+    use crate::testing::TemporaryDir;
+
+    fn config(max_megabytes: u64, max_age_hours: u64) -> SpoolConfig {
+        SpoolConfig { dir: None, max_megabytes, max_age_hours }
+    }
+
+    fn batch(sequences: std::ops::Range<u16>) -> Vec<InfluxDBMeasurement> {
+        sequences.map(|sequence| {
+            let measurement = Measurement {
+                id: 0x1234,
+                moisture_frequency: 1234,
+                temperature: 86,
+                capacitor_voltage: 0,
+                sequence,
+                status: Status::empty(),
+            };
+            InfluxDBMeasurement::new(
+                &measurement, &[0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0xff], Local.timestamp_opt(1_700_000_000, 0).unwrap())
+        }).collect()
+    }
+
+    fn sequences(batch: Option<Vec<InfluxDBMeasurement>>) -> Vec<u16> {
+        batch.unwrap().iter().map(|measurement| measurement.sequence).collect()
+    }
+
+    #[test]
+    fn replays_in_order_after_a_restart() {
+        let dir = TemporaryDir::new("spool");
+        let mut spool = Spool::open(dir.join("sink"), &config(100, 720)).unwrap();
+        assert!(spool.is_empty());
+        assert_eq!(spool.oldest().unwrap(), None);
+        // More than ten, so that the order can't come from sorting the names as strings
+        for i in 0..12 {
+            assert_eq!(spool.push(&batch(i * 2..i * 2 + 2)).unwrap(), 0);
+        }
+        assert_eq!(spool.backlog().batches, 12);
+        assert_eq!(spool.backlog().measurements, 24);
+        drop(spool);
+
+        let mut spool = Spool::open(dir.join("sink"), &config(100, 720)).unwrap();
+        assert_eq!(spool.backlog().measurements, 24);
+        for i in 0..12 {
+            assert_eq!(sequences(spool.oldest().unwrap()), [i * 2, i * 2 + 1]);
+            assert_eq!(spool.pop().unwrap(), 2);
+        }
+        assert!(spool.is_empty());
+        assert_eq!(spool.backlog(), Backlog::default());
+        // Numbering carries on after the segments which were there when it was opened
+        spool.push(&batch(0..1)).unwrap();
+        drop(spool);
+        let spool = Spool::open(dir.join("sink"), &config(100, 720)).unwrap();
+        assert_eq!(sequences(spool.oldest().unwrap()), [0]);
+    }
+
+    #[test]
+    fn size_limit_drops_the_oldest_batches() {
+        let dir = TemporaryDir::new("spool");
+        // No room at all, so only the newest batch is ever kept
+        let mut spool = Spool::open(dir.path().to_path_buf(), &config(0, 720)).unwrap();
+        assert_eq!(spool.push(&batch(0..3)).unwrap(), 0);
+        assert_eq!(spool.push(&batch(3..5)).unwrap(), 3);
+        assert_eq!(spool.push(&batch(5..6)).unwrap(), 2);
+        assert_eq!(spool.backlog().batches, 1);
+        assert_eq!(spool.backlog().measurements, 1);
+        assert_eq!(spool.backlog().bytes, fs::metadata(spool.path(2)).unwrap().len());
+        assert_eq!(sequences(spool.oldest().unwrap()), [5]);
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn age_limit_drops_the_oldest_batches() {
+        let dir = TemporaryDir::new("spool");
+        let mut spool = Spool::open(dir.path().to_path_buf(), &config(100, 1)).unwrap();
+        spool.push(&batch(0..2)).unwrap();
+        spool.push(&batch(2..3)).unwrap();
+        assert_eq!(spool.expire().unwrap(), 0);
+        let written = SystemTime::now() - Duration::from_secs(2 * 60 * 60);
+        fs::File::options().write(true).open(spool.path(0)).unwrap().set_modified(written).unwrap();
+        drop(spool);
+
+        // Ages are picked up from the files, so they survive a restart
+        let mut spool = Spool::open(dir.path().to_path_buf(), &config(100, 1)).unwrap();
+        assert_eq!(spool.backlog().oldest, Some(written));
+        assert_eq!(spool.expire().unwrap(), 2);
+        assert_eq!(spool.expire().unwrap(), 0);
+        assert_eq!(spool.backlog().batches, 1);
+        assert_eq!(sequences(spool.oldest().unwrap()), [2]);
+    }
+
+    #[test]
+    fn partial_writes_are_discarded_on_open() {
+        let dir = TemporaryDir::new("spool");
+        let mut spool = Spool::open(dir.path().to_path_buf(), &config(100, 720)).unwrap();
+        spool.push(&batch(0..2)).unwrap();
+        drop(spool);
+        // As if the bridge crashed while writing the next segment
+        fs::write(dir.join(format!("{:020}.tmp", 1)), "{\"id\":4660,\"mac_add").unwrap();
+        fs::write(dir.join("notes.txt"), "not a segment").unwrap();
+
+        let mut spool = Spool::open(dir.path().to_path_buf(), &config(100, 720)).unwrap();
+        assert!(!dir.join(format!("{:020}.tmp", 1)).exists());
+        assert!(dir.join("notes.txt").exists());
+        assert_eq!(spool.backlog().batches, 1);
+        assert_eq!(spool.backlog().measurements, 2);
+        spool.push(&batch(2..3)).unwrap();
+        assert_eq!(sequences(spool.oldest().unwrap()), [0, 1]);
+        spool.pop().unwrap();
+        assert_eq!(sequences(spool.oldest().unwrap()), [2]);
+    }
+}
@@ -0,0 +1,224 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use bluer::Address;
+use chrono::{DateTime, Local};
+use log::info;
+use soil_sensor_common::{DecodeError, Measurement, Status};
+use soil_sensor_common::sequence::{SequenceEvent, SequenceTracker, WINDOW};
+use soil_sensor_common::web::InfluxDBMeasurement;
+
+/// Counters for a single device. Keyed by MAC address rather than sensor ID, because a
+/// payload that fails to decode doesn't have an ID.
+#[derive(Debug, Default, Clone)]
+pub struct DeviceStats {
+    /// Advertisements carrying our manufacturer data, whether or not they decoded.
+    pub advertisements: u64,
+    pub decoded: u64,
+    pub decode_errors: u64,
+    pub last_error: Option<DecodeError>,
+    /// Payloads which decoded, but failed authentication or replay checks.
+    pub rejected: u64,
+    /// Signal strength of the device, as last reported by the adapter.
+    pub rssi: Option<i16>,
+}
+
+/// Counters for a single sensor, from the sequence numbers of its measurements. Keyed by sensor
+/// ID, since the same packet is often heard through more than one device.
+#[derive(Debug, Default, Clone)]
+pub struct SensorStats {
+    tracker: SequenceTracker,
+    /// The last few measurements from a sensor without a boot count, to tell repeats of them
+    /// apart from new measurements which reuse their sequence numbers after a reset.
+    recent: VecDeque<Measurement>,
+    pub received: u64,
+    /// Packets which were skipped over, and haven't turned up since.
+    pub missed: u64,
+    pub duplicates: u64,
+    pub out_of_order: u64,
+    /// Measurements which were buffered by the sensor, and sent later in a history.
+    pub backfilled: u64,
+    pub reboots: u64,
+    pub last_reboot: Option<DateTime<Local>>,
+    /// Status of the newest live measurement.
+    pub status: Status,
+    /// Measurements which had a probe fault, or too little energy to power the probe.
+    pub invalid_moisture: u64,
+    /// The newest measurement, live or backfilled, and the device it was heard through.
+    pub latest: Option<InfluxDBMeasurement>,
+    pub address: Option<Address>,
+}
+
+impl SensorStats {
+    /// Fraction of packets which were never received. Backfilled measurements count as received.
+    pub fn loss_rate(&self) -> f32 {
+        let expected = self.received + self.missed;
+        if expected == 0 { 0.0 } else { self.missed as f32 / expected as f32 }
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct Stats {
+    devices: Arc<Mutex<HashMap<Address, DeviceStats>>>,
+    sensors: Arc<Mutex<HashMap<u16, SensorStats>>>,
+}
+
+impl Stats {
+    pub fn record_advertisement(&self, addr: Address) {
+        let mut devices = self.devices.lock().unwrap();
+        devices.entry(addr).or_default().advertisements += 1;
+    }
+
+    pub fn record_rssi(&self, addr: Address, rssi: i16) {
+        let mut devices = self.devices.lock().unwrap();
+        devices.entry(addr).or_default().rssi = Some(rssi);
+    }
+
+    /// Signal strength of a device, as last reported by the adapter.
+    pub fn rssi(&self, addr: Address) -> Option<i16> {
+        self.devices.lock().unwrap().get(&addr).and_then(|device| device.rssi)
+    }
+
+    pub fn record_decoded(&self, addr: Address) {
+        let mut devices = self.devices.lock().unwrap();
+        devices.entry(addr).or_default().decoded += 1;
+    }
+
+    /// Returns the total number of decode errors seen from this device so far.
+    pub fn record_decode_error(&self, addr: Address, error: DecodeError) -> u64 {
+        let mut devices = self.devices.lock().unwrap();
+        let device = devices.entry(addr).or_default();
+        device.decode_errors += 1;
+        device.last_error = Some(error);
+        device.decode_errors
+    }
+
+    /// Returns the total number of rejected payloads seen from this device so far.
+    pub fn record_rejection(&self, addr: Address) -> u64 {
+        let mut devices = self.devices.lock().unwrap();
+        let device = devices.entry(addr).or_default();
+        device.rejected += 1;
+        device.rejected
+    }
+
+    /// Classifies a measurement by its sequence number, and returns the sensor's loss rate
+    /// including it.
+    ///
+    /// A sensor without a boot count reuses sequence numbers after a reset, so its measurements
+    /// are only duplicates if they match one heard recently. Any other measurement that the
+    /// tracker takes for a duplicate, or which is the first since the sensor reset, starts the
+    /// tracker over.
+    pub fn record_sequence(&self, measurement: &Measurement, boot_count: Option<u16>) -> (SequenceEvent, f32) {
+        let mut sensors = self.sensors.lock().unwrap();
+        let sensor = sensors.entry(measurement.id).or_default();
+        let sequence = measurement.sequence;
+        let event = if boot_count.is_some() {
+            sensor.tracker.update(boot_count, sequence)
+        } else if sensor.recent.contains(measurement) {
+            SequenceEvent::Duplicate
+        } else {
+            if sensor.recent.len() == WINDOW as usize {
+                sensor.recent.pop_front();
+            }
+            sensor.recent.push_back(*measurement);
+            if measurement.status.contains(Status::FIRST_AFTER_RESET) {
+                sensor.tracker.reset(None, sequence)
+            } else {
+                match sensor.tracker.update(None, sequence) {
+                    SequenceEvent::Duplicate | SequenceEvent::Stale => sensor.tracker.reset(None, sequence),
+                    event => event,
+                }
+            }
+        };
+        match event {
+            SequenceEvent::Duplicate | SequenceEvent::Stale => sensor.duplicates += 1,
+            SequenceEvent::OutOfOrder => {
+                // It was counted as missed when the packets after it arrived
+                sensor.received += 1;
+                sensor.out_of_order += 1;
+                sensor.missed = sensor.missed.saturating_sub(1);
+            },
+            event => {
+                sensor.received += 1;
+                sensor.missed += event.missed() as u64;
+                if let SequenceEvent::Reboot { .. } = event {
+                    sensor.reboots += 1;
+                    sensor.last_reboot = Some(Local::now());
+                }
+            }
+        }
+        (event, sensor.loss_rate())
+    }
+
+    /// Buffered measurements were counted as missed when the packets after them arrived, unless
+    /// they were sent before the bridge started.
+    pub fn record_backfilled(&self, id: u16, count: usize) {
+        let mut sensors = self.sensors.lock().unwrap();
+        let sensor = sensors.entry(id).or_default();
+        sensor.backfilled += count as u64;
+        sensor.received += count as u64;
+        sensor.missed = sensor.missed.saturating_sub(count as u64);
+    }
+
+    /// Remembers the status of a live measurement, and returns the flags which were raised and
+    /// cleared since the previous one.
+    pub fn record_status(&self, id: u16, status: Status) -> (Status, Status) {
+        let mut sensors = self.sensors.lock().unwrap();
+        let sensor = sensors.entry(id).or_default();
+        if !status.moisture_valid() {
+            sensor.invalid_moisture += 1;
+        }
+        let previous = std::mem::replace(&mut sensor.status, status);
+        (Status(status.0 & !previous.0), Status(previous.0 & !status.0))
+    }
+
+    /// Remembers a measurement which is about to be written, unless a newer one from the same
+    /// sensor has already been seen.
+    pub fn record_measurement(&self, addr: Address, measurement: &InfluxDBMeasurement) {
+        let mut sensors = self.sensors.lock().unwrap();
+        let sensor = sensors.entry(measurement.id).or_default();
+        if sensor.latest.as_ref().is_some_and(|latest| latest.time > measurement.time) {
+            return;
+        }
+        sensor.latest = Some(measurement.clone());
+        sensor.address = Some(addr);
+    }
+
+    /// A copy of every device's counters.
+    pub fn devices(&self) -> Vec<(Address, DeviceStats)> {
+        let devices = self.devices.lock().unwrap();
+        let mut devices: Vec<_> = devices.iter().map(|(addr, device)| (*addr, device.clone())).collect();
+        devices.sort_by_key(|(addr, _)| *addr);
+        devices
+    }
+
+    /// A copy of one sensor's counters.
+    pub fn sensor(&self, id: u16) -> Option<SensorStats> {
+        self.sensors.lock().unwrap().get(&id).cloned()
+    }
+
+    /// A copy of every sensor's counters, ordered by ID.
+    pub fn sensors(&self) -> Vec<(u16, SensorStats)> {
+        let sensors = self.sensors.lock().unwrap();
+        let mut sensors: Vec<_> = sensors.iter().map(|(id, sensor)| (*id, sensor.clone())).collect();
+        sensors.sort_by_key(|(id, _)| *id);
+        sensors
+    }
+
+    pub fn log_summary(&self) {
+        let devices = self.devices.lock().unwrap();
+        for (addr, device) in devices.iter().filter(|(_, d)| d.decode_errors > 0 || d.rejected > 0) {
+            info!("{}: {} decoded, {} rejected, {} failed to decode (last error: {})",
+                addr, device.decoded, device.rejected, device.decode_errors,
+                device.last_error.map(|e| e.to_string()).unwrap_or_default());
+        }
+
+        let sensors = self.sensors.lock().unwrap();
+        for (id, sensor) in sensors.iter() {
+            info!("Sensor {:04X}: {} received ({} backfilled, {} out of order, {} with invalid moisture), {} missed ({:.1}% loss), {} duplicates, {} reboots{}, status [{}]",
+                id, sensor.received, sensor.backfilled, sensor.out_of_order, sensor.invalid_moisture, sensor.missed,
+                sensor.loss_rate() * 100.0, sensor.duplicates, sensor.reboots,
+                sensor.last_reboot.map(|t| format!(" (last at {})", t.to_rfc3339())).unwrap_or_default(),
+                sensor.status.names().collect::<Vec<_>>().join(", "));
+        }
+    }
+}
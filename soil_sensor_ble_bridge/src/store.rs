@@ -0,0 +1,347 @@
+//! Local SQLite history of every measurement, so that data can be inspected on the bridge itself
+//! without any server.
+//!
+//! Measurements are written by a thread of their own, since SQLite blocks. The `history`
+//! subcommand reads them back with [`query`].
+
+use std::path::Path;
+use chrono::{DateTime, Local, NaiveDate, TimeZone};
+use log::{error, warn};
+use rusqlite::{params, Connection, OpenFlags, ToSql};
+use serde::Serialize;
+use soil_sensor_common::web::InfluxDBMeasurement;
+use tokio::sync::mpsc;
+
+/// Measurements waiting to be written. Beyond this, new ones are dropped.
+const QUEUE_LEN: usize = 1000;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS measurements (
+        id INTEGER PRIMARY KEY,
+        sensor_id INTEGER NOT NULL,
+        mac_address TEXT NOT NULL,
+        adapter TEXT NOT NULL,
+        rssi INTEGER,
+        -- Milliseconds since the Unix epoch
+        time INTEGER NOT NULL,
+        received INTEGER NOT NULL,
+        backfilled INTEGER NOT NULL,
+        moisture_level INTEGER NOT NULL,
+        moisture_vwc REAL,
+        temperature REAL,
+        capacitor_voltage REAL NOT NULL,
+        sequence INTEGER NOT NULL,
+        status INTEGER NOT NULL,
+        raw BLOB NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS measurements_sensor_time ON measurements (sensor_id, time);
+    CREATE INDEX IF NOT EXISTS measurements_time ON measurements (time);
+";
+
+/// A measurement, along with how it was received.
+#[derive(Debug, Clone, Serialize)]
+pub struct Row {
+    pub sensor_id: u16,
+    pub mac_address: String,
+    pub adapter: String,
+    /// Signal strength of the device when the measurement was received, if known
+    pub rssi: Option<i16>,
+    /// When the measurement was taken. Earlier than `received` for backfilled measurements.
+    pub time: DateTime<Local>,
+    pub received: DateTime<Local>,
+    pub backfilled: bool,
+    pub moisture_level: u32,
+    pub moisture_vwc: Option<f32>,
+    pub temperature: Option<f32>,
+    pub capacitor_voltage: f32,
+    pub sequence: u16,
+    pub status: u8,
+    /// The payload the measurement came from
+    #[serde(serialize_with = "serialize_hex")]
+    pub raw: Vec<u8>,
+}
+
+impl Row {
+    pub fn new(measurement: &InfluxDBMeasurement, adapter: &str, rssi: Option<i16>, backfilled: bool, raw: &[u8]) -> Self {
+        Self {
+            sensor_id: measurement.id,
+            mac_address: measurement.mac_address.clone(),
+            adapter: adapter.to_string(),
+            rssi,
+            time: measurement.time,
+            received: Local::now(),
+            backfilled,
+            moisture_level: measurement.moisture_level,
+            moisture_vwc: measurement.moisture_vwc,
+            temperature: measurement.temperature,
+            capacitor_voltage: measurement.capacitor_voltage,
+            sequence: measurement.sequence,
+            status: measurement.status.0,
+            raw: raw.to_vec(),
+        }
+    }
+}
+
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn serialize_hex<S: serde::Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&hex(bytes))
+}
+
+/// Hands measurements to the writer thread.
+pub struct Store {
+    queue: mpsc::Sender<Row>,
+}
+
+impl Store {
+    /// Opens the database, creating it if necessary, and starts the writer thread.
+    pub fn open(path: &Path) -> rusqlite::Result<Self> {
+        let connection = Connection::open(path)?;
+        connection.pragma_update(None, "journal_mode", "WAL")?;
+        connection.execute_batch(SCHEMA)?;
+
+        let (queue, receiver) = mpsc::channel(QUEUE_LEN);
+        std::thread::spawn(move || write_rows(connection, receiver));
+        Ok(Self { queue })
+    }
+
+    /// Queues a measurement to be written, without waiting for it.
+    pub fn record(&self, row: Row) {
+        if self.queue.try_send(row).is_err() {
+            warn!("History store is too far behind, dropping a measurement");
+        }
+    }
+}
+
+fn write_rows(connection: Connection, mut receiver: mpsc::Receiver<Row>) {
+    while let Some(row) = receiver.blocking_recv() {
+        let result = connection.execute(
+            "INSERT INTO measurements (sensor_id, mac_address, adapter, rssi, time, received, backfilled,
+                moisture_level, moisture_vwc, temperature, capacitor_voltage, sequence, status, raw)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14)",
+            params![
+                row.sensor_id, row.mac_address, row.adapter, row.rssi,
+                row.time.timestamp_millis(), row.received.timestamp_millis(), row.backfilled,
+                row.moisture_level, row.moisture_vwc, row.temperature, row.capacitor_voltage,
+                row.sequence, row.status, row.raw,
+            ],
+        );
+        if let Err(e) = result {
+            error!("Failed to write measurement from sensor {:04X} to history: {}", row.sensor_id, e);
+        }
+    }
+}
+
+/// Which measurements to read back. Every condition that is set must match.
+#[derive(Debug, Default)]
+pub struct Query {
+    pub sensor_id: Option<u16>,
+    /// Lowercase, like `InfluxDBMeasurement::mac_address`
+    pub mac_address: Option<String>,
+    pub since: Option<DateTime<Local>>,
+    pub until: Option<DateTime<Local>>,
+    /// Only the newest this many
+    pub limit: Option<u32>,
+}
+
+/// Parses an RFC 3339 time, a date (meaning local midnight), or a duration like `6h`, meaning
+/// that long ago.
+pub fn parse_time(s: &str) -> Result<DateTime<Local>, String> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(s) {
+        return Ok(time.with_timezone(&Local));
+    }
+    if let Ok(date) = NaiveDate::parse_from_str(s, "%Y-%m-%d") {
+        return Local.from_local_datetime(&date.and_hms_opt(0, 0, 0).unwrap_or_default())
+            .earliest()
+            .ok_or_else(|| format!("{} has no local midnight", s));
+    }
+    let invalid = || "expected an RFC 3339 time, a date like 2024-05-01, or a time ago like 6h".to_string();
+    let seconds = match s.chars().last() {
+        Some('s') => 1,
+        Some('m') => 60,
+        Some('h') => 60 * 60,
+        Some('d') => 24 * 60 * 60,
+        _ => return Err(invalid()),
+    };
+    let count: i64 = s[..s.len() - 1].parse().map_err(|_| invalid())?;
+    count.checked_mul(seconds)
+        .and_then(chrono::Duration::try_seconds)
+        .and_then(|ago| Local::now().checked_sub_signed(ago))
+        .ok_or_else(|| format!("{} is too long ago", s))
+}
+
+/// Reads measurements matching the query, oldest first.
+pub fn query(path: &Path, query: &Query) -> rusqlite::Result<Vec<Row>> {
+    let connection = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+
+    let mut conditions = Vec::new();
+    let mut values: Vec<Box<dyn ToSql>> = Vec::new();
+    if let Some(sensor_id) = query.sensor_id {
+        conditions.push("sensor_id = ?");
+        values.push(Box::new(sensor_id));
+    }
+    if let Some(mac_address) = &query.mac_address {
+        conditions.push("mac_address = ?");
+        values.push(Box::new(mac_address.clone()));
+    }
+    if let Some(since) = query.since {
+        conditions.push("time >= ?");
+        values.push(Box::new(since.timestamp_millis()));
+    }
+    if let Some(until) = query.until {
+        conditions.push("time < ?");
+        values.push(Box::new(until.timestamp_millis()));
+    }
+    let filter = if conditions.is_empty() { String::new() } else { format!("WHERE {}", conditions.join(" AND ")) };
+    let limit = query.limit.map(|limit| format!("LIMIT {}", limit)).unwrap_or_default();
+    // Newest first, so the limit keeps the newest, then back into order
+    let sql = format!(
+        "SELECT sensor_id, mac_address, adapter, rssi, time, received, backfilled, moisture_level, moisture_vwc,
+            temperature, capacitor_voltage, sequence, status, raw
+         FROM measurements {} ORDER BY time DESC, id DESC {}", filter, limit);
+
+    let mut statement = connection.prepare(&sql)?;
+    let rows = statement.query_map(rusqlite::params_from_iter(values.iter()), |row| {
+        Ok(Row {
+            sensor_id: row.get(0)?,
+            mac_address: row.get(1)?,
+            adapter: row.get(2)?,
+            rssi: row.get(3)?,
+            time: from_millis(row.get(4)?),
+            received: from_millis(row.get(5)?),
+            backfilled: row.get(6)?,
+            moisture_level: row.get(7)?,
+            moisture_vwc: row.get(8)?,
+            temperature: row.get(9)?,
+            capacitor_voltage: row.get(10)?,
+            sequence: row.get(11)?,
+            status: row.get(12)?,
+            raw: row.get(13)?,
+        })
+    })?;
+    let mut rows = rows.collect::<rusqlite::Result<Vec<_>>>()?;
+    rows.reverse();
+    Ok(rows)
+}
+
+fn from_millis(millis: i64) -> DateTime<Local> {
+    Local.timestamp_millis_opt(millis).single().unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use soil_sensor_common::{Measurement, Status};
+    use crate::testing::TemporaryDir;
+
+    const START: i64 = 1_700_000_000;
+
+    fn row(id: u16, address: [u8; 6], seconds: i64, sequence: u16) -> Row {
+        let measurement = Measurement {
+            id,
+            moisture_frequency: 1234,
+            temperature: 86,
+            capacitor_voltage: 0,
+            sequence,
+            status: Status::BUFFERED,
+        };
+        let measurement = InfluxDBMeasurement::new(&measurement, &address, Local.timestamp_opt(seconds, 0).unwrap());
+        Row::new(&measurement, "hci0", Some(-60), true, &[0x01, 0xab])
+    }
+
+    /// A database with two sensors, one of which moved to another device, with a measurement
+    /// from each every minute.
+    fn database(dir: &TemporaryDir) -> std::path::PathBuf {
+        let path = dir.join("history.db");
+        let connection = Connection::open(&path).unwrap();
+        connection.execute_batch(SCHEMA).unwrap();
+        let (queue, receiver) = mpsc::channel(QUEUE_LEN);
+        for i in 0..10 {
+            let first = if i < 5 { [0xaa; 6] } else { [0xbb; 6] };
+            queue.try_send(row(0x0001, first, START + i * 60, i as u16)).unwrap();
+            queue.try_send(row(0x0002, [0xcc; 6], START + i * 60, 100 + i as u16)).unwrap();
+        }
+        drop(queue);
+        write_rows(connection, receiver);
+        path
+    }
+
+    fn sequences(rows: &[Row]) -> Vec<u16> {
+        rows.iter().map(|row| row.sequence).collect()
+    }
+
+    fn at(seconds: i64) -> Option<DateTime<Local>> {
+        Some(Local.timestamp_opt(seconds, 0).unwrap())
+    }
+
+    #[test]
+    fn parses_times() {
+        assert_eq!(parse_time("2024-05-01T12:30:00Z").unwrap(), Local.timestamp_opt(1_714_566_600, 0).unwrap());
+        assert_eq!(parse_time("2024-05-01T12:30:00+02:00").unwrap(), Local.timestamp_opt(1_714_559_400, 0).unwrap());
+        let midnight = NaiveDate::from_ymd_opt(2024, 5, 1).unwrap().and_hms_opt(0, 0, 0).unwrap();
+        assert_eq!(parse_time("2024-05-01").unwrap().naive_local(), midnight);
+
+        for (s, seconds) in [("90s", 90), ("30m", 30 * 60), ("6h", 6 * 60 * 60), ("2d", 2 * 24 * 60 * 60)] {
+            let ago = chrono::Duration::seconds(seconds);
+            let before = Local::now();
+            let time = parse_time(s).unwrap();
+            assert!(before - ago <= time && time <= Local::now() - ago, "{} parsed as {}", s, time);
+        }
+
+        for s in ["", "h", "6", "6w", "-h", "yesterday", "2024-13-01"] {
+            assert!(parse_time(s).is_err(), "{} parsed", s);
+        }
+        assert_eq!(parse_time("9223372036854775807d").unwrap_err(), "9223372036854775807d is too long ago");
+    }
+
+    #[test]
+    fn reads_back_what_was_written() {
+        let dir = TemporaryDir::new("store");
+        let path = database(&dir);
+        let rows = query(&path, &Query::default()).unwrap();
+        assert_eq!(rows.len(), 20);
+        let row = &rows[0];
+        assert_eq!(row.time, Local.timestamp_opt(START, 0).unwrap());
+        assert_eq!((row.sensor_id, row.sequence, row.mac_address.as_str()), (0x0001, 0, "aa:aa:aa:aa:aa:aa"));
+        assert_eq!((row.adapter.as_str(), row.rssi, row.backfilled), ("hci0", Some(-60), true));
+        assert_eq!((row.moisture_level, row.status), (1234, Status::BUFFERED.0));
+        assert_eq!(row.raw, [0x01, 0xab]);
+        // Oldest first, and in the order they were written within the same time
+        assert_eq!(sequences(&rows[..4]), [0, 100, 1, 101]);
+    }
+
+    #[test]
+    fn filters_and_limits() {
+        let dir = TemporaryDir::new("store");
+        let path = database(&dir);
+
+        let sensor = |sensor_id| Query { sensor_id: Some(sensor_id), ..Query::default() };
+        assert_eq!(sequences(&query(&path, &sensor(0x0002)).unwrap()), (100..110).collect::<Vec<_>>());
+        assert!(query(&path, &sensor(0x0003)).unwrap().is_empty());
+
+        let device = Query { mac_address: Some("bb:bb:bb:bb:bb:bb".to_string()), ..Query::default() };
+        assert_eq!(sequences(&query(&path, &device).unwrap()), [5, 6, 7, 8, 9]);
+
+        // `since` is inclusive, and `until` exclusive
+        let range = Query { sensor_id: Some(0x0001), since: at(START + 2 * 60), until: at(START + 5 * 60), ..Query::default() };
+        assert_eq!(sequences(&query(&path, &range).unwrap()), [2, 3, 4]);
+
+        // The limit keeps the newest, still oldest first
+        let newest = Query { sensor_id: Some(0x0001), limit: Some(3), ..Query::default() };
+        assert_eq!(sequences(&query(&path, &newest).unwrap()), [7, 8, 9]);
+        let everything = Query { limit: Some(3), until: at(START + 60), ..Query::default() };
+        assert_eq!(sequences(&query(&path, &everything).unwrap()), [0, 100]);
+        let all = Query { sensor_id: Some(0x0001), mac_address: Some("aa:aa:aa:aa:aa:aa".to_string()), since: at(START + 4 * 60), limit: Some(10), ..Query::default() };
+        assert_eq!(sequences(&query(&path, &all).unwrap()), [4]);
+    }
+
+    #[test]
+    fn missing_database() {
+        let dir = TemporaryDir::new("store");
+        // Opened read-only, so it isn't created
+        assert!(query(&dir.join("history.db"), &Query::default()).is_err());
+        assert!(!dir.join("history.db").exists());
+    }
+}
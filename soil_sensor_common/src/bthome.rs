@@ -0,0 +1,187 @@
+//! BTHome v2 service data, so that Home Assistant and other BTHome receivers can read sensors
+//! without knowing our own payload format. See <https://bthome.io/format/>.
+//!
+//! The service data (for UUID 0xFCD2) is a device information byte, followed by objects. Each
+//! object is a one byte ID, followed by a little-endian value whose type and scale depend on the
+//! ID. Objects are sent in increasing order of ID.
+//!
+//! ```text
+//!  | device info (1) | id (1) | value | id (1) | value | ... |
+//! ```
+//!
+//! The decoder skips objects it knows the length of but doesn't use, and gives up at the first
+//! one it doesn't know, since there is no way to find where the next one starts.
+
+use core::fmt;
+use crate::Measurement;
+
+/// 16-bit service UUID assigned to BTHome.
+pub const SERVICE_UUID: u16 = 0xFCD2;
+
+/// Version of the format, which goes in the top 3 bits of the device information byte.
+pub const VERSION: u8 = 2;
+
+const VERSION_SHIFT: u32 = 5;
+
+/// The objects are encrypted with AES-CCM. Not supported here.
+pub const DEVICE_INFO_ENCRYPTED: u8 = 1 << 0;
+
+/// The device advertises when something happens, rather than at a regular interval.
+pub const DEVICE_INFO_TRIGGER_BASED: u8 = 1 << 2;
+
+/// uint8. Receivers use it to drop repeated advertisements.
+pub const OBJECT_PACKET_ID: u8 = 0x00;
+/// sint16, 0.01 degrees C.
+pub const OBJECT_TEMPERATURE: u8 = 0x02;
+/// uint16, 0.001 V.
+pub const OBJECT_VOLTAGE: u8 = 0x0C;
+/// uint16, 0.01 %.
+pub const OBJECT_MOISTURE: u8 = 0x14;
+/// uint8, 1 %.
+pub const OBJECT_MOISTURE_COARSE: u8 = 0x2F;
+/// sint16, 0.1 degrees C.
+pub const OBJECT_TEMPERATURE_COARSE: u8 = 0x45;
+/// uint16, 0.1 V.
+pub const OBJECT_VOLTAGE_COARSE: u8 = 0x4A;
+
+/// Device information plus the packet ID, temperature, voltage and moisture objects.
+pub const MAX_SERVICE_DATA_LEN: usize = 1 + 2 + 3 + 3 + 3;
+
+/// Length of the value of a fixed-size object, or `None` for IDs that aren't known here.
+const fn object_len(id: u8) -> Option<usize> {
+    match id {
+        0x00 | 0x01 | 0x09 | 0x0F..=0x11 | 0x15..=0x2F | 0x3A | 0x46 => Some(1),
+        0x02 | 0x03 | 0x06..=0x08 | 0x0C..=0x0E | 0x12..=0x14 | 0x3C | 0x3D | 0x3F..=0x41
+        | 0x43..=0x45 | 0x47..=0x4A | 0x51 | 0x52 => Some(2),
+        0x04 | 0x05 | 0x0A | 0x0B | 0x42 | 0x4B => Some(3),
+        0x3E | 0x4C..=0x50 => Some(4),
+        _ => None,
+    }
+}
+
+/// Reasons that BTHome service data could not be decoded.
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum BthomeError {
+    /// There is no device information byte.
+    Empty,
+    /// The device information byte names a version other than 2.
+    UnknownVersion(u8),
+    /// The objects are encrypted.
+    Encrypted,
+    /// An object ID whose length isn't known, so nothing after it can be decoded.
+    UnknownObject(u8),
+    /// The service data ends in the middle of this object.
+    Truncated(u8),
+}
+
+impl fmt::Display for BthomeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Empty => write!(f, "service data is empty"),
+            Self::UnknownVersion(version) => write!(f, "unknown BTHome version {}", version),
+            Self::Encrypted => write!(f, "encrypted BTHome data is not supported"),
+            Self::UnknownObject(id) => write!(f, "unknown object ID {:#04x}", id),
+            Self::Truncated(id) => write!(f, "service data ends in the middle of object {:#04x}", id),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for BthomeError {}
+
+/// The BTHome objects that a soil sensor sends. Anything which is `None` is left out.
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct BthomeData {
+    pub packet_id: Option<u8>,
+    /// Degrees C, sent in steps of 0.01.
+    pub temperature: Option<f32>,
+    /// Volts, sent in steps of 0.001.
+    pub voltage: Option<f32>,
+    /// Percent, sent in steps of 0.01.
+    pub moisture: Option<f32>,
+}
+
+impl BthomeData {
+    /// Moisture has to be converted into a percentage with a calibration first, since BTHome
+    /// has nowhere to put a raw frequency. The packet ID is the low byte of the sequence number.
+    pub fn from_measurement(measurement: &Measurement, moisture: Option<f32>) -> Self {
+        Self {
+            packet_id: Some(measurement.sequence as u8),
+            temperature: measurement.temperature_celsius(),
+            voltage: Some(measurement.capacitor_volts()),
+            moisture,
+        }
+    }
+
+    /// Writes the device information byte and objects into the start of `buf`, and returns the
+    /// number of bytes written. The service UUID is not included.
+    pub fn encode(&self, buf: &mut [u8; MAX_SERVICE_DATA_LEN]) -> usize {
+        buf[0] = VERSION << VERSION_SHIFT;
+        let mut len = 1;
+        let mut object = |id: u8, value: &[u8]| {
+            buf[len] = id;
+            buf[len + 1..len + 1 + value.len()].copy_from_slice(value);
+            len += 1 + value.len();
+        };
+
+        if let Some(packet_id) = self.packet_id {
+            object(OBJECT_PACKET_ID, &[packet_id]);
+        }
+        if let Some(temperature) = self.temperature {
+            let value = round(temperature * 100.0).clamp(i16::MIN as i32, i16::MAX as i32) as i16;
+            object(OBJECT_TEMPERATURE, &value.to_le_bytes());
+        }
+        if let Some(voltage) = self.voltage {
+            let value = round(voltage * 1000.0).clamp(0, u16::MAX as i32) as u16;
+            object(OBJECT_VOLTAGE, &value.to_le_bytes());
+        }
+        if let Some(moisture) = self.moisture {
+            let value = round(moisture * 100.0).clamp(0, u16::MAX as i32) as u16;
+            object(OBJECT_MOISTURE, &value.to_le_bytes());
+        }
+        len
+    }
+
+    /// Decodes service data for the BTHome UUID, not including the UUID itself.
+    pub fn decode(bytes: &[u8]) -> Result<Self, BthomeError> {
+        let (&info, mut objects) = bytes.split_first().ok_or(BthomeError::Empty)?;
+        let version = info >> VERSION_SHIFT;
+        if version != VERSION {
+            return Err(BthomeError::UnknownVersion(version));
+        }
+        if info & DEVICE_INFO_ENCRYPTED != 0 {
+            return Err(BthomeError::Encrypted);
+        }
+
+        let mut data = Self::default();
+        while let [id, rest @ ..] = objects {
+            let len = object_len(*id).ok_or(BthomeError::UnknownObject(*id))?;
+            if rest.len() < len {
+                return Err(BthomeError::Truncated(*id));
+            }
+            let (value, rest) = rest.split_at(len);
+            objects = rest;
+
+            let uint = || value.iter().rev().fold(0u32, |acc, byte| (acc << 8) | *byte as u32);
+            match *id {
+                OBJECT_PACKET_ID => data.packet_id = Some(value[0]),
+                OBJECT_TEMPERATURE => data.temperature = Some(uint() as u16 as i16 as f32 * 0.01),
+                OBJECT_TEMPERATURE_COARSE => data.temperature = Some(uint() as u16 as i16 as f32 * 0.1),
+                OBJECT_VOLTAGE => data.voltage = Some(uint() as f32 * 0.001),
+                OBJECT_VOLTAGE_COARSE => data.voltage = Some(uint() as f32 * 0.1),
+                OBJECT_MOISTURE => data.moisture = Some(uint() as f32 * 0.01),
+                OBJECT_MOISTURE_COARSE => data.moisture = Some(uint() as f32),
+                _ => (),
+            }
+        }
+        Ok(data)
+    }
+}
+
+/// Rounds to the nearest integer, since `f32::round` needs std.
+fn round(value: f32) -> i32 {
+    if value < 0.0 { (value - 0.5) as i32 } else { (value + 0.5) as i32 }
+}
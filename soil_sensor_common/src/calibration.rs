@@ -0,0 +1,142 @@
+//! Conversion of probe oscillator frequency into volumetric water content (VWC).
+//!
+//! Every probe and soil type responds differently, so each sensor needs its own calibration.
+//! A calibration always has a dry and a wet anchor point, measured by putting the probe into
+//! dry and saturated soil. The curve between them is either a straight line, a piecewise-linear
+//! curve through extra intermediate points, or a polynomial. Readings outside the anchors are
+//! clamped to them.
+
+use core::fmt;
+
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct CalibrationPoint {
+    /// Probe oscillator frequency, see [`crate::Measurement::moisture_hz`]
+    pub hz: f32,
+    /// Volumetric water content, in percent
+    pub vwc: f32,
+}
+
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Curve<'a> {
+    /// A straight line between the dry and wet anchors.
+    Linear,
+    /// Straight lines between the dry anchor, each of these points in order, and the wet anchor.
+    PiecewiseLinear(&'a [CalibrationPoint]),
+    /// A polynomial in frequency, with coefficients in increasing order of power.
+    Polynomial(&'a [f32]),
+}
+
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Calibration<'a> {
+    pub dry: CalibrationPoint,
+    pub wet: CalibrationPoint,
+    pub curve: Curve<'a>,
+}
+
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum CalibrationError {
+    /// The dry and wet anchors have the same frequency.
+    AnchorsEqual,
+    /// Piecewise-linear points must be strictly between the anchors, ordered from dry to wet.
+    NotMonotonic,
+    /// A polynomial needs at least one coefficient.
+    EmptyPolynomial,
+}
+
+impl fmt::Display for CalibrationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::AnchorsEqual =>
+                write!(f, "dry and wet anchors have the same frequency"),
+            Self::NotMonotonic =>
+                write!(f, "calibration points must be strictly between the anchors, in order from dry to wet"),
+            Self::EmptyPolynomial =>
+                write!(f, "polynomial has no coefficients"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for CalibrationError {}
+
+impl<'a> Calibration<'a> {
+    pub const fn linear(dry: CalibrationPoint, wet: CalibrationPoint) -> Self {
+        Self { dry, wet, curve: Curve::Linear }
+    }
+
+    pub fn validate(&self) -> Result<(), CalibrationError> {
+        if self.dry.hz == self.wet.hz {
+            return Err(CalibrationError::AnchorsEqual);
+        }
+        match self.curve {
+            Curve::Linear => Ok(()),
+            Curve::PiecewiseLinear(_) => {
+                // Frequency may either increase or decrease with moisture, but it must be consistent
+                let increasing = self.wet.hz > self.dry.hz;
+                let mut points = self.points();
+                let mut previous = points.next().unwrap_or(self.dry);
+                for point in points {
+                    if point.hz == previous.hz || (point.hz > previous.hz) != increasing {
+                        return Err(CalibrationError::NotMonotonic);
+                    }
+                    previous = point;
+                }
+                Ok(())
+            },
+            Curve::Polynomial([]) => Err(CalibrationError::EmptyPolynomial),
+            Curve::Polynomial(_) => Ok(()),
+        }
+    }
+
+    /// Volumetric water content, in percent, for the given probe frequency. The calibration
+    /// should have been checked with [`Calibration::validate`].
+    pub fn vwc(&self, hz: f32) -> f32 {
+        let (low, high) = if self.dry.hz < self.wet.hz { (self.dry, self.wet) } else { (self.wet, self.dry) };
+        let hz = hz.clamp(low.hz, high.hz);
+
+        let vwc = match self.curve {
+            Curve::Linear => interpolate(self.dry, self.wet, hz),
+            Curve::PiecewiseLinear(_) => {
+                let mut points = self.points();
+                let mut previous = points.next().unwrap_or(self.dry);
+                let mut vwc = self.wet.vwc;
+                for point in points {
+                    if (previous.hz.min(point.hz)..=previous.hz.max(point.hz)).contains(&hz) {
+                        vwc = interpolate(previous, point, hz);
+                        break;
+                    }
+                    previous = point;
+                }
+                vwc
+            },
+            // Horner's method
+            Curve::Polynomial(coefficients) => coefficients.iter().rev().fold(0.0, |acc, c| acc * hz + c),
+        };
+
+        let (min, max) = (self.dry.vwc.min(self.wet.vwc), self.dry.vwc.max(self.wet.vwc));
+        vwc.clamp(min, max)
+    }
+
+    /// Every point on a piecewise-linear curve, including both anchors, from dry to wet.
+    fn points(&self) -> impl Iterator<Item = CalibrationPoint> + 'a {
+        let intermediate: &'a [CalibrationPoint] = match self.curve {
+            Curve::PiecewiseLinear(points) => points,
+            _ => &[],
+        };
+        core::iter::once(self.dry)
+            .chain(intermediate.iter().copied())
+            .chain(core::iter::once(self.wet))
+    }
+}
+
+fn interpolate(a: CalibrationPoint, b: CalibrationPoint, hz: f32) -> f32 {
+    if a.hz == b.hz {
+        return a.vwc;
+    }
+    a.vwc + (hz - a.hz) * (b.vwc - a.vwc) / (b.hz - a.hz)
+}
@@ -0,0 +1,35 @@
+//! Temperature compensation of the moisture probe oscillator.
+//!
+//! The oscillator frequency drifts with temperature, which shows up as a daily swing in the
+//! moisture reading. The drift is modelled as linear in temperature around a reference point,
+//! and removed before the frequency is calibrated.
+
+use crate::Measurement;
+
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct TemperatureCompensation {
+    /// Temperature at which no correction is applied
+    pub reference_celsius: f32,
+    /// Change in oscillator frequency per degree above the reference
+    pub hz_per_celsius: f32,
+}
+
+impl TemperatureCompensation {
+    /// The frequency that would have been measured at the reference temperature.
+    pub fn compensate(&self, hz: f32, celsius: f32) -> f32 {
+        hz - self.hz_per_celsius * (celsius - self.reference_celsius)
+    }
+}
+
+impl Measurement {
+    /// [`Measurement::moisture_hz`], corrected for temperature. If the measurement has no valid
+    /// temperature, the uncorrected frequency is returned.
+    pub fn compensated_moisture_hz(&self, compensation: &TemperatureCompensation) -> f32 {
+        match self.temperature_celsius() {
+            Some(celsius) => compensation.compensate(self.moisture_hz(), celsius),
+            None => self.moisture_hz(),
+        }
+    }
+}
@@ -0,0 +1,340 @@
+//! AES-128 based primitives used to authenticate and encrypt advertisements.
+//!
+//! The block cipher itself is abstracted behind [`BlockEncrypt`], so that the firmware can use the
+//! SoftDevice's hardware ECB peripheral while the bridge uses a software implementation. Only the
+//! encrypt direction is ever needed.
+
+pub const KEY_LEN: usize = 16;
+pub const BLOCK_LEN: usize = 16;
+
+pub type Key = [u8; KEY_LEN];
+pub type Block = [u8; BLOCK_LEN];
+
+/// An AES-128 block cipher with a fixed key, encrypting a single block in place.
+pub trait BlockEncrypt {
+    fn encrypt_block(&mut self, block: &mut Block);
+}
+
+/// AES-CMAC (RFC 4493) of `message`.
+pub fn cmac<C: BlockEncrypt + ?Sized>(cipher: &mut C, message: &[u8]) -> Block {
+    let mut l: Block = [0; BLOCK_LEN];
+    cipher.encrypt_block(&mut l);
+    let k1 = double(&l);
+    let k2 = double(&k1);
+
+    // The last block is treated specially, and is the only one that may be partial. An empty
+    // message still has one (empty, padded) last block.
+    let last_start = if message.is_empty() { 0 } else { (message.len() - 1) / BLOCK_LEN * BLOCK_LEN };
+    let (full_blocks, last) = message.split_at(last_start);
+
+    let mut x: Block = [0; BLOCK_LEN];
+    for block in full_blocks.chunks_exact(BLOCK_LEN) {
+        xor_into(&mut x, block);
+        cipher.encrypt_block(&mut x);
+    }
+
+    let mut last_block: Block = [0; BLOCK_LEN];
+    last_block[..last.len()].copy_from_slice(last);
+    if last.len() == BLOCK_LEN {
+        xor_into(&mut last_block, &k1);
+    } else {
+        last_block[last.len()] = 0x80;
+        xor_into(&mut last_block, &k2);
+    }
+    xor_into(&mut x, &last_block);
+    cipher.encrypt_block(&mut x);
+
+    x
+}
+
+/// CCM (RFC 3610) with a 2 byte length field, which leaves 13 bytes for the nonce.
+pub const CCM_NONCE_LEN: usize = 13;
+const CCM_L: usize = BLOCK_LEN - 1 - CCM_NONCE_LEN;
+
+pub type Nonce = [u8; CCM_NONCE_LEN];
+
+/// AES-CCM encryption of `data` in place, returning an `M` byte tag which also covers `aad`.
+///
+/// `M` must be one of 4, 6, 8, 10, 12, 14 or 16, and `data` must be shorter than 64KiB.
+pub fn ccm_seal<C: BlockEncrypt + ?Sized, const M: usize>(
+    cipher: &mut C, nonce: &Nonce, aad: &[u8], data: &mut [u8]
+) -> [u8; M] {
+    let mac = ccm_cbc_mac::<C, M>(cipher, nonce, aad, data);
+    let s0 = ccm_ctr(cipher, nonce, data);
+
+    let mut tag = [0u8; M];
+    for i in 0..M {
+        tag[i] = mac[i] ^ s0[i];
+    }
+    tag
+}
+
+/// AES-CCM decryption of `data` in place. Returns false, and leaves `data` in an unspecified
+/// state, if the tag does not match.
+pub fn ccm_open<C: BlockEncrypt + ?Sized, const M: usize>(
+    cipher: &mut C, nonce: &Nonce, aad: &[u8], data: &mut [u8], tag: &[u8; M]
+) -> bool {
+    let s0 = ccm_ctr(cipher, nonce, data);
+    let mac = ccm_cbc_mac::<C, M>(cipher, nonce, aad, data);
+
+    let mut expected = [0u8; M];
+    for i in 0..M {
+        expected[i] = mac[i] ^ s0[i];
+    }
+    constant_time_eq(&expected, tag)
+}
+
+/// CBC-MAC over the B0 block, the length-prefixed `aad`, and `data`, each zero padded.
+fn ccm_cbc_mac<C: BlockEncrypt + ?Sized, const M: usize>(
+    cipher: &mut C, nonce: &Nonce, aad: &[u8], data: &[u8]
+) -> Block {
+    debug_assert!((4..=16).contains(&M) && M.is_multiple_of(2));
+    debug_assert!(data.len() <= u16::MAX as usize && aad.len() < 0xFF00);
+
+    let mut x: Block = [0; BLOCK_LEN];
+    x[0] = (if aad.is_empty() { 0 } else { 0x40 }) | ((((M - 2) / 2) as u8) << 3) | (CCM_L - 1) as u8;
+    x[1..1 + CCM_NONCE_LEN].copy_from_slice(nonce);
+    x[BLOCK_LEN - CCM_L..].copy_from_slice(&(data.len() as u16).to_be_bytes());
+    cipher.encrypt_block(&mut x);
+
+    if !aad.is_empty() {
+        // The first AAD block starts with its length, so the AAD is offset by 2 bytes
+        let mut block: Block = [0; BLOCK_LEN];
+        block[..2].copy_from_slice(&(aad.len() as u16).to_be_bytes());
+        let (first, rest) = aad.split_at(aad.len().min(BLOCK_LEN - 2));
+        block[2..2 + first.len()].copy_from_slice(first);
+        xor_into(&mut x, &block);
+        cipher.encrypt_block(&mut x);
+
+        for chunk in rest.chunks(BLOCK_LEN) {
+            xor_into(&mut x, chunk);
+            cipher.encrypt_block(&mut x);
+        }
+    }
+
+    for chunk in data.chunks(BLOCK_LEN) {
+        xor_into(&mut x, chunk);
+        cipher.encrypt_block(&mut x);
+    }
+
+    x
+}
+
+/// Applies the CTR keystream (starting from counter 1) to `data` in place, and returns the
+/// keystream block for counter 0, which is used to encrypt the tag.
+fn ccm_ctr<C: BlockEncrypt + ?Sized>(cipher: &mut C, nonce: &Nonce, data: &mut [u8]) -> Block {
+    let mut counter_block = |counter: u16| {
+        let mut a: Block = [0; BLOCK_LEN];
+        a[0] = (CCM_L - 1) as u8;
+        a[1..1 + CCM_NONCE_LEN].copy_from_slice(nonce);
+        a[BLOCK_LEN - CCM_L..].copy_from_slice(&counter.to_be_bytes());
+        cipher.encrypt_block(&mut a);
+        a
+    };
+
+    let s0 = counter_block(0);
+    for (i, chunk) in data.chunks_mut(BLOCK_LEN).enumerate() {
+        let s = counter_block(i as u16 + 1);
+        for (d, k) in chunk.iter_mut().zip(s.iter()) {
+            *d ^= k;
+        }
+    }
+    s0
+}
+
+/// Compares two byte strings in time which depends only on their lengths.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Multiplication by x in GF(2^128), used to derive the CMAC subkeys.
+fn double(block: &Block) -> Block {
+    let mut out: Block = [0; BLOCK_LEN];
+    for i in 0..BLOCK_LEN {
+        let carry = if i + 1 < BLOCK_LEN { block[i + 1] >> 7 } else { 0 };
+        out[i] = (block[i] << 1) | carry;
+    }
+    if block[0] & 0x80 != 0 {
+        out[BLOCK_LEN - 1] ^= 0x87;
+    }
+    out
+}
+
+fn xor_into(target: &mut Block, other: &[u8]) {
+    for (t, o) in target.iter_mut().zip(other) {
+        *t ^= o;
+    }
+}
+
+/// Software AES-128, for hosts without access to the SoftDevice.
+#[cfg(any(feature = "aes", test))]
+pub struct SoftwareAes(aes::Aes128);
+
+#[cfg(any(feature = "aes", test))]
+impl SoftwareAes {
+    pub fn new(key: &Key) -> Self {
+        use aes::cipher::KeyInit;
+        Self(aes::Aes128::new(key.into()))
+    }
+}
+
+#[cfg(any(feature = "aes", test))]
+impl BlockEncrypt for SoftwareAes {
+    fn encrypt_block(&mut self, block: &mut Block) {
+        use aes::cipher::BlockEncrypt as _;
+        self.0.encrypt_block(block.into());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const fn hex<const N: usize>(string: &str) -> [u8; N] {
+        const fn digit(byte: u8) -> u8 {
+            match byte {
+                b'0'..=b'9' => byte - b'0',
+                b'a'..=b'f' => byte - b'a' + 10,
+                _ => panic!("not a hex digit"),
+            }
+        }
+        let bytes = string.as_bytes();
+        assert!(bytes.len() == N * 2);
+        let mut out = [0; N];
+        let mut i = 0;
+        while i < N {
+            out[i] = (digit(bytes[2 * i]) << 4) | digit(bytes[2 * i + 1]);
+            i += 1;
+        }
+        out
+    }
+
+    /// Key and message of the examples in RFC 4493, section 4.
+    const CMAC_KEY: Key = hex("2b7e151628aed2a6abf7158809cf4f3c");
+    const CMAC_MESSAGE: [u8; 64] = hex(
+        "6bc1bee22e409f96e93d7e117393172aae2d8a571e03ac9c9eb76fac45af8e51\
+         30c81c46a35ce411e5fbc1191a0a52eff69f2445df4f9b17ad2b417be66c3710"
+    );
+
+    #[test]
+    fn cmac_subkeys() {
+        let mut l: Block = [0; BLOCK_LEN];
+        SoftwareAes::new(&CMAC_KEY).encrypt_block(&mut l);
+        assert_eq!(l, hex("7df76b0c1ab899b33e42f047b91b546f"));
+        let k1 = double(&l);
+        assert_eq!(k1, hex("fbeed618357133667c85e08f7236a8de"));
+        assert_eq!(double(&k1), hex("f7ddac306ae266ccf90bc11ee46d513b"));
+    }
+
+    #[test]
+    fn cmac_rfc4493_examples() {
+        let mut cipher = SoftwareAes::new(&CMAC_KEY);
+        let examples: [(usize, Block); 4] = [
+            (0, hex("bb1d6929e95937287fa37d129b756746")),
+            (16, hex("070a16b46b4d4144f79bdd9dd04a287c")),
+            (40, hex("dfa66747de9ae63030ca32611497c827")),
+            (64, hex("51f0bebf7e3b9d92fc49741779363cfe")),
+        ];
+        for (len, expected) in examples {
+            assert_eq!(cmac(&mut cipher, &CMAC_MESSAGE[..len]), expected, "Mlen = {}", len);
+        }
+    }
+
+    #[test]
+    fn constant_time_eq_compares_lengths() {
+        assert!(constant_time_eq(b"abcd", b"abcd"));
+        assert!(!constant_time_eq(b"abcd", b"abce"));
+        assert!(!constant_time_eq(b"abcd", b"abc"));
+    }
+
+    /// Key of the packet vectors in RFC 3610, section 8. Those are used instead of the examples in
+    /// SP 800-38C, since only its fourth example uses a 13 byte nonce, and its 64KiB of associated
+    /// data is more than [`ccm_seal`] supports.
+    const CCM_KEY: Key = hex("c0c1c2c3c4c5c6c7c8c9cacbcccdcecf");
+
+    fn counting<const N: usize>(start: u8) -> [u8; N] {
+        core::array::from_fn(|i| start + i as u8)
+    }
+
+    fn ccm_round_trip<const M: usize, const N: usize>(
+        nonce: &Nonce, aad: &[u8], plaintext: [u8; N], ciphertext: [u8; N], tag: [u8; M]
+    ) {
+        let mut cipher = SoftwareAes::new(&CCM_KEY);
+        let mut data = plaintext;
+        assert_eq!(ccm_seal::<_, M>(&mut cipher, nonce, aad, &mut data), tag);
+        assert_eq!(data, ciphertext);
+
+        assert!(ccm_open(&mut cipher, nonce, aad, &mut data, &tag));
+        assert_eq!(data, plaintext);
+    }
+
+    #[test]
+    fn ccm_rfc3610_packet_vector_1() {
+        ccm_round_trip(
+            &hex("00000003020100a0a1a2a3a4a5"),
+            &counting::<8>(0),
+            counting::<23>(8),
+            hex("588c979a61c663d2f066d0c2c0f989806d5f6b61dac384"),
+            hex::<8>("17e8d12cfdf926e0"),
+        );
+    }
+
+    #[test]
+    fn ccm_rfc3610_packet_vector_2() {
+        ccm_round_trip(
+            &hex("00000004030201a0a1a2a3a4a5"),
+            &counting::<8>(0),
+            counting::<24>(8),
+            hex("72c91a36e135f8cf291ca894085c87e3cc15c439c9e43a3b"),
+            hex::<8>("a091d56e10400916"),
+        );
+    }
+
+    #[test]
+    fn ccm_short_tag_without_aad() {
+        ccm_round_trip(
+            &hex("00000004030201a0a1a2a3a4a5"),
+            &[],
+            counting::<24>(8),
+            hex("72c91a36e135f8cf291ca894085c87e3cc15c439c9e43a3b"),
+            hex::<4>("d7d13792"),
+        );
+    }
+
+    #[test]
+    fn ccm_aad_spanning_several_blocks() {
+        ccm_round_trip(
+            &hex("00000003020100a0a1a2a3a4a5"),
+            &counting::<40>(0),
+            counting::<20>(40),
+            hex("78acb7ba41e643f2d046f0e2e0d9a9a04d7f4b41"),
+            hex::<16>("a0457f35278af0c3058ddbafd6f83c78"),
+        );
+    }
+
+    #[test]
+    fn ccm_open_rejects_modifications() {
+        let mut cipher = SoftwareAes::new(&CCM_KEY);
+        let nonce: Nonce = hex("00000003020100a0a1a2a3a4a5");
+        let aad = counting::<8>(0);
+        let mut sealed = counting::<23>(8);
+        let tag = ccm_seal::<_, 4>(&mut cipher, &nonce, &aad, &mut sealed);
+
+        let mut data = sealed;
+        data[0] ^= 1;
+        assert!(!ccm_open(&mut cipher, &nonce, &aad, &mut data, &tag));
+
+        let mut data = sealed;
+        assert!(!ccm_open(&mut cipher, &nonce, &aad[1..], &mut data, &tag));
+
+        let mut data = sealed;
+        let mut other_nonce = nonce;
+        other_nonce[0] ^= 1;
+        assert!(!ccm_open(&mut cipher, &other_nonce, &aad, &mut data, &tag));
+
+        let mut data = sealed;
+        let mut other_tag = tag;
+        other_tag[3] ^= 1;
+        assert!(!ccm_open(&mut cipher, &nonce, &aad, &mut data, &other_tag));
+    }
+}
@@ -0,0 +1,362 @@
+//! Compact records of buffered measurements.
+//!
+//! When a sensor can't send a measurement straight away (the capacitor is too low, or the
+//! previous one is still being advertised), it keeps it in RAM and sends it later, a few at a
+//! time, in a history payload. Each record is squeezed into 7 bytes, so that two of them fit
+//! alongside the authentication tag:
+//!
+//! ```text
+//!  body:   | id (2) | first sequence (2) | age (2) × n | record (5) × n |
+//!  record: | moisture (3) | temperature (1) | capacitor voltage (1) |
+//! ```
+//!
+//! The records have consecutive sequence numbers, starting at `first sequence`. Each age is the
+//! number of minutes between the measurement being taken and the payload being built, so the
+//! receiver can work out when it was taken from when it heard the payload.
+//!
+//! Compared to a [`Measurement`], the moisture count is cut to 24 bits, the temperature to half
+//! degree steps, and the capacitor voltage to the top 8 of the ADC's 14 bits. There is no room for
+//! the status, so only measurements with a valid moisture reading are buffered, and a stale
+//! temperature is sent as unavailable.
+
+use crate::{DecodeError, Measurement, Status};
+use crate::units::{ADC_RESOLUTION_BITS, TEMPERATURE_UNAVAILABLE};
+
+/// Length of the id and first sequence number.
+pub const HISTORY_FIXED_LEN: usize = 4;
+
+pub const HISTORY_AGE_LEN: usize = 2;
+
+pub const HISTORY_VALUES_LEN: usize = 5;
+
+pub const HISTORY_RECORD_LEN: usize = HISTORY_AGE_LEN + HISTORY_VALUES_LEN;
+
+/// The most records that fit in a payload with no trailers. Authenticated payloads fit fewer, see
+/// [`crate::payload::history_capacity`].
+pub const MAX_HISTORY_RECORDS: usize = 3;
+
+pub const MAX_HISTORY_BODY_LEN: usize = HISTORY_FIXED_LEN + HISTORY_RECORD_LEN * MAX_HISTORY_RECORDS;
+
+const EMPTY_RECORD: HistoryRecord =
+    HistoryRecord { age_minutes: 0, moisture_frequency: 0, temperature: 0, capacitor_voltage: 0 };
+
+const MAX_MOISTURE: u32 = (1 << 24) - 1;
+
+/// Each temperature step in a record is this many [`Measurement::temperature`] steps.
+const TEMPERATURE_DIVISOR: i32 = 2;
+
+/// Sent as the temperature if the measurement had none.
+const RECORD_TEMPERATURE_UNAVAILABLE: i8 = i8::MIN;
+
+/// The capacitor voltage is shifted right by this much to fit in a byte.
+const CAPACITOR_SHIFT: u32 = ADC_RESOLUTION_BITS - 8;
+
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct HistoryRecord {
+    /// Minutes between the measurement and the payload being built.
+    pub age_minutes: u16,
+    pub moisture_frequency: u32,
+    /// In units of 0.5 degrees C.
+    pub temperature: i8,
+    /// The top 8 bits of the 14 bit ADC reading.
+    pub capacitor_voltage: u8,
+}
+
+impl HistoryRecord {
+    /// Shrinks a measurement into a record. The ID and sequence number are left out, since the
+    /// [`History`] it goes into carries them. A stale temperature is left out, since the record
+    /// can't say that it is stale.
+    pub fn new(measurement: &Measurement, age_minutes: u16) -> Self {
+        let stale = measurement.status.contains(Status::TEMPERATURE_STALE);
+        let temperature = if measurement.has_temperature() && !stale {
+            (measurement.temperature / TEMPERATURE_DIVISOR).clamp(i8::MIN as i32 + 1, i8::MAX as i32) as i8
+        } else {
+            RECORD_TEMPERATURE_UNAVAILABLE
+        };
+
+        Self {
+            age_minutes,
+            moisture_frequency: measurement.moisture_frequency.min(MAX_MOISTURE),
+            temperature,
+            capacitor_voltage: (measurement.capacitor_voltage >> CAPACITOR_SHIFT).clamp(0, u8::MAX as i16) as u8,
+        }
+    }
+
+    /// Expands the record back into a measurement, at the reduced precision. Records don't carry
+    /// a status, so it is [`Status::BUFFERED`], plus [`Status::TEMPERATURE_STALE`] if there is no
+    /// temperature.
+    pub fn measurement(&self, id: u16, sequence: u16) -> Measurement {
+        let (temperature, status) = if self.temperature == RECORD_TEMPERATURE_UNAVAILABLE {
+            (TEMPERATURE_UNAVAILABLE, Status::BUFFERED | Status::TEMPERATURE_STALE)
+        } else {
+            (self.temperature as i32 * TEMPERATURE_DIVISOR, Status::BUFFERED)
+        };
+
+        Measurement {
+            id,
+            moisture_frequency: self.moisture_frequency,
+            temperature,
+            capacitor_voltage: (self.capacitor_voltage as i16) << CAPACITOR_SHIFT,
+            sequence,
+            status,
+        }
+    }
+
+    fn values_to_bytes(&self) -> [u8; HISTORY_VALUES_LEN] {
+        let moisture = self.moisture_frequency.to_be_bytes();
+        [moisture[1], moisture[2], moisture[3], self.temperature as u8, self.capacitor_voltage]
+    }
+
+    fn set_values(&mut self, bytes: &[u8]) {
+        self.moisture_frequency = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+        self.temperature = bytes[3] as i8;
+        self.capacitor_voltage = bytes[4];
+    }
+}
+
+/// Up to [`MAX_HISTORY_RECORDS`] buffered measurements from one sensor, with consecutive sequence
+/// numbers.
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct History {
+    pub id: u16,
+    pub first_sequence: u16,
+    len: u8,
+    records: [HistoryRecord; MAX_HISTORY_RECORDS],
+}
+
+impl History {
+    pub const fn new(id: u16, first_sequence: u16) -> Self {
+        Self {
+            id,
+            first_sequence,
+            len: 0,
+            records: [EMPTY_RECORD; MAX_HISTORY_RECORDS],
+        }
+    }
+
+    /// Appends a record, with the next sequence number. Returns `false` if there is no room.
+    pub fn push(&mut self, record: HistoryRecord) -> bool {
+        let Some(slot) = self.records.get_mut(self.len as usize) else {
+            return false;
+        };
+        *slot = record;
+        self.len += 1;
+        true
+    }
+
+    pub fn records(&self) -> &[HistoryRecord] {
+        &self.records[..self.len as usize]
+    }
+
+    pub fn len(&self) -> usize {
+        self.len as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Every record, expanded into a measurement, along with its age in minutes.
+    pub fn measurements(&self) -> impl Iterator<Item = (u16, Measurement)> + '_ {
+        self.records().iter().enumerate().map(|(i, record)| {
+            let sequence = self.first_sequence.wrapping_add(i as u16);
+            (record.age_minutes, record.measurement(self.id, sequence))
+        })
+    }
+
+    /// Sequence number of the last record.
+    pub fn last_sequence(&self) -> u16 {
+        self.first_sequence.wrapping_add(self.len.saturating_sub(1) as u16)
+    }
+
+    /// The records with sequence numbers after `sequence`, or `None` if there are none.
+    pub fn after(&self, sequence: u16) -> Option<Self> {
+        // Sequence numbers wrap, so anything up to half the range ahead of the first record is
+        // taken to be at or after it, like `SequenceTracker` does
+        let ahead = sequence.wrapping_sub(self.first_sequence);
+        let skip = if ahead <= u16::MAX / 2 { ahead as usize + 1 } else { 0 };
+        let rest = self.records().get(skip..).filter(|rest| !rest.is_empty())?;
+
+        let mut history = Self::new(self.id, self.first_sequence.wrapping_add(skip as u16));
+        for record in rest {
+            history.push(*record);
+        }
+        Some(history)
+    }
+
+    pub(crate) fn encoded_len(&self) -> usize {
+        HISTORY_FIXED_LEN + HISTORY_RECORD_LEN * self.len()
+    }
+
+    /// Offset of the first record's values in the encoded body. Everything before it is sent in
+    /// the clear, even in encrypted payloads.
+    pub(crate) fn values_offset(&self) -> usize {
+        HISTORY_FIXED_LEN + HISTORY_AGE_LEN * self.len()
+    }
+
+    /// Writes the body into the start of `buf`, and returns the number of bytes written.
+    pub(crate) fn encode(&self, buf: &mut [u8]) -> usize {
+        buf[0..2].copy_from_slice(self.id.to_be_bytes().as_slice());
+        buf[2..4].copy_from_slice(self.first_sequence.to_be_bytes().as_slice());
+        let values_offset = self.values_offset();
+        for (i, record) in self.records().iter().enumerate() {
+            let age = HISTORY_FIXED_LEN + HISTORY_AGE_LEN * i;
+            buf[age..age + HISTORY_AGE_LEN].copy_from_slice(record.age_minutes.to_be_bytes().as_slice());
+            let values = values_offset + HISTORY_VALUES_LEN * i;
+            buf[values..values + HISTORY_VALUES_LEN].copy_from_slice(record.values_to_bytes().as_slice());
+        }
+        self.encoded_len()
+    }
+
+    /// Overwrites the values of every record from their encoded form, e.g. after decryption.
+    pub(crate) fn set_values(&mut self, bytes: &[u8]) {
+        let len = self.len();
+        for (record, values) in self.records[..len].iter_mut().zip(bytes.chunks_exact(HISTORY_VALUES_LEN)) {
+            record.set_values(values);
+        }
+    }
+
+    pub(crate) fn decode(bytes: &[u8]) -> Result<Self, DecodeError> {
+        let records_len = bytes.len().saturating_sub(HISTORY_FIXED_LEN);
+        let count = records_len / HISTORY_RECORD_LEN;
+        if bytes.len() < HISTORY_FIXED_LEN || !records_len.is_multiple_of(HISTORY_RECORD_LEN)
+            || count == 0 || count > MAX_HISTORY_RECORDS {
+            let expected = HISTORY_FIXED_LEN + HISTORY_RECORD_LEN * count.clamp(1, MAX_HISTORY_RECORDS);
+            return Err(DecodeError::Length { expected, actual: bytes.len() });
+        }
+
+        let mut history = Self::new(
+            u16::from_be_bytes([bytes[0], bytes[1]]),
+            u16::from_be_bytes([bytes[2], bytes[3]]));
+        history.len = count as u8;
+
+        let (ages, values) = bytes[HISTORY_FIXED_LEN..].split_at(HISTORY_AGE_LEN * count);
+        for (record, age) in history.records[..count].iter_mut().zip(ages.chunks_exact(HISTORY_AGE_LEN)) {
+            record.age_minutes = u16::from_be_bytes([age[0], age[1]]);
+        }
+        history.set_values(values);
+
+        Ok(history)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn measurement(sequence: u16) -> Measurement {
+        Measurement {
+            id: 0x0123,
+            moisture_frequency: 6543 + sequence as u32,
+            temperature: 88,
+            capacitor_voltage: 9000,
+            sequence,
+            status: Status::empty(),
+        }
+    }
+
+    fn history(first_sequence: u16, len: u16) -> History {
+        let mut history = History::new(0x0123, first_sequence);
+        for i in 0..len {
+            assert!(history.push(HistoryRecord::new(&measurement(first_sequence.wrapping_add(i)), 60 - i)));
+        }
+        history
+    }
+
+    #[test]
+    fn encode_decode_round_trip() {
+        let history = history(40, 3);
+        let mut buf = [0u8; MAX_HISTORY_BODY_LEN];
+        let len = history.encode(&mut buf);
+        assert_eq!(len, MAX_HISTORY_BODY_LEN);
+        // Ages come before the values, so that they can be sent in the clear
+        assert_eq!(&buf[..8], &[0x01, 0x23, 0, 40, 0, 60, 0, 59]);
+        assert_eq!(History::decode(&buf[..len]), Ok(history));
+    }
+
+    #[test]
+    fn decode_lengths() {
+        let buf = [0u8; MAX_HISTORY_BODY_LEN + HISTORY_RECORD_LEN];
+        for len in [0, 3, HISTORY_FIXED_LEN, HISTORY_FIXED_LEN + HISTORY_RECORD_LEN - 1] {
+            assert_eq!(History::decode(&buf[..len]),
+                       Err(DecodeError::Length { expected: HISTORY_FIXED_LEN + HISTORY_RECORD_LEN, actual: len }));
+        }
+        assert_eq!(History::decode(&buf[..HISTORY_FIXED_LEN + HISTORY_RECORD_LEN + 1]),
+                   Err(DecodeError::Length { expected: HISTORY_FIXED_LEN + HISTORY_RECORD_LEN, actual: 12 }));
+        assert_eq!(History::decode(&buf),
+                   Err(DecodeError::Length { expected: MAX_HISTORY_BODY_LEN, actual: buf.len() }));
+        assert_eq!(History::decode(&buf[..HISTORY_FIXED_LEN + HISTORY_RECORD_LEN]).map(|history| history.len()), Ok(1));
+    }
+
+    #[test]
+    fn push_stops_when_full() {
+        let mut history = history(40, MAX_HISTORY_RECORDS as u16);
+        assert!(!history.push(HistoryRecord::new(&measurement(43), 0)));
+        assert_eq!(history.len(), MAX_HISTORY_RECORDS);
+        assert_eq!(history.last_sequence(), 42);
+    }
+
+    #[test]
+    fn records_lose_precision() {
+        let mut original = measurement(40);
+        original.moisture_frequency = 1 << 25;
+        original.temperature = -41;
+        let record = HistoryRecord::new(&original, 5);
+        let expanded = record.measurement(original.id, original.sequence);
+        assert_eq!(expanded.moisture_frequency, MAX_MOISTURE);
+        // Rounded towards zero, to half degrees
+        assert_eq!(expanded.temperature, -40);
+        assert_eq!(expanded.capacitor_voltage, 9000 & !((1 << CAPACITOR_SHIFT) - 1));
+        assert_eq!(expanded.status, Status::BUFFERED);
+    }
+
+    #[test]
+    fn stale_or_missing_temperature_is_unavailable() {
+        let stale = Measurement { status: Status::TEMPERATURE_STALE, ..measurement(40) };
+        let missing = Measurement { temperature: TEMPERATURE_UNAVAILABLE, ..measurement(40) };
+        for original in [stale, missing] {
+            let expanded = HistoryRecord::new(&original, 0).measurement(original.id, original.sequence);
+            assert_eq!(expanded.temperature, TEMPERATURE_UNAVAILABLE);
+            assert_eq!(expanded.status, Status::BUFFERED | Status::TEMPERATURE_STALE);
+        }
+    }
+
+    #[test]
+    fn measurements_have_consecutive_sequences() {
+        let history = history(u16::MAX, 2);
+        let sequences = history.measurements().map(|(age, measurement)| (age, measurement.sequence));
+        assert!(sequences.eq([(60, u16::MAX), (59, 0)]));
+    }
+
+    #[test]
+    fn after() {
+        let history = history(40, 3);
+        assert_eq!(history.after(39), Some(history));
+        assert_eq!(history.after(40), Some({
+            let mut rest = History::new(0x0123, 41);
+            rest.push(history.records()[1]);
+            rest.push(history.records()[2]);
+            rest
+        }));
+        assert_eq!(history.after(41).map(|rest| (rest.first_sequence, rest.len())), Some((42, 1)));
+        assert_eq!(history.after(42), None);
+        assert_eq!(history.after(1000), None);
+    }
+
+    #[test]
+    fn after_wraparound() {
+        let late = history(u16::MAX - 1, 3);
+        assert_eq!(late.after(u16::MAX - 2), Some(late));
+        assert_eq!(late.after(u16::MAX).map(|rest| (rest.first_sequence, rest.len())), Some((0, 1)));
+        assert_eq!(late.after(0), None);
+        assert_eq!(late.after(5), None);
+
+        let early = history(2, 3);
+        assert_eq!(early.after(u16::MAX), Some(early));
+        assert_eq!(early.after(2).map(|rest| (rest.first_sequence, rest.len())), Some((3, 2)));
+    }
+}
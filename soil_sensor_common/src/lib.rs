@@ -2,6 +2,19 @@
 
 #[cfg(feature = "full")]
 pub mod web;
+pub mod payload;
+pub mod crypto;
+pub mod units;
+pub mod calibration;
+pub mod compensation;
+pub mod history;
+pub mod bthome;
+pub mod sequence;
+pub mod status;
+pub mod telemetry;
+
+pub use payload::{Body, DecodeError, Header, Payload};
+pub use status::Status;
 
 #[cfg(feature = "defmt")]
 use defmt::Format;
@@ -15,8 +28,12 @@ pub struct Measurement {
     pub temperature: i32,
     pub capacitor_voltage: i16,
     pub sequence: u16,
+    /// Not part of the [`Serialized`] body. Sent after it, if the payload has
+    /// [`payload::FLAG_STATUS`] set, and empty otherwise.
+    pub status: Status,
 }
 
+/// The measurement body, as sent by legacy (v0) sensors and wrapped by every later format.
 pub type Serialized = [u8; 14];
 
 pub const COMPANY_ID_CODE: [u8; 2] = [0xFF, 0xFF];
@@ -34,14 +51,31 @@ impl Measurement {
     }
 
     pub fn from_bytes(bytes: Serialized) -> Self {
-        let id = u16::from_be_bytes(<[u8; 2]>::try_from(&bytes[0..2]).unwrap());
-        let moisture_frequency = u32::from_be_bytes(<[u8; 4]>::try_from(&bytes[2..6]).unwrap());
-        let temperature = i32::from_be_bytes(<[u8; 4]>::try_from(&bytes[6..10]).unwrap());
-        let capacitor_voltage = i16::from_be_bytes(<[u8; 2]>::try_from(&bytes[10..12]).unwrap());
-        let sequence = u16::from_be_bytes(<[u8; 2]>::try_from(&bytes[12..14]).unwrap());
+        let id = u16::from_be_bytes([bytes[0], bytes[1]]);
+        let moisture_frequency = u32::from_be_bytes([bytes[2], bytes[3], bytes[4], bytes[5]]);
+        let temperature = i32::from_be_bytes([bytes[6], bytes[7], bytes[8], bytes[9]]);
+        let capacitor_voltage = i16::from_be_bytes([bytes[10], bytes[11]]);
+        let sequence = u16::from_be_bytes([bytes[12], bytes[13]]);
 
         Self {
-            id, moisture_frequency, temperature, capacitor_voltage, sequence
+            id, moisture_frequency, temperature, capacitor_voltage, sequence, status: Status::empty()
+        }
+    }
+
+    /// Decodes a complete advertisement payload of any known version, as produced by
+    /// [`Payload::encode`]. Fails if the payload carries a history instead. Telemetry extensions
+    /// are dropped.
+    pub fn decode(bytes: &[u8]) -> Result<Self, DecodeError> {
+        match Payload::decode(bytes)?.body {
+            Body::Measurement(measurement) => Ok(measurement),
+            Body::History(_) => Err(DecodeError::UnexpectedHistory),
+            Body::Telemetry(telemetry) => Ok(telemetry.measurement),
         }
     }
+
+    pub(crate) fn decode_body(bytes: &[u8]) -> Result<Self, DecodeError> {
+        let body = Serialized::try_from(bytes)
+            .map_err(|_| DecodeError::Length { expected: payload::BODY_LEN, actual: bytes.len() })?;
+        Ok(Self::from_bytes(body))
+    }
 }
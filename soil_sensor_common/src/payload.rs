@@ -0,0 +1,792 @@
+//! Self-describing advertisement payload.
+//!
+//! Every payload sent by current firmware starts with a two byte [`Header`] (format version and
+//! flags), followed by a body whose layout depends on the version. Sensors running older firmware
+//! send the bare 14 byte [`Serialized`] body with no header at all; these are decoded as "v0",
+//! and recognized by their length. The only versioned payload of that length is a v1 [`History`]
+//! of one record with a checksum, so a 14 byte payload is decoded as v0 only if it isn't a valid
+//! one of those. A legacy body would have to come from sensor `0109`, and happen to end in the
+//! right checksum, to be mistaken for one.
+//!
+//! ```text
+//!  v0: | body (14) |
+//!  v1: | version (1) | flags (1) | body | [status (1)] | [boot count (2) | tag (4)] | [crc8 (1)] |
+//!  v2: | version (1) | flags (1) | records | [boot count (2) | tag (4)] | [crc8 (1)] |
+//! ```
+//!
+//! The body is a single 14 byte measurement, or a [`History`] of buffered measurements if
+//! [`FLAG_HISTORY`] is set. A single measurement may be followed by its [`Status`] byte.
+//! Optional parts are only present if the corresponding flag is set.
+//!
+//! The v2 body is a sequence of [`telemetry`](crate::telemetry) records: the measurement, then
+//! any extensions. Its flags are the same as v1, except that v2 payloads can't be encrypted, and
+//! can't carry a history.
+//!
+//! The authentication tag is a truncated AES-CMAC over the header, body and boot count, keyed
+//! with the sensor's provisioned key. Together, the boot count and the `sequence` in the body
+//! strictly increase for every new measurement, which lets a receiver reject replayed payloads.
+//! Histories are older than the measurements sent around them, so the receiver keeps a second
+//! counter for them, of the last record it accepted from a history. Buffered measurements don't
+//! survive a reset, so a history from an earlier boot than any payload already accepted is a
+//! replay.
+//!
+//! Encrypted payloads have the same layout, but the measured values in the body (bytes 2..12 of
+//! a single measurement, or the records of a history) are encrypted with AES-CCM, and the tag is
+//! the CCM tag instead. The sensor ID, sequence, status and record ages stay in the clear, so
+//! that the receiver can look up the key and build the nonce:
+//!
+//! ```text
+//!  nonce: | id (2) | boot count (2) | sequence (2) | history (1) | zero (6) |
+//!  history nonce: | id (2) | boot count (2) | first sequence (2) | history (1) |
+//!                 | last sequence (2) | age (2) × n | zero |
+//! ```
+//!
+//! A sensor resends a history if advertising it failed, by which time it may have fewer or more
+//! records, or older ones. Every record's values are fixed by its sequence number, so putting the
+//! sequence numbers and ages in the nonce means a nonce is never reused for a different message.
+//!
+//! The header is passed as associated data, so it is covered by the tag in both modes, along
+//! with the status of a measurement, or the record ages of a history.
+
+use core::fmt;
+use core::ops::Range;
+use crate::{Measurement, Serialized, Status};
+use crate::crypto::{self, BlockEncrypt};
+use crate::telemetry::{RecordError, Telemetry, MEASUREMENT_RECORD_LEN};
+use crate::history::{History, HISTORY_AGE_LEN, HISTORY_FIXED_LEN, HISTORY_RECORD_LEN, MAX_HISTORY_RECORDS};
+
+/// Version of the payload format that this crate encodes.
+pub const FORMAT_VERSION: u8 = 1;
+
+/// Payloads whose body is made of [`telemetry`](crate::telemetry) records.
+pub const VERSION_TELEMETRY: u8 = 2;
+
+/// Legacy sensors send the bare body, with no header.
+pub const VERSION_LEGACY: u8 = 0;
+
+/// A CRC-8 of everything before it is appended to the payload.
+pub const FLAG_CHECKSUM: u8 = 1 << 0;
+
+/// The payload carries a boot count and authentication tag.
+pub const FLAG_AUTHENTICATED: u8 = 1 << 1;
+
+/// Part of the body is encrypted, and the payload carries a boot count and CCM tag. Mutually
+/// exclusive with [`FLAG_AUTHENTICATED`].
+pub const FLAG_ENCRYPTED: u8 = 1 << 2;
+
+/// The body is a [`History`] of buffered measurements, rather than a single one.
+pub const FLAG_HISTORY: u8 = 1 << 3;
+
+/// A [`Status`] byte follows the measurement. Not allowed with [`FLAG_HISTORY`].
+pub const FLAG_STATUS: u8 = 1 << 4;
+
+/// Flags which are defined for v1. All other bits are reserved, and must be zero.
+pub const FLAGS_V1: u8 = FLAG_CHECKSUM | FLAG_AUTHENTICATED | FLAG_ENCRYPTED | FLAG_HISTORY | FLAG_STATUS;
+
+/// Flags which are defined for v2. The status is part of the measurement record instead.
+pub const FLAGS_V2: u8 = FLAG_CHECKSUM | FLAG_AUTHENTICATED;
+
+pub const HEADER_LEN: usize = 2;
+
+pub const BODY_LEN: usize = core::mem::size_of::<Serialized>();
+
+pub const STATUS_LEN: usize = 1;
+
+pub const CHECKSUM_LEN: usize = 1;
+
+/// Length of the truncated CMAC. 32 bits is too short to resist a determined offline attack,
+/// but every guess costs an advertisement and the bridge sees every failed one.
+pub const TAG_LEN: usize = 4;
+
+pub const AUTH_LEN: usize = 2 + TAG_LEN;
+
+pub type Tag = [u8; TAG_LEN];
+
+/// The part of a single measurement body which is encrypted in encrypted payloads.
+pub const ENCRYPTED_RANGE: Range<usize> = 2..12;
+
+/// A scan response is at most 31 bytes. The manufacturer specific data AD structure needs 1 byte
+/// for length, 1 byte for type, and 2 bytes for the company ID, which leaves 27 for the payload.
+pub const MAX_PAYLOAD_LEN: usize = 31 - 4;
+
+pub type PayloadBuffer = [u8; MAX_PAYLOAD_LEN];
+
+/// The header, plus the record ages of the largest history.
+const MAX_ASSOCIATED_DATA_LEN: usize = HEADER_LEN + HISTORY_AGE_LEN * MAX_HISTORY_RECORDS;
+
+// Every record age of an encrypted history has to fit in its nonce
+const _: () = assert!(9 + HISTORY_AGE_LEN * history_capacity(FLAG_ENCRYPTED) <= crypto::CCM_NONCE_LEN);
+
+/// Total length of the trailers that the flags call for.
+pub const fn trailers_len(flags: u8) -> usize {
+    let mut len = 0;
+    if flags & (FLAG_AUTHENTICATED | FLAG_ENCRYPTED) != 0 {
+        len += AUTH_LEN;
+    }
+    if flags & FLAG_CHECKSUM != 0 {
+        len += CHECKSUM_LEN;
+    }
+    len
+}
+
+/// How many history records fit in a payload with the given flags.
+pub const fn history_capacity(flags: u8) -> usize {
+    let fits = (MAX_PAYLOAD_LEN - HEADER_LEN - HISTORY_FIXED_LEN - trailers_len(flags)) / HISTORY_RECORD_LEN;
+    if fits < MAX_HISTORY_RECORDS { fits } else { MAX_HISTORY_RECORDS }
+}
+
+/// How many bytes of extension records fit in a v2 payload with the given flags.
+pub const fn extensions_capacity(flags: u8) -> usize {
+    (MAX_PAYLOAD_LEN - HEADER_LEN - MEASUREMENT_RECORD_LEN).saturating_sub(trailers_len(flags))
+}
+
+/// Reasons that a received payload could not be decoded.
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum DecodeError {
+    /// The payload is not the length that its header says it should be.
+    Length { expected: usize, actual: usize },
+    /// The header names a format version that this crate does not know about.
+    UnknownVersion(u8),
+    /// The checksum in the payload does not match the one computed over the received bytes.
+    BadChecksum { expected: u8, actual: u8 },
+    /// The header sets flag bits which are reserved in its version, or a combination of flags
+    /// which is not allowed.
+    ReservedFlags(u8),
+    /// A single measurement was asked for, but the payload carries a history.
+    UnexpectedHistory,
+    /// The records in a v2 payload are malformed.
+    Record(RecordError),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Length { expected, actual } =>
+                write!(f, "payload is {} bytes long, expected {}", actual, expected),
+            Self::UnknownVersion(version) =>
+                write!(f, "unknown payload format version {}", version),
+            Self::BadChecksum { expected, actual } =>
+                write!(f, "checksum mismatch: computed {:#04x}, received {:#04x}", expected, actual),
+            Self::ReservedFlags(flags) =>
+                write!(f, "reserved flag bits set: {:#010b}", flags),
+            Self::UnexpectedHistory =>
+                write!(f, "payload carries buffered history, not a single measurement"),
+            Self::Record(e) =>
+                write!(f, "bad telemetry: {}", e),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for DecodeError {}
+
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Header {
+    pub version: u8,
+    pub flags: u8,
+}
+
+impl Header {
+    pub const fn current() -> Self {
+        Self { version: FORMAT_VERSION, flags: 0 }
+    }
+
+    pub const fn legacy() -> Self {
+        Self { version: VERSION_LEGACY, flags: 0 }
+    }
+
+    pub const fn has_flag(&self, flag: u8) -> bool {
+        self.flags & flag != 0
+    }
+
+    /// Whether the payload carries a boot count and tag.
+    pub const fn has_auth(&self) -> bool {
+        self.has_flag(FLAG_AUTHENTICATED | FLAG_ENCRYPTED)
+    }
+}
+
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Auth {
+    /// Incremented by the sensor every time it resets, since `sequence` starts over from 0.
+    pub boot_count: u16,
+    pub tag: Tag,
+}
+
+/// What a payload carries.
+///
+/// If the payload is encrypted, only the ID, sequence numbers and ages are meaningful. The other
+/// fields hold ciphertext, and [`Payload::decrypt`] must be used to recover them.
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Body {
+    Measurement(Measurement),
+    History(History),
+    Telemetry(Telemetry),
+}
+
+impl Body {
+    pub fn id(&self) -> u16 {
+        match self {
+            Self::Measurement(measurement) => measurement.id,
+            Self::History(history) => history.id,
+            Self::Telemetry(telemetry) => telemetry.measurement.id,
+        }
+    }
+
+    /// The sequence number which goes into the nonce.
+    pub fn sequence(&self) -> u16 {
+        match self {
+            Self::Measurement(measurement) => measurement.sequence,
+            Self::History(history) => history.first_sequence,
+            Self::Telemetry(telemetry) => telemetry.measurement.sequence,
+        }
+    }
+
+    /// Writes the body into the start of `buf`, and returns the number of bytes written. The
+    /// status of a measurement is written after it if `flags` has [`FLAG_STATUS`] set.
+    fn encode(&self, flags: u8, buf: &mut [u8]) -> usize {
+        match self {
+            Self::Measurement(measurement) => {
+                buf[..BODY_LEN].copy_from_slice(measurement.to_bytes().as_slice());
+                if flags & FLAG_STATUS != 0 {
+                    buf[BODY_LEN] = measurement.status.0;
+                    BODY_LEN + STATUS_LEN
+                } else {
+                    BODY_LEN
+                }
+            },
+            Self::History(history) => history.encode(buf),
+            Self::Telemetry(telemetry) => telemetry.encode(buf),
+        }
+    }
+
+    /// Calls `f` with the encoded body split into the bytes which are only authenticated, and the
+    /// bytes which are encrypted, then takes back whatever `f` left in the encrypted part. The ID
+    /// and sequence numbers are left out of the first part, since they go into the nonce instead.
+    fn with_secret<R>(&mut self, flags: u8, f: impl FnOnce(&[u8], &mut [u8]) -> R) -> R {
+        match self {
+            Self::Measurement(measurement) => {
+                let status = measurement.status;
+                let clear: &[u8] = if flags & FLAG_STATUS != 0 { &[status.0] } else { &[] };
+                let mut bytes = measurement.to_bytes();
+                let result = f(clear, &mut bytes[ENCRYPTED_RANGE]);
+                *measurement = Measurement { status, ..Measurement::from_bytes(bytes) };
+                result
+            },
+            Self::History(history) => {
+                let mut bytes = [0u8; MAX_PAYLOAD_LEN];
+                let len = history.encode(&mut bytes);
+                let (clear, secret) = bytes[..len].split_at_mut(history.values_offset());
+                let result = f(&clear[HISTORY_FIXED_LEN..], secret);
+                history.set_values(secret);
+                result
+            },
+            // Never encrypted, so there is nothing to hand over. Encoding will fail instead.
+            Self::Telemetry(_) => f(&[], &mut []),
+        }
+    }
+}
+
+/// A body, along with the header that it was (or will be) sent with.
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Payload {
+    pub header: Header,
+    pub body: Body,
+    pub auth: Option<Auth>,
+}
+
+impl Payload {
+    /// Sends the measurement's status along with it.
+    pub const fn new(measurement: Measurement) -> Self {
+        Self { header: Header::current(), body: Body::Measurement(measurement), auth: None }
+            .with_flags(FLAG_STATUS)
+    }
+
+    pub const fn history(history: History) -> Self {
+        Self { header: Header::current(), body: Body::History(history), auth: None }
+            .with_flags(FLAG_HISTORY)
+    }
+
+    /// A v2 payload. Only the checksum and authenticated flags may be set.
+    pub const fn telemetry(telemetry: Telemetry) -> Self {
+        let header = Header { version: VERSION_TELEMETRY, flags: 0 };
+        Self { header, body: Body::Telemetry(telemetry), auth: None }
+    }
+
+    /// Sets the authenticated flag, and computes the tag with the sensor's key. Flags must be set
+    /// before calling this, since the header is covered by the tag.
+    pub fn authenticate<C: BlockEncrypt + ?Sized>(mut self, boot_count: u16, cipher: &mut C) -> Self {
+        self.header.flags |= FLAG_AUTHENTICATED;
+        let tag = self.compute_tag(boot_count, cipher);
+        self.auth = Some(Auth { boot_count, tag });
+        self
+    }
+
+    /// Checks the authentication tag against the given key. Unauthenticated payloads never verify.
+    pub fn verify<C: BlockEncrypt + ?Sized>(&self, cipher: &mut C) -> bool {
+        match self.auth {
+            Some(auth) if self.header.has_flag(FLAG_AUTHENTICATED) =>
+                crypto::constant_time_eq(&self.compute_tag(auth.boot_count, cipher), &auth.tag),
+            _ => false
+        }
+    }
+
+    /// Sets the encrypted flag, and encrypts the body with the sensor's key. Flags must be set
+    /// before calling this, since the header is covered by the tag.
+    pub fn encrypt<C: BlockEncrypt + ?Sized>(mut self, boot_count: u16, cipher: &mut C) -> Self {
+        self.header.flags |= FLAG_ENCRYPTED;
+        let nonce = self.nonce(boot_count);
+        let header = self.header;
+        let tag = self.body.with_secret(header.flags, |clear, secret| {
+            let (aad, aad_len) = associated_data(header, clear);
+            crypto::ccm_seal::<C, TAG_LEN>(cipher, &nonce, &aad[..aad_len], secret)
+        });
+
+        self.auth = Some(Auth { boot_count, tag });
+        self
+    }
+
+    /// Decrypts an encrypted payload, returning `None` if it is not encrypted or the tag does
+    /// not match.
+    pub fn decrypt<C: BlockEncrypt + ?Sized>(&self, cipher: &mut C) -> Option<Body> {
+        let auth = self.auth.filter(|_| self.header.has_flag(FLAG_ENCRYPTED))?;
+        let nonce = self.nonce(auth.boot_count);
+        let mut body = self.body;
+        let valid = body.with_secret(self.header.flags, |clear, secret| {
+            let (aad, aad_len) = associated_data(self.header, clear);
+            crypto::ccm_open::<C, TAG_LEN>(cipher, &nonce, &aad[..aad_len], secret, &auth.tag)
+        });
+
+        valid.then_some(body)
+    }
+
+    fn compute_tag<C: BlockEncrypt + ?Sized>(&self, boot_count: u16, cipher: &mut C) -> Tag {
+        // The header and body fit in a payload, but the boot count might not
+        let mut message = [0u8; MAX_PAYLOAD_LEN + 2];
+        message[0] = self.header.version;
+        message[1] = self.header.flags;
+        let mut len = HEADER_LEN;
+        len += self.body.encode(self.header.flags, &mut message[len..]);
+        message[len..len + 2].copy_from_slice(boot_count.to_be_bytes().as_slice());
+        len += 2;
+
+        let mac = crypto::cmac(cipher, &message[..len]);
+        let mut tag: Tag = [0; TAG_LEN];
+        tag.copy_from_slice(&mac[..TAG_LEN]);
+        tag
+    }
+
+    fn nonce(&self, boot_count: u16) -> crypto::Nonce {
+        let mut nonce: crypto::Nonce = [0; crypto::CCM_NONCE_LEN];
+        nonce[0..2].copy_from_slice(self.body.id().to_be_bytes().as_slice());
+        nonce[2..4].copy_from_slice(boot_count.to_be_bytes().as_slice());
+        nonce[4..6].copy_from_slice(self.body.sequence().to_be_bytes().as_slice());
+        // Keeps a history apart from a measurement with the same sequence number
+        nonce[6] = self.header.has_flag(FLAG_HISTORY) as u8;
+        if let Body::History(history) = &self.body {
+            nonce[7..9].copy_from_slice(history.last_sequence().to_be_bytes().as_slice());
+            for (age, record) in nonce[9..].chunks_exact_mut(HISTORY_AGE_LEN).zip(history.records()) {
+                age.copy_from_slice(record.age_minutes.to_be_bytes().as_slice());
+            }
+        }
+        nonce
+    }
+
+    pub const fn with_flags(mut self, flags: u8) -> Self {
+        self.header.flags |= flags;
+        self
+    }
+
+    /// Writes the payload into the start of `buf`, and returns the number of bytes written.
+    ///
+    /// Returns `None` if the header names a version this crate cannot encode or which doesn't
+    /// match the body, sets reserved flags, sets the authenticated or encrypted flag without
+    /// [`Payload::authenticate`] or [`Payload::encrypt`] having been called, or disagrees with
+    /// the body about being a history. A status can only be sent with a single measurement.
+    /// Also returns `None` for a history which is empty, or has more records than
+    /// [`history_capacity`] allows, and for telemetry with more extensions than
+    /// [`extensions_capacity`] allows.
+    pub fn encode(&self, buf: &mut PayloadBuffer) -> Option<usize> {
+        let flags = self.header.flags;
+        let valid = match (self.header.version, &self.body) {
+            (VERSION_LEGACY, Body::Measurement(measurement)) => {
+                buf[0..BODY_LEN].copy_from_slice(measurement.to_bytes().as_slice());
+                return Some(BODY_LEN);
+            },
+            (1, Body::Telemetry(_)) => false,
+            (1, body) => {
+                let invalid = flags & !FLAGS_V1 != 0
+                    || (self.header.has_flag(FLAG_AUTHENTICATED) && self.header.has_flag(FLAG_ENCRYPTED))
+                    || (self.header.has_flag(FLAG_HISTORY) && self.header.has_flag(FLAG_STATUS))
+                    || self.header.has_flag(FLAG_HISTORY) != matches!(body, Body::History(_));
+                match body {
+                    Body::History(history) =>
+                        !invalid && !history.is_empty() && history.len() <= history_capacity(flags),
+                    _ => !invalid,
+                }
+            },
+            (VERSION_TELEMETRY, Body::Telemetry(telemetry)) => flags & !FLAGS_V2 == 0
+                && telemetry.encoded_len() - MEASUREMENT_RECORD_LEN <= extensions_capacity(flags),
+            _ => false
+        };
+        if !valid {
+            return None;
+        }
+
+        buf[0] = self.header.version;
+        buf[1] = flags;
+        let mut len = HEADER_LEN;
+        len += self.body.encode(flags, &mut buf[len..]);
+        if self.header.has_auth() {
+            let auth = self.auth?;
+            buf[len..len + 2].copy_from_slice(auth.boot_count.to_be_bytes().as_slice());
+            buf[len + 2..len + AUTH_LEN].copy_from_slice(auth.tag.as_slice());
+            len += AUTH_LEN;
+        }
+        if self.header.has_flag(FLAG_CHECKSUM) {
+            buf[len] = crc8(&buf[..len]);
+            len += CHECKSUM_LEN;
+        }
+        Some(len)
+    }
+
+    /// Decodes a payload of any known version.
+    pub fn decode(bytes: &[u8]) -> Result<Self, DecodeError> {
+        if bytes.len() == BODY_LEN {
+            if let Ok(payload) = Self::decode_versioned(bytes) {
+                return Ok(payload);
+            }
+            let measurement = Measurement::decode_body(bytes)?;
+            return Ok(Self { header: Header::legacy(), body: Body::Measurement(measurement), auth: None });
+        }
+        Self::decode_versioned(bytes)
+    }
+
+    /// Decodes a payload which starts with a [`Header`].
+    fn decode_versioned(bytes: &[u8]) -> Result<Self, DecodeError> {
+        let header = match bytes {
+            [version, flags, ..] => Header { version: *version, flags: *flags },
+            _ => return Err(DecodeError::Length { expected: HEADER_LEN + BODY_LEN, actual: bytes.len() })
+        };
+
+        match header.version {
+            1 => Self::decode_v1(header, bytes),
+            VERSION_TELEMETRY => Self::decode_v2(header, bytes),
+            version => Err(DecodeError::UnknownVersion(version))
+        }
+    }
+
+    fn decode_v1(header: Header, bytes: &[u8]) -> Result<Self, DecodeError> {
+        let reserved = header.flags & !FLAGS_V1;
+        if reserved != 0 {
+            return Err(DecodeError::ReservedFlags(reserved));
+        }
+        if header.has_flag(FLAG_AUTHENTICATED) && header.has_flag(FLAG_ENCRYPTED) {
+            return Err(DecodeError::ReservedFlags(FLAG_AUTHENTICATED | FLAG_ENCRYPTED));
+        }
+        if header.has_flag(FLAG_HISTORY) && header.has_flag(FLAG_STATUS) {
+            return Err(DecodeError::ReservedFlags(FLAG_HISTORY | FLAG_STATUS));
+        }
+
+        let status_len = if header.has_flag(FLAG_STATUS) { STATUS_LEN } else { 0 };
+        let trailers = status_len + trailers_len(header.flags);
+        let body_len = bytes.len().saturating_sub(HEADER_LEN + trailers);
+        let body_bytes = &bytes[HEADER_LEN..HEADER_LEN + body_len];
+        let body = if header.has_flag(FLAG_HISTORY) {
+            History::decode(body_bytes).map(Body::History)
+        } else {
+            let status = bytes.get(HEADER_LEN + body_len).filter(|_| status_len != 0);
+            Measurement::decode_body(body_bytes).map(|measurement| Body::Measurement(Measurement {
+                status: status.map_or(Status::empty(), |status| Status(*status)),
+                ..measurement
+            }))
+        };
+        // The body only knows its own length, but the whole payload is the wrong length
+        let body = body.map_err(|e| match e {
+            DecodeError::Length { expected, .. } =>
+                DecodeError::Length { expected: HEADER_LEN + expected + trailers, actual: bytes.len() },
+            e => e,
+        })?;
+
+        let auth = decode_trailers(header, bytes, HEADER_LEN + body_len + status_len)?;
+        Ok(Self { header, body, auth })
+    }
+
+    fn decode_v2(header: Header, bytes: &[u8]) -> Result<Self, DecodeError> {
+        let reserved = header.flags & !FLAGS_V2;
+        if reserved != 0 {
+            return Err(DecodeError::ReservedFlags(reserved));
+        }
+
+        let trailers = trailers_len(header.flags);
+        let shortest = HEADER_LEN + MEASUREMENT_RECORD_LEN + trailers;
+        if bytes.len() < shortest {
+            return Err(DecodeError::Length { expected: shortest, actual: bytes.len() });
+        }
+        if bytes.len() > MAX_PAYLOAD_LEN {
+            return Err(DecodeError::Length { expected: MAX_PAYLOAD_LEN, actual: bytes.len() });
+        }
+
+        // Checked first, so that corruption isn't reported as malformed records
+        let body_end = bytes.len() - trailers;
+        let auth = decode_trailers(header, bytes, body_end)?;
+        let telemetry = Telemetry::decode(&bytes[HEADER_LEN..body_end]).map_err(DecodeError::Record)?;
+        Ok(Self { header, body: Body::Telemetry(telemetry), auth })
+    }
+}
+
+/// Checks the checksum, if there is one, and reads the boot count and tag which start at
+/// `start`, if there are any.
+fn decode_trailers(header: Header, bytes: &[u8], start: usize) -> Result<Option<Auth>, DecodeError> {
+    if header.has_flag(FLAG_CHECKSUM) {
+        let (covered, checksum) = bytes.split_at(bytes.len() - CHECKSUM_LEN);
+        let (expected, actual) = (crc8(covered), checksum[0]);
+        if actual != expected {
+            return Err(DecodeError::BadChecksum { expected, actual });
+        }
+    }
+
+    if !header.has_auth() {
+        return Ok(None);
+    }
+    let trailer = &bytes[start..start + AUTH_LEN];
+    let mut tag: Tag = [0; TAG_LEN];
+    tag.copy_from_slice(&trailer[2..]);
+    Ok(Some(Auth { boot_count: u16::from_be_bytes([trailer[0], trailer[1]]), tag }))
+}
+
+/// The header, followed by whatever else is authenticated but not encrypted.
+fn associated_data(header: Header, clear: &[u8]) -> ([u8; MAX_ASSOCIATED_DATA_LEN], usize) {
+    let mut aad = [0u8; MAX_ASSOCIATED_DATA_LEN];
+    aad[0] = header.version;
+    aad[1] = header.flags;
+    aad[HEADER_LEN..HEADER_LEN + clear.len()].copy_from_slice(clear);
+    (aad, HEADER_LEN + clear.len())
+}
+
+/// CRC-8 with polynomial 0x07 and no reflection or final XOR (CRC-8/SMBUS).
+pub fn crc8(bytes: &[u8]) -> u8 {
+    let mut crc: u8 = 0;
+    for byte in bytes {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 { (crc << 1) ^ 0x07 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::history::HistoryRecord;
+
+    fn measurement() -> Measurement {
+        Measurement {
+            id: 0x0123,
+            moisture_frequency: 6543,
+            temperature: 88,
+            capacitor_voltage: 9000,
+            sequence: 42,
+            status: Status::TEMPERATURE_STALE,
+        }
+    }
+
+    fn encode(payload: &Payload) -> ([u8; MAX_PAYLOAD_LEN], usize) {
+        let mut buf: PayloadBuffer = [0; MAX_PAYLOAD_LEN];
+        let len = payload.encode(&mut buf).expect("payload should encode");
+        (buf, len)
+    }
+
+    #[test]
+    fn legacy_body_decodes_as_v0() {
+        let bytes = measurement().to_bytes();
+        let payload = Payload::decode(&bytes).unwrap();
+        assert_eq!(payload.header, Header::legacy());
+        assert_eq!(payload.auth, None);
+        // v0 has no status byte
+        assert_eq!(payload.body, Body::Measurement(Measurement { status: Status::empty(), ..measurement() }));
+        assert_eq!(Measurement::decode(&bytes), Ok(Measurement { status: Status::empty(), ..measurement() }));
+    }
+
+    #[test]
+    fn v1_round_trip() {
+        let payload = Payload::new(measurement()).with_flags(FLAG_CHECKSUM);
+        let (buf, len) = encode(&payload);
+        assert_eq!(len, HEADER_LEN + BODY_LEN + STATUS_LEN + CHECKSUM_LEN);
+        assert_eq!(&buf[..2], &[FORMAT_VERSION, FLAG_STATUS | FLAG_CHECKSUM]);
+        assert_eq!(Payload::decode(&buf[..len]), Ok(payload));
+        assert_eq!(Measurement::decode(&buf[..len]), Ok(measurement()));
+    }
+
+    #[test]
+    fn v1_without_status_or_checksum() {
+        let payload = Payload { header: Header::current(), body: Body::Measurement(measurement()), auth: None };
+        let (buf, len) = encode(&payload);
+        assert_eq!(len, HEADER_LEN + BODY_LEN);
+        assert_eq!(Measurement::decode(&buf[..len]), Ok(Measurement { status: Status::empty(), ..measurement() }));
+    }
+
+    #[test]
+    fn history_is_not_a_measurement() {
+        let mut history = History::new(0x0123, 40);
+        history.push(HistoryRecord::new(&measurement(), 60));
+        let (buf, len) = encode(&Payload::history(history));
+        assert_eq!(Payload::decode(&buf[..len]).map(|payload| payload.body), Ok(Body::History(history)));
+        assert_eq!(Measurement::decode(&buf[..len]), Err(DecodeError::UnexpectedHistory));
+    }
+
+    #[test]
+    fn history_the_length_of_a_legacy_body() {
+        let mut history = History::new(0x0123, 40);
+        history.push(HistoryRecord::new(&measurement(), 60));
+        let payload = Payload::history(history).with_flags(FLAG_CHECKSUM);
+        let (buf, len) = encode(&payload);
+        assert_eq!(len, BODY_LEN);
+        assert_eq!(Payload::decode(&buf[..len]), Ok(payload));
+
+        // Anything else that long is still a legacy body, even with a header-like ID
+        let mut corrupted = buf;
+        corrupted[len - 1] ^= 1;
+        assert_eq!(Payload::decode(&corrupted[..len]).map(|payload| payload.header), Ok(Header::legacy()));
+    }
+
+    #[test]
+    fn wrong_lengths() {
+        assert_eq!(Payload::decode(&[]), Err(DecodeError::Length { expected: HEADER_LEN + BODY_LEN, actual: 0 }));
+        assert_eq!(Payload::decode(&[1]), Err(DecodeError::Length { expected: HEADER_LEN + BODY_LEN, actual: 1 }));
+
+        let (buf, len) = encode(&Payload::new(measurement()).with_flags(FLAG_CHECKSUM));
+        let expected = HEADER_LEN + BODY_LEN + STATUS_LEN + CHECKSUM_LEN;
+        let mut truncated = buf[..len - 2].to_vec();
+        // Keep the checksum valid, so that only the length is wrong
+        truncated.push(crc8(&truncated));
+        assert_eq!(Payload::decode(&truncated), Err(DecodeError::Length { expected, actual: len - 1 }));
+    }
+
+    #[test]
+    fn unknown_version() {
+        let mut bytes = [0u8; HEADER_LEN + BODY_LEN];
+        bytes[0] = 7;
+        assert_eq!(Payload::decode(&bytes), Err(DecodeError::UnknownVersion(7)));
+    }
+
+    #[test]
+    fn bad_checksum() {
+        let (mut buf, len) = encode(&Payload::new(measurement()).with_flags(FLAG_CHECKSUM));
+        let expected = buf[len - 1];
+        buf[5] ^= 0x01;
+        match Payload::decode(&buf[..len]) {
+            Err(DecodeError::BadChecksum { expected: computed, actual }) => {
+                assert_eq!(actual, expected);
+                assert_ne!(computed, actual);
+            },
+            other => panic!("expected a checksum error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn reserved_flags() {
+        let mut bytes = [0u8; HEADER_LEN + BODY_LEN];
+        bytes[0] = FORMAT_VERSION;
+        bytes[1] = 1 << 7;
+        assert_eq!(Payload::decode(&bytes), Err(DecodeError::ReservedFlags(1 << 7)));
+
+        bytes[1] = FLAG_AUTHENTICATED | FLAG_ENCRYPTED;
+        assert_eq!(Payload::decode(&bytes), Err(DecodeError::ReservedFlags(FLAG_AUTHENTICATED | FLAG_ENCRYPTED)));
+
+        bytes[1] = FLAG_HISTORY | FLAG_STATUS;
+        assert_eq!(Payload::decode(&bytes), Err(DecodeError::ReservedFlags(FLAG_HISTORY | FLAG_STATUS)));
+
+        bytes[0] = VERSION_TELEMETRY;
+        bytes[1] = FLAG_ENCRYPTED;
+        assert_eq!(Payload::decode(&bytes), Err(DecodeError::ReservedFlags(FLAG_ENCRYPTED)));
+    }
+
+    #[test]
+    fn invalid_payloads_do_not_encode() {
+        let mut buf: PayloadBuffer = [0; MAX_PAYLOAD_LEN];
+        // Authenticated, but never given a tag
+        let payload = Payload::new(measurement()).with_flags(FLAG_AUTHENTICATED);
+        assert_eq!(payload.encode(&mut buf), None);
+        // A status can't be sent with a history
+        let mut history = History::new(0x0123, 40);
+        history.push(HistoryRecord::new(&measurement(), 60));
+        assert_eq!(Payload::history(history).with_flags(FLAG_STATUS).encode(&mut buf), None);
+        // An empty history
+        assert_eq!(Payload::history(History::new(0x0123, 40)).encode(&mut buf), None);
+    }
+
+    #[test]
+    fn authenticated_round_trip() {
+        let mut cipher = crypto::SoftwareAes::new(&[0x42; crypto::KEY_LEN]);
+        let payload = Payload::new(measurement()).with_flags(FLAG_CHECKSUM).authenticate(7, &mut cipher);
+        let (mut buf, len) = encode(&payload);
+        assert_eq!(len, HEADER_LEN + BODY_LEN + STATUS_LEN + AUTH_LEN + CHECKSUM_LEN);
+        let decoded = Payload::decode(&buf[..len]).unwrap();
+        assert_eq!(decoded, payload);
+        assert!(decoded.verify(&mut cipher));
+        assert!(!decoded.verify(&mut crypto::SoftwareAes::new(&[0x43; crypto::KEY_LEN])));
+
+        // Tampering with the body, and fixing up the checksum
+        buf[HEADER_LEN + 4] ^= 0x01;
+        buf[len - 1] = crc8(&buf[..len - 1]);
+        assert!(!Payload::decode(&buf[..len]).unwrap().verify(&mut cipher));
+    }
+
+    #[test]
+    fn encrypted_round_trip() {
+        let mut cipher = crypto::SoftwareAes::new(&[0x42; crypto::KEY_LEN]);
+        let payload = Payload::new(measurement()).encrypt(7, &mut cipher);
+        let (buf, len) = encode(&payload);
+        let decoded = Payload::decode(&buf[..len]).unwrap();
+        assert_ne!(decoded.body, Body::Measurement(measurement()));
+        // Unencrypted payloads can't be verified, and encrypted ones can't be verified as if they
+        // were only authenticated
+        assert!(!decoded.verify(&mut cipher));
+        assert_eq!(decoded.decrypt(&mut cipher), Some(Body::Measurement(measurement())));
+        assert_eq!(decoded.decrypt(&mut crypto::SoftwareAes::new(&[0x43; crypto::KEY_LEN])), None);
+        assert_eq!(Payload::new(measurement()).decrypt(&mut cipher), None);
+    }
+
+    #[test]
+    fn encrypted_history_round_trip() {
+        let mut cipher = crypto::SoftwareAes::new(&[0x42; crypto::KEY_LEN]);
+        let mut history = History::new(0x0123, 40);
+        history.push(HistoryRecord::new(&measurement(), 60));
+        history.push(HistoryRecord::new(&measurement(), 30));
+        let payload = Payload::history(history).encrypt(7, &mut cipher);
+        let (buf, len) = encode(&payload);
+        let decoded = Payload::decode(&buf[..len]).unwrap();
+        assert_eq!(decoded.decrypt(&mut cipher), Some(Body::History(history)));
+    }
+
+    #[test]
+    fn history_nonce_covers_every_record() {
+        let mut one = History::new(0x0123, 40);
+        one.push(HistoryRecord::new(&measurement(), 60));
+        let mut two = one;
+        two.push(HistoryRecord::new(&measurement(), 30));
+        let mut older = History::new(0x0123, 40);
+        older.push(HistoryRecord::new(&measurement(), 120));
+
+        let nonces = [one, two, older].map(|history| Payload::history(history).nonce(7));
+        assert_ne!(nonces[0], nonces[1]);
+        assert_ne!(nonces[0], nonces[2]);
+        assert_ne!(nonces[0], Payload::new(measurement()).nonce(7));
+    }
+
+    #[test]
+    fn crc8_check_value() {
+        // The standard check value of CRC-8/SMBUS
+        assert_eq!(crc8(b"123456789"), 0xF4);
+    }
+}
@@ -0,0 +1,209 @@
+//! Classification of sequence numbers, for detecting lost, repeated and reordered packets.
+//!
+//! Sensors number their measurements from 0 after every reset, wrapping around after 65535. A
+//! receiver keeps one [`SequenceTracker`] per sensor, and feeds it every packet it hears. Like
+//! an anti-replay window, the tracker remembers which of the last [`WINDOW`] sequence numbers it
+//! has seen, so it can tell a late packet from a repeated one.
+//!
+//! Reboots are only detected reliably from sensors which send a boot count. Without one, a
+//! sequence number which jumps back by more than the window is taken to be a reboot, and a
+//! receiver which knows better (e.g. from [`Status::FIRST_AFTER_RESET`](crate::Status::FIRST_AFTER_RESET)) can
+//! [`reset`](SequenceTracker::reset) the tracker.
+
+/// How far back from the newest sequence number the tracker remembers what it has seen.
+pub const WINDOW: u16 = 64;
+
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum SequenceEvent {
+    /// The first packet from this sensor.
+    First,
+    /// The packet after the newest one so far.
+    New,
+    /// A packet which has already been seen.
+    Duplicate,
+    /// An older packet which hasn't been seen before, and fills in a gap.
+    OutOfOrder,
+    /// A packet which is too old to tell whether it has been seen before, or which was sent
+    /// before the sensor's last reboot.
+    Stale,
+    /// A newer packet, after some which were never seen.
+    Gap { missed: u16 },
+    /// A newer packet, after the sequence number wrapped around from 65535 to 0.
+    Wraparound { missed: u16 },
+    /// The first packet seen since the sensor reset. Since it counts from 0 again, `missed` is
+    /// the number of packets since the reset which were never seen.
+    Reboot { missed: u16 },
+}
+
+impl SequenceEvent {
+    /// Number of packets which were skipped over, and may never arrive.
+    pub fn missed(&self) -> u16 {
+        match self {
+            Self::Gap { missed } | Self::Wraparound { missed } | Self::Reboot { missed } => *missed,
+            _ => 0,
+        }
+    }
+
+    /// Whether the packet carries a measurement which hasn't been seen before.
+    pub fn is_new(&self) -> bool {
+        !matches!(self, Self::Duplicate | Self::Stale)
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+struct Newest {
+    boot_count: Option<u16>,
+    sequence: u16,
+    /// Bit `i` is set if `sequence - i` has been seen.
+    seen: u64,
+}
+
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct SequenceTracker {
+    newest: Option<Newest>,
+}
+
+impl SequenceTracker {
+    pub const fn new() -> Self {
+        Self { newest: None }
+    }
+
+    /// Classifies a packet, and remembers it. `boot_count` should be given whenever the packet
+    /// carries one.
+    pub fn update(&mut self, boot_count: Option<u16>, sequence: u16) -> SequenceEvent {
+        let Some(newest) = &mut self.newest else {
+            self.newest = Some(Newest { boot_count, sequence, seen: 1 });
+            return SequenceEvent::First;
+        };
+
+        if let (Some(boot_count), Some(newest_boot_count)) = (boot_count, newest.boot_count) {
+            if boot_count > newest_boot_count {
+                *newest = Newest { boot_count: Some(boot_count), sequence, seen: 1 };
+                return SequenceEvent::Reboot { missed: sequence };
+            }
+            if boot_count < newest_boot_count {
+                return SequenceEvent::Stale;
+            }
+        }
+
+        let ahead = sequence.wrapping_sub(newest.sequence);
+        let behind = newest.sequence.wrapping_sub(sequence);
+        if ahead == 0 {
+            SequenceEvent::Duplicate
+        } else if ahead <= u16::MAX / 2 {
+            let wrapped = sequence < newest.sequence;
+            newest.seen = newest.seen.checked_shl(ahead as u32).unwrap_or(0) | 1;
+            newest.sequence = sequence;
+            newest.boot_count = boot_count.or(newest.boot_count);
+            match (ahead - 1, wrapped) {
+                (missed, true) => SequenceEvent::Wraparound { missed },
+                (0, false) => SequenceEvent::New,
+                (missed, false) => SequenceEvent::Gap { missed },
+            }
+        } else if behind < WINDOW {
+            let bit = 1 << behind;
+            if newest.seen & bit != 0 {
+                SequenceEvent::Duplicate
+            } else {
+                newest.seen |= bit;
+                SequenceEvent::OutOfOrder
+            }
+        } else if boot_count.is_some() && newest.boot_count.is_some() {
+            SequenceEvent::Stale
+        } else {
+            *newest = Newest { boot_count, sequence, seen: 1 };
+            SequenceEvent::Reboot { missed: sequence }
+        }
+    }
+
+    /// Starts over from a packet which is known to have been sent since the sensor's last reset,
+    /// forgetting everything seen before it.
+    pub fn reset(&mut self, boot_count: Option<u16>, sequence: u16) -> SequenceEvent {
+        let first = self.newest.is_none();
+        self.newest = Some(Newest { boot_count, sequence, seen: 1 });
+        if first {
+            SequenceEvent::First
+        } else {
+            SequenceEvent::Reboot { missed: sequence }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use SequenceEvent::*;
+
+    fn tracker(boot_count: Option<u16>, sequence: u16) -> SequenceTracker {
+        let mut tracker = SequenceTracker::new();
+        assert_eq!(tracker.update(boot_count, sequence), First);
+        tracker
+    }
+
+    #[test]
+    fn new_gap_and_wraparound() {
+        let mut tracker = tracker(None, 10);
+        assert_eq!(tracker.update(None, 11), New);
+        assert_eq!(tracker.update(None, 14), Gap { missed: 2 });
+        assert_eq!(tracker.update(None, 15), New);
+
+        let mut tracker = self::tracker(None, u16::MAX - 1);
+        assert_eq!(tracker.update(None, u16::MAX), New);
+        assert_eq!(tracker.update(None, 0), Wraparound { missed: 0 });
+        let mut tracker = self::tracker(None, u16::MAX - 1);
+        assert_eq!(tracker.update(None, 2), Wraparound { missed: 3 });
+    }
+
+    #[test]
+    fn duplicates_and_out_of_order() {
+        let mut tracker = tracker(None, 10);
+        assert_eq!(tracker.update(None, 10), Duplicate);
+        assert_eq!(tracker.update(None, 13), Gap { missed: 2 });
+        assert_eq!(tracker.update(None, 12), OutOfOrder);
+        assert_eq!(tracker.update(None, 12), Duplicate);
+        assert_eq!(tracker.update(None, 11), OutOfOrder);
+        assert_eq!(tracker.update(None, 13), Duplicate);
+        assert_eq!(tracker.update(None, 14), New);
+    }
+
+    #[test]
+    fn window_edge() {
+        let mut tracker = tracker(None, 1000);
+        assert_eq!(tracker.update(None, 1000 + WINDOW), Gap { missed: WINDOW - 1 });
+        assert_eq!(tracker.update(None, 1001), OutOfOrder);
+        // Too far back to remember, so without a boot count it must be a reboot
+        assert_eq!(tracker.update(None, 1000), Reboot { missed: 1000 });
+    }
+
+    #[test]
+    fn boot_counts() {
+        let mut tracker = tracker(Some(3), 500);
+        assert_eq!(tracker.update(Some(4), 2), Reboot { missed: 2 });
+        assert_eq!(tracker.update(Some(3), 501), Stale);
+        assert_eq!(tracker.update(Some(4), 3), New);
+        // Far behind, but the boot count says it's not a reboot
+        assert_eq!(tracker.update(Some(4), 3 + WINDOW), Gap { missed: WINDOW - 1 });
+        assert_eq!(tracker.update(Some(4), 3), Stale);
+    }
+
+    #[test]
+    fn reset() {
+        let mut tracker = SequenceTracker::new();
+        assert_eq!(tracker.reset(None, 0), First);
+        assert_eq!(tracker.update(None, 1), New);
+        // Within the window, so update would call it a duplicate
+        assert_eq!(tracker.reset(None, 0), Reboot { missed: 0 });
+        assert_eq!(tracker.update(None, 1), New);
+        assert_eq!(tracker.update(None, 0), Duplicate);
+    }
+
+    #[test]
+    fn events() {
+        assert_eq!(Gap { missed: 3 }.missed(), 3);
+        assert_eq!(OutOfOrder.missed(), 0);
+        assert!(New.is_new() && OutOfOrder.is_new() && Reboot { missed: 0 }.is_new());
+        assert!(!Duplicate.is_new() && !Stale.is_new());
+    }
+}
@@ -0,0 +1,76 @@
+//! Diagnostic bits sent along with a measurement.
+//!
+//! Without these, a disconnected probe looks the same as very dry soil, and a temperature which
+//! failed to read looks the same as the one before it.
+
+use core::ops::{BitOr, BitOrAssign};
+
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct Status(pub u8);
+
+impl Status {
+    /// The probe was powered, but no pulses were counted, so it is probably disconnected.
+    pub const PROBE_FAULT: Self = Self(1 << 0);
+    /// The temperature sensor had no new reading, so the temperature is from an earlier
+    /// measurement (or unavailable, if there has never been one).
+    pub const TEMPERATURE_STALE: Self = Self(1 << 1);
+    /// The capacitor was too low to power the probe, so the moisture reading is meaningless.
+    pub const LOW_ENERGY: Self = Self(1 << 2);
+    /// The first measurement since the sensor reset, e.g. after a brown-out.
+    pub const FIRST_AFTER_RESET: Self = Self(1 << 3);
+    /// The measurement was buffered by the sensor, and sent later in a history. Never sent by
+    /// the sensor itself, but set on measurements expanded from a history.
+    pub const BUFFERED: Self = Self(1 << 4);
+
+    /// Every flag, with a name for logs and sinks.
+    pub const NAMES: [(Self, &'static str); 5] = [
+        (Self::PROBE_FAULT, "probe_fault"),
+        (Self::TEMPERATURE_STALE, "temperature_stale"),
+        (Self::LOW_ENERGY, "low_energy"),
+        (Self::FIRST_AFTER_RESET, "first_after_reset"),
+        (Self::BUFFERED, "buffered"),
+    ];
+
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    pub const fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    /// Whether every flag in `other` is set.
+    pub const fn contains(&self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub const fn intersects(&self, other: Self) -> bool {
+        self.0 & other.0 != 0
+    }
+
+    /// Whether the moisture reading can be trusted.
+    pub const fn moisture_valid(&self) -> bool {
+        !self.intersects(Self(Self::PROBE_FAULT.0 | Self::LOW_ENERGY.0))
+    }
+
+    /// Names of the flags which are set.
+    pub fn names(&self) -> impl Iterator<Item = &'static str> + '_ {
+        Self::NAMES.iter().filter(|(flag, _)| self.contains(*flag)).map(|(_, name)| *name)
+    }
+}
+
+impl BitOr for Status {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+impl BitOrAssign for Status {
+    fn bitor_assign(&mut self, rhs: Self) {
+        self.0 |= rhs.0;
+    }
+}
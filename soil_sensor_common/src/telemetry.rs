@@ -0,0 +1,558 @@
+//! Type-length-value records, so that new kinds of data can be sent without changing the layout
+//! of the payload again.
+//!
+//! A v2 payload body is a sequence of records. Each one is a type byte, a length byte, and that
+//! many bytes of value:
+//!
+//! ```text
+//!  body:   | record | record | ... |
+//!  record: | type (1) | length (1) | value (length) |
+//! ```
+//!
+//! The first record is always the measurement, and any others are optional extensions. Since
+//! every record says how long it is, a receiver can skip types that it doesn't know, and a known
+//! type may grow new fields at the end of its value without breaking older receivers.
+//!
+//! The measurement is packed tighter than the v1 body, into 11 bytes instead of 15:
+//!
+//! ```text
+//!  measurement: | id (2) | sequence (2) | moisture (3) | temperature (10 bits) | capacitor voltage (14 bits) | status (1) |
+//! ```
+//!
+//! The moisture count saturates at 24 bits, and the temperature is in the same 0.25 degree steps
+//! as [`Measurement::temperature`], from -127.75 to 127.75 degrees C, or -512 if unavailable.
+//! The capacitor voltage is the whole 14 bit ADC reading.
+//!
+//! A payload is still at most 27 bytes, so there is only room for one or two small extensions
+//! alongside the measurement, and just one in an authenticated payload. Senders should spread
+//! extensions out over several advertisements.
+
+use core::fmt;
+use crate::{Measurement, Status};
+use crate::payload::{extensions_capacity, FLAG_AUTHENTICATED, FLAG_CHECKSUM, HEADER_LEN, MAX_PAYLOAD_LEN};
+use crate::units::{ADC_RESOLUTION_BITS, TEMPERATURE_UNAVAILABLE};
+
+/// Length of the type and length bytes before every value.
+pub const RECORD_HEADER_LEN: usize = 2;
+
+/// The packed measurement, see the module documentation.
+pub const RECORD_MEASUREMENT: u8 = 0x01;
+/// Major, minor and patch version of the sensor's firmware, one byte each.
+pub const RECORD_FIRMWARE_VERSION: u8 = 0x02;
+/// u24, minutes since the sensor reset, saturating.
+pub const RECORD_UPTIME: u8 = 0x03;
+/// u8, a [`ResetReason`].
+pub const RECORD_RESET_REASON: u8 = 0x04;
+/// i8, dBm. Signal strength of the bridge, as heard by the sensor.
+pub const RECORD_RSSI: u8 = 0x05;
+
+pub const MEASUREMENT_VALUE_LEN: usize = 11;
+
+pub const MEASUREMENT_RECORD_LEN: usize = RECORD_HEADER_LEN + MEASUREMENT_VALUE_LEN;
+
+/// Room for extension records in a payload with a header and no trailers.
+pub const MAX_EXTENSIONS_LEN: usize = MAX_PAYLOAD_LEN - HEADER_LEN - MEASUREMENT_RECORD_LEN;
+
+const UPTIME_VALUE_LEN: usize = 3;
+
+// Every known extension has to fit in a payload with every trailer that a v2 payload can have
+const _: () = assert!(
+    RECORD_HEADER_LEN + UPTIME_VALUE_LEN <= extensions_capacity(FLAG_AUTHENTICATED | FLAG_CHECKSUM)
+);
+
+const MAX_MOISTURE: u32 = (1 << 24) - 1;
+
+const TEMPERATURE_BITS: u32 = 10;
+
+/// Sent as the temperature if the measurement had none.
+const RECORD_TEMPERATURE_UNAVAILABLE: i32 = -(1 << (TEMPERATURE_BITS - 1));
+
+const CAPACITOR_MASK: u32 = (1 << ADC_RESOLUTION_BITS) - 1;
+
+const MAX_UPTIME_MINUTES: u32 = (1 << 24) - 1;
+
+/// Reasons that records could not be decoded.
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum RecordError {
+    /// The bytes end in the middle of a record of this type.
+    Truncated(u8),
+    /// A record of a known type has a value too short to hold it.
+    TooShort { kind: u8, len: usize },
+    /// The records don't start with a measurement.
+    MissingMeasurement,
+    /// There is more than one measurement record.
+    ExtraMeasurement,
+}
+
+impl fmt::Display for RecordError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Truncated(kind) => write!(f, "record of type {:#04x} is truncated", kind),
+            Self::TooShort { kind, len } => write!(f, "record of type {:#04x} is too short ({} bytes)", kind, len),
+            Self::MissingMeasurement => write!(f, "records don't start with a measurement"),
+            Self::ExtraMeasurement => write!(f, "more than one measurement record"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for RecordError {}
+
+/// Why the sensor last reset, from the nRF52's RESETREAS register.
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ResetReason {
+    /// Power was applied, or the supply dropped below the brown-out threshold.
+    PowerOn,
+    Pin,
+    Watchdog,
+    /// The firmware asked for a reset.
+    SoftReset,
+    /// The CPU locked up, e.g. from a fault inside a fault handler.
+    Lockup,
+    /// Woke up from System OFF mode.
+    WakeFromOff,
+    Debugger,
+    /// A reason that this crate doesn't know about.
+    Other(u8),
+}
+
+impl ResetReason {
+    pub const fn from_u8(value: u8) -> Self {
+        match value {
+            0 => Self::PowerOn,
+            1 => Self::Pin,
+            2 => Self::Watchdog,
+            3 => Self::SoftReset,
+            4 => Self::Lockup,
+            5 => Self::WakeFromOff,
+            6 => Self::Debugger,
+            value => Self::Other(value),
+        }
+    }
+
+    pub const fn to_u8(self) -> u8 {
+        match self {
+            Self::PowerOn => 0,
+            Self::Pin => 1,
+            Self::Watchdog => 2,
+            Self::SoftReset => 3,
+            Self::Lockup => 4,
+            Self::WakeFromOff => 5,
+            Self::Debugger => 6,
+            Self::Other(value) => value,
+        }
+    }
+}
+
+impl fmt::Display for ResetReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::PowerOn => write!(f, "power on"),
+            Self::Pin => write!(f, "reset pin"),
+            Self::Watchdog => write!(f, "watchdog"),
+            Self::SoftReset => write!(f, "soft reset"),
+            Self::Lockup => write!(f, "lockup"),
+            Self::WakeFromOff => write!(f, "wake from off"),
+            Self::Debugger => write!(f, "debugger"),
+            Self::Other(value) => write!(f, "unknown ({})", value),
+        }
+    }
+}
+
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Record<'a> {
+    /// Encoding saturates the moisture count, temperature and capacitor voltage to what the
+    /// record can hold.
+    Measurement(Measurement),
+    FirmwareVersion { major: u8, minor: u8, patch: u8 },
+    /// Encoding saturates at 2^24 - 1 minutes.
+    Uptime { minutes: u32 },
+    ResetReason(ResetReason),
+    Rssi { dbm: i8 },
+    /// A type that this crate doesn't know about, which receivers should skip.
+    Unknown { kind: u8, value: &'a [u8] },
+}
+
+impl<'a> Record<'a> {
+    pub fn kind(&self) -> u8 {
+        match self {
+            Self::Measurement(_) => RECORD_MEASUREMENT,
+            Self::FirmwareVersion { .. } => RECORD_FIRMWARE_VERSION,
+            Self::Uptime { .. } => RECORD_UPTIME,
+            Self::ResetReason(_) => RECORD_RESET_REASON,
+            Self::Rssi { .. } => RECORD_RSSI,
+            Self::Unknown { kind, .. } => *kind,
+        }
+    }
+
+    /// Length of the encoded record, including the type and length bytes.
+    pub fn encoded_len(&self) -> usize {
+        let value_len = match self {
+            Self::Measurement(_) => MEASUREMENT_VALUE_LEN,
+            Self::FirmwareVersion { .. } => 3,
+            Self::Uptime { .. } => UPTIME_VALUE_LEN,
+            Self::ResetReason(_) | Self::Rssi { .. } => 1,
+            Self::Unknown { value, .. } => value.len(),
+        };
+        RECORD_HEADER_LEN + value_len
+    }
+
+    /// Writes the record into the start of `buf`, and returns the number of bytes written, or
+    /// `None` if it doesn't fit.
+    pub fn encode(&self, buf: &mut [u8]) -> Option<usize> {
+        let len = self.encoded_len();
+        let value_len = u8::try_from(len - RECORD_HEADER_LEN).ok()?;
+        let buf = buf.get_mut(..len)?;
+        buf[0] = self.kind();
+        buf[1] = value_len;
+        let value = &mut buf[RECORD_HEADER_LEN..];
+        match self {
+            Self::Measurement(measurement) => value.copy_from_slice(&encode_measurement(measurement)),
+            Self::FirmwareVersion { major, minor, patch } => value.copy_from_slice(&[*major, *minor, *patch]),
+            Self::Uptime { minutes } => value.copy_from_slice(&(*minutes).min(MAX_UPTIME_MINUTES).to_be_bytes()[1..]),
+            Self::ResetReason(reason) => value[0] = reason.to_u8(),
+            Self::Rssi { dbm } => value[0] = *dbm as u8,
+            Self::Unknown { value: unknown, .. } => value.copy_from_slice(unknown),
+        }
+        Some(len)
+    }
+
+    /// Decodes a single value. Bytes after the ones that a known type needs are ignored, since
+    /// they are fields added by a newer sender.
+    fn decode(kind: u8, value: &'a [u8]) -> Result<Self, RecordError> {
+        let too_short = RecordError::TooShort { kind, len: value.len() };
+        let record = match kind {
+            RECORD_MEASUREMENT => match value.first_chunk::<MEASUREMENT_VALUE_LEN>() {
+                Some(value) => Self::Measurement(decode_measurement(value)),
+                None => return Err(too_short),
+            },
+            RECORD_FIRMWARE_VERSION => match value {
+                [major, minor, patch, ..] => Self::FirmwareVersion { major: *major, minor: *minor, patch: *patch },
+                _ => return Err(too_short),
+            },
+            RECORD_UPTIME => match value {
+                [a, b, c, ..] => Self::Uptime { minutes: u32::from_be_bytes([0, *a, *b, *c]) },
+                _ => return Err(too_short),
+            },
+            RECORD_RESET_REASON => Self::ResetReason(ResetReason::from_u8(*value.first().ok_or(too_short)?)),
+            RECORD_RSSI => Self::Rssi { dbm: *value.first().ok_or(too_short)? as i8 },
+            kind => Self::Unknown { kind, value },
+        };
+        Ok(record)
+    }
+}
+
+fn encode_measurement(measurement: &Measurement) -> [u8; MEASUREMENT_VALUE_LEN] {
+    let temperature = if measurement.has_temperature() {
+        measurement.temperature.clamp(RECORD_TEMPERATURE_UNAVAILABLE + 1, -RECORD_TEMPERATURE_UNAVAILABLE - 1)
+    } else {
+        RECORD_TEMPERATURE_UNAVAILABLE
+    };
+    let capacitor_voltage = (measurement.capacitor_voltage.max(0) as u32).min(CAPACITOR_MASK);
+    let packed = ((temperature as u32) << ADC_RESOLUTION_BITS) | capacitor_voltage;
+
+    let id = measurement.id.to_be_bytes();
+    let sequence = measurement.sequence.to_be_bytes();
+    let moisture = measurement.moisture_frequency.min(MAX_MOISTURE).to_be_bytes();
+    let packed = packed.to_be_bytes();
+    [
+        id[0], id[1], sequence[0], sequence[1],
+        moisture[1], moisture[2], moisture[3],
+        packed[1], packed[2], packed[3],
+        measurement.status.0,
+    ]
+}
+
+fn decode_measurement(value: &[u8; MEASUREMENT_VALUE_LEN]) -> Measurement {
+    let packed = u32::from_be_bytes([0, value[7], value[8], value[9]]);
+    // Shifted up to the top of the word and back down, to sign extend it
+    let temperature = ((packed << (32 - ADC_RESOLUTION_BITS - TEMPERATURE_BITS)) as i32) >> (32 - TEMPERATURE_BITS);
+    Measurement {
+        id: u16::from_be_bytes([value[0], value[1]]),
+        sequence: u16::from_be_bytes([value[2], value[3]]),
+        moisture_frequency: u32::from_be_bytes([0, value[4], value[5], value[6]]),
+        temperature: if temperature == RECORD_TEMPERATURE_UNAVAILABLE { TEMPERATURE_UNAVAILABLE } else { temperature },
+        capacitor_voltage: (packed & CAPACITOR_MASK) as i16,
+        status: Status(value[10]),
+    }
+}
+
+/// Iterates over encoded records. Stops after the first error, since the records after a bad one
+/// can't be found.
+#[derive(Clone, Debug)]
+pub struct Records<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Records<'a> {
+    pub const fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes }
+    }
+}
+
+impl<'a> Iterator for Records<'a> {
+    type Item = Result<Record<'a>, RecordError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (kind, len, rest) = match self.bytes {
+            [] => return None,
+            [kind, len, rest @ ..] if rest.len() >= *len as usize => (*kind, *len as usize, rest),
+            [kind, ..] => {
+                let kind = *kind;
+                self.bytes = &[];
+                return Some(Err(RecordError::Truncated(kind)));
+            },
+        };
+        let (value, rest) = rest.split_at(len);
+        self.bytes = rest;
+        let record = Record::decode(kind, value);
+        if record.is_err() {
+            self.bytes = &[];
+        }
+        Some(record)
+    }
+}
+
+/// A measurement, and the extension records sent along with it, as carried by a v2 payload.
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Telemetry {
+    pub measurement: Measurement,
+    /// Encoded extension records
+    extensions: [u8; MAX_EXTENSIONS_LEN],
+    len: u8,
+}
+
+impl Telemetry {
+    pub const fn new(measurement: Measurement) -> Self {
+        Self { measurement, extensions: [0; MAX_EXTENSIONS_LEN], len: 0 }
+    }
+
+    /// Appends an extension record. Returns `false` if there is no room, or it is a measurement.
+    ///
+    /// This only checks that the record fits in a payload with no trailers. The payload will fail
+    /// to encode if its trailers don't fit as well, see [`crate::payload::extensions_capacity`].
+    pub fn push(&mut self, record: &Record) -> bool {
+        if let Record::Measurement(_) = record {
+            return false;
+        }
+        match record.encode(&mut self.extensions[self.len as usize..]) {
+            Some(len) => {
+                self.len += len as u8;
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// The extension records, which have all been checked to decode.
+    pub fn extensions(&self) -> impl Iterator<Item = Record<'_>> {
+        Records::new(self.extensions_bytes()).filter_map(Result::ok)
+    }
+
+    fn extensions_bytes(&self) -> &[u8] {
+        &self.extensions[..self.len as usize]
+    }
+
+    pub(crate) fn encoded_len(&self) -> usize {
+        MEASUREMENT_RECORD_LEN + self.len as usize
+    }
+
+    /// Writes the body into the start of `buf`, and returns the number of bytes written.
+    pub(crate) fn encode(&self, buf: &mut [u8]) -> usize {
+        let len = Record::Measurement(self.measurement).encode(buf).unwrap_or(0);
+        buf[len..len + self.len as usize].copy_from_slice(self.extensions_bytes());
+        len + self.len as usize
+    }
+
+    pub(crate) fn decode(bytes: &[u8]) -> Result<Self, RecordError> {
+        let mut records = Records::new(bytes);
+        let measurement = match records.next() {
+            Some(Ok(Record::Measurement(measurement))) => measurement,
+            Some(Err(e)) => return Err(e),
+            _ => return Err(RecordError::MissingMeasurement),
+        };
+
+        let mut telemetry = Self::new(measurement);
+        for record in records {
+            let record = record?;
+            if let Record::Measurement(_) = record {
+                return Err(RecordError::ExtraMeasurement);
+            }
+            // Re-encoding a record which decoded can only fail if there are more extensions than
+            // fit in a payload, which the caller has already ruled out
+            if !telemetry.push(&record) {
+                return Err(RecordError::Truncated(record.kind()));
+            }
+        }
+        Ok(telemetry)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::{SoftwareAes, KEY_LEN};
+    use crate::payload::{Body, Payload, PayloadBuffer};
+
+    fn measurement() -> Measurement {
+        Measurement {
+            id: 0x0123,
+            moisture_frequency: 6543,
+            temperature: -41,
+            capacitor_voltage: 9000,
+            sequence: 42,
+            status: Status::TEMPERATURE_STALE,
+        }
+    }
+
+    fn encode(record: &Record) -> ([u8; MAX_PAYLOAD_LEN], usize) {
+        let mut buf = [0u8; MAX_PAYLOAD_LEN];
+        let len = record.encode(&mut buf).expect("record should fit");
+        assert_eq!(len, record.encoded_len());
+        (buf, len)
+    }
+
+    /// Only for known types, since the decoded record can't borrow from the buffer.
+    fn round_trip(record: Record) -> Record<'static> {
+        let (buf, len) = encode(&record);
+        let mut records = Records::new(&buf[..len]);
+        let decoded = records.next().unwrap().unwrap();
+        assert!(records.next().is_none());
+        match decoded {
+            Record::Measurement(measurement) => Record::Measurement(measurement),
+            Record::FirmwareVersion { major, minor, patch } => Record::FirmwareVersion { major, minor, patch },
+            Record::Uptime { minutes } => Record::Uptime { minutes },
+            Record::ResetReason(reason) => Record::ResetReason(reason),
+            Record::Rssi { dbm } => Record::Rssi { dbm },
+            Record::Unknown { kind, .. } => panic!("decoded as unknown type {:#04x}", kind),
+        }
+    }
+
+    #[test]
+    fn measurement_round_trip() {
+        let (buf, len) = encode(&Record::Measurement(measurement()));
+        assert_eq!(len, MEASUREMENT_RECORD_LEN);
+        assert_eq!(&buf[..4], &[RECORD_MEASUREMENT, MEASUREMENT_VALUE_LEN as u8, 0x01, 0x23]);
+        assert_eq!(round_trip(Record::Measurement(measurement())), Record::Measurement(measurement()));
+
+        let missing = Measurement { temperature: TEMPERATURE_UNAVAILABLE, ..measurement() };
+        assert_eq!(round_trip(Record::Measurement(missing)), Record::Measurement(missing));
+    }
+
+    #[test]
+    fn measurement_saturates() {
+        let hot = Measurement { moisture_frequency: u32::MAX, temperature: 1000, capacitor_voltage: i16::MAX, ..measurement() };
+        let cold = Measurement { temperature: -1000, capacitor_voltage: -3, ..measurement() };
+        assert_eq!(round_trip(Record::Measurement(hot)), Record::Measurement(Measurement {
+            moisture_frequency: MAX_MOISTURE, temperature: 511, capacitor_voltage: 16383, ..hot
+        }));
+        // -512 means unavailable, so the coldest temperature is one step above it
+        assert_eq!(round_trip(Record::Measurement(cold)), Record::Measurement(Measurement {
+            temperature: -511, capacitor_voltage: 0, ..cold
+        }));
+    }
+
+    #[test]
+    fn extensions_round_trip() {
+        for record in [
+            Record::FirmwareVersion { major: 1, minor: 2, patch: 3 },
+            Record::Uptime { minutes: 0x123456 },
+            Record::ResetReason(ResetReason::Watchdog),
+            Record::ResetReason(ResetReason::Other(42)),
+            Record::Rssi { dbm: -70 },
+        ] {
+            assert_eq!(round_trip(record), record);
+        }
+        assert_eq!(round_trip(Record::Uptime { minutes: u32::MAX }), Record::Uptime { minutes: MAX_UPTIME_MINUTES });
+    }
+
+    #[test]
+    fn unknown_records_are_skipped() {
+        let bytes = [RECORD_RSSI, 1, 0xBA, 0x7F, 3, 1, 2, 3, RECORD_RESET_REASON, 1, 2];
+        let mut records = Records::new(&bytes);
+        assert_eq!(records.next(), Some(Ok(Record::Rssi { dbm: -70 })));
+        assert_eq!(records.next(), Some(Ok(Record::Unknown { kind: 0x7F, value: &[1, 2, 3] })));
+        assert_eq!(records.next(), Some(Ok(Record::ResetReason(ResetReason::Watchdog))));
+        assert_eq!(records.next(), None);
+    }
+
+    #[test]
+    fn newer_fields_are_ignored() {
+        let bytes = [RECORD_FIRMWARE_VERSION, 5, 1, 2, 3, 0xFF, 0xFF, RECORD_RSSI, 1, 0xBA];
+        let records = Records::new(&bytes).map(Result::unwrap);
+        assert!(records.eq([Record::FirmwareVersion { major: 1, minor: 2, patch: 3 }, Record::Rssi { dbm: -70 }]));
+    }
+
+    #[test]
+    fn errors_stop_the_iterator() {
+        let truncated = [RECORD_RSSI, 1, 0xBA, RECORD_UPTIME, 3, 0];
+        let mut records = Records::new(&truncated);
+        assert_eq!(records.next(), Some(Ok(Record::Rssi { dbm: -70 })));
+        assert_eq!(records.next(), Some(Err(RecordError::Truncated(RECORD_UPTIME))));
+        assert_eq!(records.next(), None);
+
+        let too_short = [RECORD_UPTIME, 2, 0, 0, RECORD_RSSI, 1, 0xBA];
+        let mut records = Records::new(&too_short);
+        assert_eq!(records.next(), Some(Err(RecordError::TooShort { kind: RECORD_UPTIME, len: 2 })));
+        assert_eq!(records.next(), None);
+
+        assert_eq!(Records::new(&[RECORD_RSSI]).next(), Some(Err(RecordError::Truncated(RECORD_RSSI))));
+    }
+
+    #[test]
+    fn telemetry_decode() {
+        let mut telemetry = Telemetry::new(measurement());
+        assert!(telemetry.push(&Record::Uptime { minutes: 60 }));
+        assert!(!telemetry.push(&Record::Measurement(measurement())));
+        let mut buf = [0u8; MAX_PAYLOAD_LEN];
+        let len = telemetry.encode(&mut buf);
+        assert_eq!(Telemetry::decode(&buf[..len]), Ok(telemetry));
+
+        let (rssi, rssi_len) = encode(&Record::Rssi { dbm: -70 });
+        assert_eq!(Telemetry::decode(&rssi[..rssi_len]), Err(RecordError::MissingMeasurement));
+        assert_eq!(Telemetry::decode(&[]), Err(RecordError::MissingMeasurement));
+
+        let (twice, measurement_len) = encode(&Record::Measurement(measurement()));
+        let mut bytes = [0u8; 2 * MEASUREMENT_RECORD_LEN];
+        bytes[..measurement_len].copy_from_slice(&twice[..measurement_len]);
+        bytes[measurement_len..].copy_from_slice(&twice[..measurement_len]);
+        assert_eq!(Telemetry::decode(&bytes), Err(RecordError::ExtraMeasurement));
+    }
+
+    #[test]
+    fn push_stops_when_full() {
+        let mut telemetry = Telemetry::new(measurement());
+        let mut pushed = 0;
+        while telemetry.push(&Record::Rssi { dbm: -70 }) {
+            pushed += 1;
+        }
+        assert_eq!(pushed, MAX_EXTENSIONS_LEN / 3);
+        assert_eq!(telemetry.extensions().count(), pushed);
+    }
+
+    #[test]
+    fn authenticated_payload_has_room_for_an_extension() {
+        let mut cipher = SoftwareAes::new(&[0x42; KEY_LEN]);
+        for extension in [
+            Record::FirmwareVersion { major: 1, minor: 2, patch: 3 },
+            Record::Uptime { minutes: 600 },
+            Record::ResetReason(ResetReason::PowerOn),
+        ] {
+            let mut telemetry = Telemetry::new(measurement());
+            assert!(telemetry.push(&extension));
+            let payload = Payload::telemetry(telemetry).with_flags(FLAG_CHECKSUM).authenticate(7, &mut cipher);
+            let mut buf: PayloadBuffer = [0; MAX_PAYLOAD_LEN];
+            let len = payload.encode(&mut buf).expect("payload should fit");
+            let decoded = Payload::decode(&buf[..len]).unwrap();
+            assert!(decoded.verify(&mut cipher));
+            assert_eq!(decoded.body, Body::Telemetry(telemetry));
+        }
+    }
+}
@@ -0,0 +1,76 @@
+//! Conversion of raw measurement fields into physical units.
+//!
+//! The constants here describe how the firmware configures its peripherals. The firmware uses
+//! them to configure the peripherals, and everything else uses them to interpret the readings,
+//! so that the two can't drift apart.
+
+use crate::Measurement;
+
+/// SAADC resolution, in bits.
+pub const ADC_RESOLUTION_BITS: u32 = 14;
+
+/// SAADC input gain (`GAIN1_4`).
+pub const ADC_GAIN: f32 = 1.0 / 4.0;
+
+/// SAADC reference voltage, as a fraction of VDD (`VDD1_4`).
+pub const ADC_REFERENCE_VDD_FRACTION: f32 = 1.0 / 4.0;
+
+/// Nominal supply voltage. The ADC reference is derived from this, so readings are only as
+/// accurate as the regulator.
+pub const VDD_VOLTS: f32 = 3.3;
+
+/// Input voltage which reads as full scale, `reference / gain`.
+pub const ADC_FULL_SCALE_VOLTS: f32 = VDD_VOLTS * ADC_REFERENCE_VDD_FRACTION / ADC_GAIN;
+
+/// The TEMP peripheral reports in units of 0.25 degrees C.
+pub const TEMPERATURE_STEP_CELSIUS: f32 = 0.25;
+
+/// Sent as the temperature if the sensor has not managed to read one since it reset.
+pub const TEMPERATURE_UNAVAILABLE: i32 = i32::MIN;
+
+/// Frequency of the low-frequency clock which drives the RTC.
+pub const LFCLK_FREQ: u32 = 32_768;
+
+/// RTC1 prescaler. The RTC ticks at `LFCLK_FREQ / (RTC_PRESCALER + 1)`.
+pub const RTC_PRESCALER: u32 = 0x007;
+
+pub const RTC_TICKS_PER_SECOND: u32 = LFCLK_FREQ / (RTC_PRESCALER + 1);
+
+/// Time given to the probe oscillator to settle, between enabling it and starting to count.
+pub const MOISTURE_SETTLE_TICKS: u32 = RTC_TICKS_PER_SECOND / 4;
+
+/// Length of the window in which probe oscillator pulses are counted.
+pub const MOISTURE_GATE_TICKS: u32 = RTC_TICKS_PER_SECOND;
+
+pub const MOISTURE_GATE_SECONDS: f32 = MOISTURE_GATE_TICKS as f32 / RTC_TICKS_PER_SECOND as f32;
+
+/// Converts a voltage into the raw ADC reading it would produce, e.g. for thresholds.
+pub const fn volts_to_adc(volts: f32) -> i16 {
+    (volts / ADC_FULL_SCALE_VOLTS * (1u32 << ADC_RESOLUTION_BITS) as f32) as i16
+}
+
+pub fn adc_to_volts(raw: i16) -> f32 {
+    raw as f32 / (1u32 << ADC_RESOLUTION_BITS) as f32 * ADC_FULL_SCALE_VOLTS
+}
+
+impl Measurement {
+    pub fn has_temperature(&self) -> bool {
+        self.temperature != TEMPERATURE_UNAVAILABLE
+    }
+
+    /// `None` if the sensor has not managed to read a temperature since it reset.
+    pub fn temperature_celsius(&self) -> Option<f32> {
+        self.has_temperature().then_some(self.temperature as f32 * TEMPERATURE_STEP_CELSIUS)
+    }
+
+    /// Voltage of the energy storage capacitor.
+    pub fn capacitor_volts(&self) -> f32 {
+        adc_to_volts(self.capacitor_voltage)
+    }
+
+    /// Frequency of the moisture probe oscillator. `moisture_frequency` is the number of pulses
+    /// counted in the gate window, which only happens to be 1 second.
+    pub fn moisture_hz(&self) -> f32 {
+        self.moisture_frequency as f32 / MOISTURE_GATE_SECONDS
+    }
+}
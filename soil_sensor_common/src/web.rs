@@ -1,23 +1,60 @@
-use crate::Measurement;
+use crate::{Measurement, Status};
+use crate::sequence::SequenceEvent;
+use crate::telemetry::{Record, Telemetry};
 use serde::{Serialize, Deserialize};
-use chrono::{DateTime, Local};
-use influxdb::InfluxDbWriteable;
+use chrono::{DateTime, Local, NaiveDate};
+use influxdb::{InfluxDbWriteable, Timestamp, WriteQuery};
 
-#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, InfluxDbWriteable)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
 pub struct InfluxDBMeasurement {
-    #[influxdb(tag)]
     pub id: u16,
-    #[influxdb(tag)]
     pub mac_address: String,
 
     pub moisture_level: u32,
-    pub temperature: f32,
+    /// Volumetric water content in percent, if the sensor has a calibration
+    pub moisture_vwc: Option<f32>,
+    /// `None` if the sensor has not managed to read a temperature since it reset
+    pub temperature: Option<f32>,
     pub capacitor_voltage: f32,
     pub sequence: u16,
+    pub status: Status,
+    /// Packets from this sensor which were skipped over just before this one
+    pub missed_packets: Option<u16>,
+    /// Fraction of packets from this sensor which were never received, since the bridge started
+    pub loss_rate: Option<f32>,
+    /// Whether this is the first packet received since the sensor reset
+    pub reboot: Option<bool>,
+    /// From telemetry extension records, if the sensor sent them
+    pub firmware_version: Option<String>,
+    pub uptime_seconds: Option<u32>,
+    pub reset_reason: Option<String>,
+    /// Signal strength of the bridge, as heard by the sensor
+    pub sensor_rssi: Option<i8>,
+    /// Signal strength of the sensor, as heard by the bridge. Left out of backfilled
+    /// measurements, since it says nothing about when they were taken.
+    pub rssi: Option<i16>,
+    /// From the bridge's sensor registry, if the sensor is registered
+    pub sensor_name: Option<String>,
+    pub zone: Option<String>,
+    pub plant: Option<String>,
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+    pub installed: Option<NaiveDate>,
 
     pub time: DateTime<Local>
 }
 
+/// What the bridge knows about a sensor from its registry, to be attached to its measurements.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct SensorInfo {
+    pub name: Option<String>,
+    pub zone: Option<String>,
+    pub plant: Option<String>,
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+    pub installed: Option<NaiveDate>,
+}
+
 impl InfluxDBMeasurement {
     pub fn new(measurement: &Measurement, address: &[u8; 6], time: DateTime<Local>) -> Self {
         let mac_address: String = format!(
@@ -29,18 +66,32 @@ impl InfluxDBMeasurement {
             address[4],
             address[5],
         );
-        let temperature: f32 = measurement.temperature as f32 * 0.25;
-        let capacitor_voltage: f32 = (measurement.capacitor_voltage as f32 / (2i32.pow(14)) as f32) * 3.3;
-
-        
+        let temperature: Option<f32> = measurement.temperature_celsius();
+        let capacitor_voltage: f32 = measurement.capacitor_volts();
 
         Self {
             id: measurement.id,
             mac_address,
             moisture_level: measurement.moisture_frequency,
+            moisture_vwc: None,
             temperature,
             capacitor_voltage,
             sequence: measurement.sequence,
+            status: measurement.status,
+            missed_packets: None,
+            loss_rate: None,
+            reboot: None,
+            firmware_version: None,
+            uptime_seconds: None,
+            reset_reason: None,
+            sensor_rssi: None,
+            rssi: None,
+            sensor_name: None,
+            zone: None,
+            plant: None,
+            latitude: None,
+            longitude: None,
+            installed: None,
             time
         }
     }
@@ -49,4 +100,142 @@ impl InfluxDBMeasurement {
         let time = Local::now();
         Self::new(measurement, address, time)
     }
-}
\ No newline at end of file
+
+    pub fn with_moisture_vwc(mut self, moisture_vwc: Option<f32>) -> Self {
+        self.moisture_vwc = moisture_vwc;
+        self
+    }
+
+    pub fn with_sequence_event(mut self, event: SequenceEvent, loss_rate: f32) -> Self {
+        self.missed_packets = Some(event.missed());
+        self.loss_rate = Some(loss_rate);
+        self.reboot = Some(matches!(event, SequenceEvent::Reboot { .. }));
+        self
+    }
+
+    pub fn with_rssi(mut self, rssi: Option<i16>) -> Self {
+        self.rssi = rssi;
+        self
+    }
+
+    pub fn with_sensor_info(mut self, info: &SensorInfo) -> Self {
+        self.sensor_name = info.name.clone();
+        self.zone = info.zone.clone();
+        self.plant = info.plant.clone();
+        self.latitude = info.latitude;
+        self.longitude = info.longitude;
+        self.installed = info.installed;
+        self
+    }
+
+    /// Fills in whatever the telemetry's extension records carry. Records of unknown types are
+    /// skipped.
+    pub fn with_telemetry(mut self, telemetry: &Telemetry) -> Self {
+        for record in telemetry.extensions() {
+            match record {
+                Record::FirmwareVersion { major, minor, patch } =>
+                    self.firmware_version = Some(format!("{}.{}.{}", major, minor, patch)),
+                Record::Uptime { minutes } => self.uptime_seconds = Some(minutes.saturating_mul(60)),
+                Record::ResetReason(reason) => self.reset_reason = Some(reason.to_string()),
+                Record::Rssi { dbm } => self.sensor_rssi = Some(dbm),
+                Record::Measurement(_) | Record::Unknown { .. } => (),
+            }
+        }
+        self
+    }
+}
+
+/// A value in a point, for sinks which write their own line protocol rather than going through
+/// the `influxdb` crate.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Boolean(bool),
+    Integer(i64),
+    UnsignedInteger(u64),
+    Float(f64),
+    Text(String),
+}
+
+impl From<Value> for influxdb::Type {
+    fn from(value: Value) -> Self {
+        match value {
+            Value::Boolean(b) => Self::Boolean(b),
+            Value::Integer(i) => Self::SignedInteger(i),
+            Value::UnsignedInteger(u) => Self::UnsignedInteger(u),
+            Value::Float(f) => Self::Float(f),
+            Value::Text(s) => Self::Text(s),
+        }
+    }
+}
+
+impl InfluxDBMeasurement {
+    /// Values written as tags, unless a sink is configured otherwise. Everything else is a field.
+    pub const DEFAULT_TAGS: [&'static str; 9] = [
+        "id", "mac_address", "moisture_valid",
+        "sensor_name", "zone", "plant", "latitude", "longitude", "installed",
+    ];
+
+    const BASE_VALUE_NAMES: [&'static str; 23] = [
+        "id", "mac_address", "moisture_valid", "moisture_level", "temperature", "capacitor_voltage",
+        "sequence", "status", "moisture_vwc", "missed_packets", "loss_rate", "reboot",
+        "firmware_version", "uptime_seconds", "reset_reason", "sensor_rssi", "rssi",
+        "sensor_name", "zone", "plant", "latitude", "longitude", "installed",
+    ];
+
+    /// Name of every value that [`Self::values`] can return.
+    pub fn value_names() -> impl Iterator<Item = &'static str> {
+        Self::BASE_VALUE_NAMES.into_iter()
+            .chain(Status::NAMES.into_iter().map(|(_, name)| name))
+    }
+
+    /// Every value in the point, by name. Optional values are left out entirely when they are
+    /// missing.
+    pub fn values(&self) -> Vec<(&'static str, Value)> {
+        let mut values = vec![
+            ("id", Value::UnsignedInteger(self.id.into())),
+            ("mac_address", Value::Text(self.mac_address.clone())),
+            ("moisture_valid", Value::Boolean(self.status.moisture_valid())),
+            ("moisture_level", Value::UnsignedInteger(self.moisture_level.into())),
+            ("capacitor_voltage", Value::Float(self.capacitor_voltage.into())),
+            ("sequence", Value::UnsignedInteger(self.sequence.into())),
+            ("status", Value::UnsignedInteger(self.status.0.into())),
+        ];
+        for (flag, name) in Status::NAMES {
+            values.push((name, Value::Boolean(self.status.contains(flag))));
+        }
+        let optional = [
+            ("temperature", self.temperature.map(|v| Value::Float(v.into()))),
+            ("moisture_vwc", self.moisture_vwc.map(|v| Value::Float(v.into()))),
+            ("missed_packets", self.missed_packets.map(|v| Value::UnsignedInteger(v.into()))),
+            ("loss_rate", self.loss_rate.map(|v| Value::Float(v.into()))),
+            ("reboot", self.reboot.map(Value::Boolean)),
+            ("firmware_version", self.firmware_version.clone().map(Value::Text)),
+            ("uptime_seconds", self.uptime_seconds.map(|v| Value::UnsignedInteger(v.into()))),
+            ("reset_reason", self.reset_reason.clone().map(Value::Text)),
+            ("sensor_rssi", self.sensor_rssi.map(|v| Value::Integer(v.into()))),
+            ("rssi", self.rssi.map(|v| Value::Integer(v.into()))),
+            ("sensor_name", self.sensor_name.clone().map(Value::Text)),
+            ("zone", self.zone.clone().map(Value::Text)),
+            ("plant", self.plant.clone().map(Value::Text)),
+            ("latitude", self.latitude.map(Value::Float)),
+            ("longitude", self.longitude.map(Value::Float)),
+            ("installed", self.installed.map(|v| Value::Text(v.to_string()))),
+        ];
+        values.extend(optional.into_iter().filter_map(|(name, value)| value.map(|value| (name, value))));
+        values
+    }
+}
+
+impl InfluxDbWriteable for InfluxDBMeasurement {
+    fn into_query<I: Into<String>>(self, name: I) -> WriteQuery {
+        let mut query = Timestamp::from(self.time).into_query(name);
+        for (name, value) in self.values() {
+            query = if Self::DEFAULT_TAGS.contains(&name) {
+                query.add_tag(name, value)
+            } else {
+                query.add_field(name, value)
+            };
+        }
+        query
+    }
+}
@@ -4,6 +4,7 @@ MEMORY
   /* These values correspond to the NRF52840 with Softdevices S140 7.0.1 */
   /* FLASH : ORIGIN = 0x00000000 + 100k, LENGTH = 192K - 100k */
   /* RAM : ORIGIN = 0x20000000 + 0x2f38, LENGTH = 24K - 0x2f38 */
-  FLASH : ORIGIN = 0x00000000 + 100k, LENGTH = 192k - 92k
+  /* The last page of flash (0x2F000) is reserved for the boot counter, see security.rs */
+  FLASH : ORIGIN = 0x00000000 + 100k, LENGTH = 192k - 100k - 4k
   RAM : ORIGIN = 0x20000000 + 0x2f38, LENGTH = 24k - 0x2f38
 }
\ No newline at end of file
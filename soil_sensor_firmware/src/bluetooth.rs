@@ -1,9 +1,17 @@
 use nrf_softdevice::ble::{gatt_server, peripheral};
-use nrf_softdevice::{raw, Softdevice};
+use nrf_softdevice::{raw, Flash, Softdevice};
 use core::mem;
-use defmt::debug;
-use soil_sensor_common::{Measurement, Serialized, COMPANY_ID_CODE};
+use defmt::{debug, error, info, warn};
+use soil_sensor_common::{Measurement, Payload, COMPANY_ID_CODE};
+use soil_sensor_common::history::History;
+use soil_sensor_common::payload::{extensions_capacity, history_capacity, PayloadBuffer, FLAG_AUTHENTICATED, FLAG_CHECKSUM, MAX_PAYLOAD_LEN};
+use soil_sensor_common::telemetry::{Record, ResetReason, Telemetry};
 use crate::sensor_periph::{SENSOR_ID_BYTES as ID, SENSOR_ID_BYTES};
+use crate::security::{self, SoftdeviceEcb, SENSOR_KEY};
+#[cfg(feature = "bthome")]
+use soil_sensor_common::bthome::{BthomeData, MAX_SERVICE_DATA_LEN, SERVICE_UUID};
+#[cfg(feature = "bthome")]
+use crate::bthome;
 
 // TODO: Surely there's a more elegant way to do this...
 const GAP_NAME: [u8; 20] = [
@@ -12,16 +20,47 @@ const GAP_NAME: [u8; 20] = [
     SENSOR_ID_BYTES[0], SENSOR_ID_BYTES[1], SENSOR_ID_BYTES[2], SENSOR_ID_BYTES[3]
 ];
 
+/// Trailer flags of every payload. Encrypted payloads have the same trailers as authenticated
+/// ones.
+const PAYLOAD_FLAGS: u8 = FLAG_CHECKSUM | if SENSOR_KEY.is_some() { FLAG_AUTHENTICATED } else { 0 };
+
+/// How many buffered measurements fit in one advertisement.
+pub const HISTORY_CAPACITY: usize = history_capacity(PAYLOAD_FLAGS);
+
+/// How many bytes of telemetry extensions fit in one advertisement.
+const EXTENSIONS_CAPACITY: usize = extensions_capacity(PAYLOAD_FLAGS);
+
+const fn parse_version(string: &str) -> u8 {
+    let bytes = string.as_bytes();
+    let mut value: u8 = 0;
+    let mut i = 0;
+    while i < bytes.len() {
+        assert!(bytes[i].is_ascii_digit());
+        value = value * 10 + (bytes[i] - b'0');
+        i += 1;
+    }
+    value
+}
+
+const FIRMWARE_VERSION: Record<'static> = Record::FirmwareVersion {
+    major: parse_version(env!("CARGO_PKG_VERSION_MAJOR")),
+    minor: parse_version(env!("CARGO_PKG_VERSION_MINOR")),
+    patch: parse_version(env!("CARGO_PKG_VERSION_PATCH")),
+};
 
 pub struct SensorBluetooth {
     pub sd: &'static Softdevice,
+    boot_count: u16,
+    reset_reason: ResetReason,
+    /// Which telemetry extension to try sending next
+    next_extension: usize,
 }
 
 impl SensorBluetooth {
     /// Panics if:
     ///  - Not enough RAM set aside for the SoftDevice
     ///  - Called more than once
-    pub fn new() -> Result<Self, gatt_server::RegisterError> {
+    pub fn new(reset_reason: ResetReason) -> Result<Self, gatt_server::RegisterError> {
         let config = nrf_softdevice::Config {
             clock: Some(raw::nrf_clock_lf_cfg_t {
                 source: raw::NRF_CLOCK_LF_SRC_XTAL as u8,
@@ -54,11 +93,79 @@ impl SensorBluetooth {
 
         Ok(Self {
             sd,
+            boot_count: 0,
+            reset_reason,
+            next_extension: 0,
         })
     }
 
-    // TODO: Documentation
-    pub async fn advertise(&self, measurement: &Measurement) -> Result<(), peripheral::AdvertiseError> {
+    /// Increments the persisted boot count, which is sent alongside authenticated payloads.
+    /// Must only be called once, and only after the SoftDevice is running.
+    ///
+    /// Returns `false` if payloads are authenticated, but the boot count couldn't be updated. The
+    /// nonces of this boot would then repeat those of an earlier one, so nothing may be sent.
+    pub async fn load_boot_count(&mut self) -> bool {
+        let mut flash = Flash::take(self.sd);
+        match security::increment_boot_count(&mut flash).await {
+            Ok(count) => {
+                info!("Boot count: {}", count);
+                self.boot_count = count;
+                true
+            },
+            Err(e) if SENSOR_KEY.is_some() => {
+                error!("Failed to update boot count, so payloads can't be authenticated: {}", e);
+                false
+            },
+            Err(e) => {
+                warn!("Failed to update boot count: {}", e);
+                true
+            }
+        }
+    }
+
+    /// Advertises a new measurement. Unless it has to be encrypted, it is sent in a v2 payload
+    /// along with one of the telemetry extensions, see [`Self::telemetry`].
+    #[cfg_attr(feature = "bthome", allow(unused_variables))]
+    pub async fn advertise(&mut self, measurement: &Measurement, uptime_seconds: u32) -> Result<(), peripheral::AdvertiseError> {
+        #[cfg(feature = "bthome")]
+        return self.advertise_bthome(measurement).await;
+        #[cfg(not(feature = "bthome"))]
+        return if cfg!(feature = "encrypted") {
+            self.advertise_payload(Payload::new(*measurement)).await
+        } else {
+            let telemetry = self.telemetry(measurement, uptime_seconds);
+            self.advertise_payload(Payload::telemetry(telemetry)).await
+        };
+    }
+
+    /// The measurement, along with the next telemetry extension which fits. There is only room
+    /// for one at a time, so they take turns, starting with the reset reason since that is most
+    /// interesting right after a reset.
+    #[cfg_attr(feature = "bthome", allow(dead_code))]
+    fn telemetry(&mut self, measurement: &Measurement, uptime_seconds: u32) -> Telemetry {
+        let extensions = [
+            Record::ResetReason(self.reset_reason),
+            FIRMWARE_VERSION,
+            Record::Uptime { minutes: uptime_seconds / 60 },
+        ];
+        let mut telemetry = Telemetry::new(*measurement);
+        for _ in 0..extensions.len() {
+            let record = &extensions[self.next_extension];
+            self.next_extension = (self.next_extension + 1) % extensions.len();
+            if record.encoded_len() <= EXTENSIONS_CAPACITY && telemetry.push(record) {
+                break;
+            }
+        }
+        telemetry
+    }
+
+    /// Advertises measurements which were buffered, instead of the latest one. `history` must not
+    /// have more than [`HISTORY_CAPACITY`] records.
+    pub async fn advertise_history(&self, history: &History) -> Result<(), peripheral::AdvertiseError> {
+        self.advertise_payload(Payload::history(*history)).await
+    }
+
+    async fn advertise_payload(&self, payload: Payload) -> Result<(), peripheral::AdvertiseError> {
 
         let adv_data = [
             // Flags
@@ -70,11 +177,56 @@ impl SensorBluetooth {
             b'S', b'e', b'n', b's', b'o', b'r', b' ', ID[0], ID[1], ID[2], ID[3],
         ];
 
-        let mut scan_data = [
-            17, 0xFF, COMPANY_ID_CODE[0], COMPANY_ID_CODE[1], 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0
-        ];
-        scan_data[4..].copy_from_slice(measurement.to_bytes().as_slice());
+        let mut payload = payload.with_flags(FLAG_CHECKSUM);
+        if let Some(key) = &SENSOR_KEY {
+            let mut cipher = SoftdeviceEcb::new(key);
+            payload = if cfg!(feature = "encrypted") {
+                payload.encrypt(self.boot_count, &mut cipher)
+            } else {
+                payload.authenticate(self.boot_count, &mut cipher)
+            };
+        }
+        let mut encoded: PayloadBuffer = [0; MAX_PAYLOAD_LEN];
+        // Encoding cannot fail, as long as histories and telemetry extensions fit
+        let payload_len = payload.encode(&mut encoded).unwrap();
+
+        let mut scan_data = [0u8; 4 + MAX_PAYLOAD_LEN];
+        // Length covers the type and company ID, as well as the payload itself
+        scan_data[0..4].copy_from_slice(&[3 + payload_len as u8, 0xFF, COMPANY_ID_CODE[0], COMPANY_ID_CODE[1]]);
+        scan_data[4..4 + payload_len].copy_from_slice(&encoded[..payload_len]);
+        let scan_data = &scan_data[..4 + payload_len];
+
+        self.send(&adv_data, scan_data).await
+    }
+
+    /// Advertises the measurement as BTHome service data, instead of our own payload. The service
+    /// data has to be in the advertising data, since receivers like Home Assistant scan passively
+    /// and never see scan responses. That leaves no room for the name, which goes in the scan
+    /// response instead.
+    #[cfg(feature = "bthome")]
+    async fn advertise_bthome(&self, measurement: &Measurement) -> Result<(), peripheral::AdvertiseError> {
+        let data = BthomeData::from_measurement(measurement, Some(bthome::moisture_percent(measurement)));
+        let mut service_data = [0u8; MAX_SERVICE_DATA_LEN];
+        let service_data_len = data.encode(&mut service_data);
+
+        let mut adv_data = [0u8; 3 + 4 + MAX_SERVICE_DATA_LEN];
+        // Flags
+        adv_data[0..3].copy_from_slice(&[0x02, 0x01, raw::BLE_GAP_ADV_FLAGS_LE_ONLY_GENERAL_DISC_MODE as u8]);
+        // Service data, 16-bit UUID. Length covers the type and UUID, as well as the data itself
+        let uuid = SERVICE_UUID.to_le_bytes();
+        adv_data[3..7].copy_from_slice(&[3 + service_data_len as u8, 0x16, uuid[0], uuid[1]]);
+        adv_data[7..7 + service_data_len].copy_from_slice(&service_data[..service_data_len]);
+        let adv_data = &adv_data[..7 + service_data_len];
+
+        let mut scan_data = [0u8; 2 + GAP_NAME.len()];
+        // Name: "BLE Soil Sensor <ID>"
+        scan_data[0..2].copy_from_slice(&[1 + GAP_NAME.len() as u8, 0x09]);
+        scan_data[2..].copy_from_slice(&GAP_NAME);
+
+        self.send(adv_data, &scan_data).await
+    }
 
+    async fn send(&self, adv_data: &[u8], scan_data: &[u8]) -> Result<(), peripheral::AdvertiseError> {
         let config = peripheral::Config{
             // 2 seconds
             timeout: Some(200),
@@ -84,7 +236,7 @@ impl SensorBluetooth {
             filter_policy: peripheral::FilterPolicy::Any,
             ..peripheral::Config::default()
         };
-        let adv = peripheral::NonconnectableAdvertisement::ScannableUndirected { adv_data: &adv_data, scan_data: &scan_data };
+        let adv = peripheral::NonconnectableAdvertisement::ScannableUndirected { adv_data, scan_data };
         let conn = peripheral::advertise(self.sd, adv, &config).await?;
         Ok(conn)
     }
@@ -0,0 +1,46 @@
+use soil_sensor_common::calibration::{Calibration, CalibrationPoint};
+use soil_sensor_common::Measurement;
+use crate::security::SENSOR_KEY;
+
+const _: () = assert!(
+    SENSOR_KEY.is_none(),
+    "BTHome advertisements can't be authenticated. Unset SENSOR_KEY, or disable the \"bthome\" feature"
+);
+
+/// Statically parse a decimal frequency in Hz from an environment variable.
+/// Compilation will fail if it is not set, or not a valid decimal number.
+const fn get_hz(value: Option<&'static str>) -> u32 {
+    let string = match value {
+        Some(string) => string,
+        None => panic!("The \"bthome\" feature requires environment variables SENSOR_DRY_HZ and SENSOR_WET_HZ")
+    };
+    let mut bytes = string.as_bytes();
+    assert!(!bytes.is_empty(), "Moisture calibration frequencies must not be empty");
+    let mut res: u32 = 0;
+    while let [byte, rest @ ..] = bytes {
+        bytes = rest;
+        let digit = match byte {
+            b'0'..=b'9' => *byte - b'0',
+            _ => panic!("Moisture calibration frequencies must be decimal numbers of Hz")
+        };
+        res = res * 10 + digit as u32;
+    }
+    res
+}
+
+const DRY_HZ: u32 = get_hz(option_env!("SENSOR_DRY_HZ"));
+const WET_HZ: u32 = get_hz(option_env!("SENSOR_WET_HZ"));
+
+const _: () = assert!(DRY_HZ != WET_HZ, "SENSOR_DRY_HZ and SENSOR_WET_HZ must be different");
+
+/// BTHome receivers expect a moisture percentage, but the sensor doesn't know anything about the
+/// soil it is in. So this is relative, from 0% at the probe's frequency in dry soil to 100% in
+/// saturated soil, rather than volumetric water content.
+const MOISTURE_CALIBRATION: Calibration<'static> = Calibration::linear(
+    CalibrationPoint { hz: DRY_HZ as f32, vwc: 0.0 },
+    CalibrationPoint { hz: WET_HZ as f32, vwc: 100.0 },
+);
+
+pub fn moisture_percent(measurement: &Measurement) -> f32 {
+    MOISTURE_CALIBRATION.vwc(measurement.moisture_hz())
+}
@@ -0,0 +1,84 @@
+use soil_sensor_common::Measurement;
+use soil_sensor_common::history::{History, HistoryRecord};
+
+/// Two days of hourly measurements. Each entry takes about 24 bytes of RAM.
+pub const BUFFER_LEN: usize = 48;
+
+#[derive(Copy, Clone)]
+struct Entry {
+    measurement: Measurement,
+    /// Seconds since reset when the measurement was taken.
+    uptime_seconds: u32,
+}
+
+/// Ring buffer of measurements which could not be sent when they were taken, oldest first.
+pub struct HistoryBuffer {
+    entries: [Option<Entry>; BUFFER_LEN],
+    /// Index of the oldest entry
+    start: usize,
+    len: usize,
+}
+
+impl HistoryBuffer {
+    pub const fn new() -> Self {
+        Self { entries: [None; BUFFER_LEN], start: 0, len: 0 }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Adds a measurement, overwriting the oldest one if the buffer is full. Returns `true` if a
+    /// measurement was overwritten.
+    pub fn push(&mut self, measurement: Measurement, uptime_seconds: u32) -> bool {
+        let full = self.len == BUFFER_LEN;
+        let index = (self.start + self.len) % BUFFER_LEN;
+        self.entries[index] = Some(Entry { measurement, uptime_seconds });
+        if full {
+            self.start = (self.start + 1) % BUFFER_LEN;
+        } else {
+            self.len += 1;
+        }
+        full
+    }
+
+    fn get(&self, i: usize) -> Option<&Entry> {
+        if i < self.len {
+            self.entries[(self.start + i) % BUFFER_LEN].as_ref()
+        } else {
+            None
+        }
+    }
+
+    /// Packs up to `capacity` of the oldest measurements into a history, stopping early at a gap
+    /// in the sequence numbers. Ages are relative to `now_seconds`, the current uptime.
+    pub fn oldest(&self, capacity: usize, now_seconds: u32) -> Option<History> {
+        let first = self.get(0)?;
+        let mut history = History::new(first.measurement.id, first.measurement.sequence);
+        for i in 0..capacity {
+            let Some(entry) = self.get(i) else { break };
+            if entry.measurement.sequence != history.first_sequence.wrapping_add(i as u16) {
+                break;
+            }
+            let age_seconds = now_seconds.saturating_sub(entry.uptime_seconds);
+            let age_minutes = ((age_seconds + 30) / 60).min(u16::MAX as u32) as u16;
+            history.push(HistoryRecord::new(&entry.measurement, age_minutes));
+        }
+        Some(history)
+    }
+
+    /// Drops the measurements in `history`, once it has been sent. Anything that was overwritten
+    /// while it was being sent is already gone, and is skipped.
+    pub fn remove_sent(&mut self, history: &History) {
+        while let Some(entry) = self.get(0) {
+            let sequence = entry.measurement.sequence;
+            let sent = sequence.wrapping_sub(history.first_sequence) < history.len() as u16;
+            if !sent {
+                break;
+            }
+            self.entries[self.start] = None;
+            self.start = (self.start + 1) % BUFFER_LEN;
+            self.len -= 1;
+        }
+    }
+}
@@ -5,6 +5,10 @@
 
 mod sensor_periph;
 mod bluetooth;
+mod security;
+mod buffer;
+#[cfg(feature = "bthome")]
+mod bthome;
 
 use rtic::app;
 
@@ -21,9 +25,13 @@ use defmt::{trace, debug, info, warn, error};
 use soil_sensor_common::Measurement;
 
 // TODO: Find the right value for this
-//  Also maybe find a better place to put it?
 //  Currently set to 1V
-pub const ADC_MEASUREMENT_THRESHOLD: i16 = ((1.0 / 3.3) * 2i32.pow(14u32) as f64) as i16;
+pub const ADC_MEASUREMENT_THRESHOLD: i16 = soil_sensor_common::units::volts_to_adc(1.0);
+
+/// How many advertisements of buffered measurements to send after each new one. Each costs as
+/// much energy as the new one, so this is kept low; a backlog is cleared over several hours.
+/// BTHome has no way to send them at all, so nothing is buffered with the "bthome" feature.
+pub const BACKFILL_ADVERTISEMENTS: usize = if cfg!(feature = "bthome") { 0 } else { 2 };
 
 #[app(device = pac, peripherals = false, dispatchers = [SWI3])]
 mod app {
@@ -34,6 +42,7 @@ mod app {
     #[shared]
     struct Shared {
         peripherals: sensor_periph::Peripherals,
+        buffer: buffer::HistoryBuffer,
     }
 
     #[local]
@@ -44,7 +53,8 @@ mod app {
 
     #[init(local = [dma_buffer : [i16; 1] = [0; 1]])]
     fn init(mut cx: init::Context) -> (Shared, Local) {
-        info!("Initialized with SENSOR_ID={=u16:#04X}", sensor_periph::SENSOR_ID);
+        info!("Initialized with SENSOR_ID={=u16:#04X}, authenticated: {}",
+            sensor_periph::SENSOR_ID, security::SENSOR_KEY.is_some());
         let p = pac::Peripherals::take().unwrap();
         let dma_buffer : &'static mut [i16] = cx.local.dma_buffer.as_mut_slice();
         let peripherals = sensor_periph::Peripherals::new(
@@ -62,6 +72,7 @@ mod app {
         (
             Shared {
                 peripherals,
+                buffer: buffer::HistoryBuffer::new(),
             },
             Local {
                 measurements_r: r,
@@ -80,25 +91,37 @@ mod app {
         }
     }
 
-    #[task(binds = RTC1, shared = [peripherals], local = [measurements_s])]
+    #[task(binds = RTC1, shared = [peripherals, buffer], local = [measurements_s])]
     fn timer_callback(mut cx: timer_callback::Context)
     {
         trace!("[timer_callback] Timer interrupt");
 
-        let meas: Measurement = cx.shared.peripherals.lock(|p: &mut sensor_periph::Peripherals| {
+        let (meas, uptime): (Measurement, u32) = cx.shared.peripherals.lock(|p: &mut sensor_periph::Peripherals| {
             // Need to reset the event, otherwise this interrupt gets triggered repeatedly
             p.reset_rtc_event();
             // TODO: Do this with PPI
             let _ = p.disable_probe();
-            p.get_measurement()
+            (p.get_measurement(), p.uptime_seconds())
         });
 
         let should_send = meas.capacitor_voltage > ADC_MEASUREMENT_THRESHOLD;
         debug!("[timer_callback] Send? {}, Measurement: {}", should_send, meas);
 
+        let mut sent = false;
         if  should_send {
             let sender: &mut Sender<'static, Measurement, 1> = cx.local.measurements_s;
-            sender.try_send(meas).unwrap_or_else(|_| warn!("[timer_callback] Send error"));
+            sent = sender.try_send(meas).map_err(|_| warn!("[timer_callback] Send error")).is_ok();
+        }
+
+        // Keep it for later, and send it along with a future measurement instead. A history has no
+        // room for the status, so only keep readings whose moisture means something.
+        if !sent && meas.status.moisture_valid() && BACKFILL_ADVERTISEMENTS > 0 {
+            cx.shared.buffer.lock(|b: &mut buffer::HistoryBuffer| {
+                if b.push(meas, uptime) {
+                    warn!("[timer_callback] History buffer full, dropped the oldest measurement");
+                }
+                debug!("[timer_callback] Buffered measurement, {} waiting", b.len());
+            });
         }
     }
 
@@ -130,12 +153,18 @@ mod app {
         }
     }
 
-    #[task(priority = 1, local = [measurements_r])]
+    #[task(priority = 1, shared = [peripherals, buffer], local = [measurements_r])]
     async fn ble_service(mut cx: ble_service::Context) {
-        let bt = bluetooth::SensorBluetooth::new().unwrap();
+        let reset_reason = cx.shared.peripherals.lock(|p: &mut sensor_periph::Peripherals| p.reset_reason());
+        info!("Reset reason: {}", reset_reason);
+        let mut bt = bluetooth::SensorBluetooth::new(reset_reason).unwrap();
 
         let receiver: &mut Receiver<'static, Measurement, 1> = &mut cx.local.measurements_r;
         softdevice_runner::spawn().unwrap();
+        if !bt.load_boot_count().await {
+            error!("Not advertising until the sensor is reset");
+            return;
+        }
 
         loop {
             let result = receiver.recv().await;
@@ -146,7 +175,8 @@ mod app {
                     debug!("HFCLK running: {}", r);
                 }
 
-                let adv_result = bt.advertise(&meas).await;
+                let uptime = cx.shared.peripherals.lock(|p: &mut sensor_periph::Peripherals| p.uptime_seconds());
+                let adv_result = bt.advertise(&meas, uptime).await;
                 unsafe {
                     let mut r: u32 = 0;
                     nrf_softdevice::raw::sd_clock_hfclk_is_running(&mut r as *mut u32);
@@ -157,6 +187,24 @@ mod app {
                 } else {
                     warn!("Unexpected advertising result: {}", adv_result);
                 }
+
+                // There was enough energy for this measurement, so send some buffered ones too
+                for _ in 0..BACKFILL_ADVERTISEMENTS {
+                    let now = cx.shared.peripherals.lock(|p: &mut sensor_periph::Peripherals| p.uptime_seconds());
+                    let history = cx.shared.buffer.lock(|b: &mut buffer::HistoryBuffer| {
+                        b.oldest(bluetooth::HISTORY_CAPACITY, now)
+                    });
+                    let Some(history) = history else { break };
+
+                    debug!("Sending buffered measurements: {}", history);
+                    let adv_result = bt.advertise_history(&history).await;
+                    if let Err(AdvertiseError::Timeout) = adv_result {
+                        cx.shared.buffer.lock(|b: &mut buffer::HistoryBuffer| b.remove_sent(&history));
+                    } else {
+                        warn!("Unexpected advertising result: {}", adv_result);
+                        break;
+                    }
+                }
             } else {
                 error!("Error receiving from channel");
             }
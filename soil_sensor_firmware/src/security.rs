@@ -0,0 +1,120 @@
+use embedded_storage_async::nor_flash::{NorFlash, ReadNorFlash};
+use nrf_softdevice::{raw, Flash, FlashError};
+use soil_sensor_common::crypto::{Block, BlockEncrypt, Key, KEY_LEN};
+
+/// First address of the last page of flash, which is kept out of the `FLASH` region in memory.x
+const BOOT_COUNT_PAGE: u32 = 0x2F000;
+const PAGE_SIZE: u32 = 4096;
+const WORD_SIZE: u32 = 4;
+
+const fn hex_digit(byte: u8) -> u8 {
+    match byte {
+        b'0'..=b'9' => byte - b'0',
+        b'a'..=b'f' => byte - b'a' + 10,
+        b'A'..=b'F' => byte - b'A' + 10,
+        _ => panic!("Environment variable SENSOR_KEY is not a valid hex number")
+    }
+}
+
+/// Statically parse the string from environment variable "SENSOR_KEY" into a 128-bit AES key.
+/// If SENSOR_KEY is not set, then payloads are sent unauthenticated.
+/// Compilation will fail if SENSOR_KEY is set, but is not a valid 32-digit hexadecimal number
+const fn get_key() -> Option<Key> {
+    let string: &'static str = match option_env!("SENSOR_KEY") {
+        Some(string) => string,
+        None => return None
+    };
+    let bytes = string.as_bytes();
+    assert!(bytes.len() == KEY_LEN * 2);
+    let mut key: Key = [0; KEY_LEN];
+    let mut i = 0;
+    while i < KEY_LEN {
+        key[i] = (hex_digit(bytes[2 * i]) << 4) | hex_digit(bytes[2 * i + 1]);
+        i += 1;
+    }
+    Some(key)
+}
+
+pub const SENSOR_KEY: Option<Key> = get_key();
+
+const _: () = assert!(
+    !cfg!(feature = "encrypted") || SENSOR_KEY.is_some(),
+    "The \"encrypted\" feature requires environment variable SENSOR_KEY to be set"
+);
+
+/// AES-128 using the SoftDevice's ECB API, since the ECB peripheral itself is reserved by the
+/// SoftDevice while it is enabled.
+pub struct SoftdeviceEcb {
+    data: raw::nrf_ecb_hal_data_t,
+}
+
+impl SoftdeviceEcb {
+    pub fn new(key: &Key) -> Self {
+        Self {
+            data: raw::nrf_ecb_hal_data_t {
+                key: *key,
+                cleartext: [0; 16],
+                ciphertext: [0; 16],
+            }
+        }
+    }
+}
+
+impl BlockEncrypt for SoftdeviceEcb {
+    fn encrypt_block(&mut self, block: &mut Block) {
+        self.data.cleartext = *block;
+        // Can only fail if the pointer is invalid, which it never is.
+        let ret = unsafe { raw::sd_ecb_block_encrypt(&mut self.data) };
+        debug_assert!(ret == raw::NRF_SUCCESS);
+        *block = self.data.ciphertext;
+    }
+}
+
+#[derive(Debug, defmt::Format)]
+pub enum BootCountError {
+    Flash(FlashError),
+    /// The boot count has reached `u16::MAX`, and incrementing it any further would wrap around
+    /// to boot counts (and therefore nonces) which have already been used.
+    Exhausted,
+}
+
+impl From<FlashError> for BootCountError {
+    fn from(e: FlashError) -> Self {
+        Self::Flash(e)
+    }
+}
+
+/// Increment and return the number of times this sensor has booted.
+///
+/// The boot count page is used as an append-only log: every boot writes the new count into
+/// the next erased word, and the page is only erased once it fills up (every 1024 boots).
+///
+/// The count saturates at `u16::MAX`: once it gets there, this always fails without writing.
+pub async fn increment_boot_count(flash: &mut Flash) -> Result<u16, BootCountError> {
+    let end = BOOT_COUNT_PAGE + PAGE_SIZE;
+    let mut previous: Option<u32> = None;
+    let mut offset = BOOT_COUNT_PAGE;
+    while offset < end {
+        let mut word = [0u8; WORD_SIZE as usize];
+        flash.read(offset, &mut word).await?;
+        let value = u32::from_le_bytes(word);
+        if value == u32::MAX {
+            break;
+        }
+        previous = Some(value);
+        offset += WORD_SIZE;
+    }
+
+    let count = match previous {
+        None => 0,
+        Some(value) if value >= u16::MAX as u32 => return Err(BootCountError::Exhausted),
+        Some(value) => value + 1,
+    };
+    if offset == end {
+        flash.erase(BOOT_COUNT_PAGE, end).await?;
+        offset = BOOT_COUNT_PAGE;
+    }
+    flash.write(offset, &count.to_le_bytes()).await?;
+
+    Ok(count as u16)
+}
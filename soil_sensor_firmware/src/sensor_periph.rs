@@ -8,7 +8,9 @@ use nrf52810_hal::pac::saadc::{resolution, oversample, ch::{pselp, config as adc
 use nrf52810_hal::prelude::{ConfigurablePpi, OutputPin};
 use nrf52810_hal::pac::timer1::{bitmode as timer_bitmode, mode as timer_mode};
 
-use soil_sensor_common::Measurement;
+use soil_sensor_common::{Measurement, Status};
+use soil_sensor_common::telemetry::ResetReason;
+use soil_sensor_common::units;
 use void::ResultVoidExt;
 
 // Sleep for 1 hour between measurements
@@ -61,6 +63,13 @@ pub struct Peripherals {
     temp: pac::TEMP,
     temp_buffer: i32,
     sequence: u16,
+    /// Number of measurements taken since reset. Unlike `sequence`, this doesn't wrap around.
+    cycles: u32,
+    /// Whether the probe was powered during the current cycle
+    probe_enabled: bool,
+    /// Whether `temp_buffer` was read during the current cycle
+    temp_fresh: bool,
+    reset_reason: ResetReason,
 }
 
 impl Peripherals {
@@ -70,6 +79,8 @@ impl Peripherals {
         core.SCB.set_sleepdeep();
         core.SCB.set_sleeponexit();
         setup_interrupt_priority(core);
+        // Has to happen before the SoftDevice is enabled, since it restricts access to POWER
+        let reset_reason = read_reset_reason(&p.POWER);
         p.POWER.dcdcen.write(|w| w.dcdcen().set_bit());
 
         let rtc = setup_rtc1(p.RTC1, core)?;
@@ -90,8 +101,12 @@ impl Peripherals {
             adc_buffer: dma_buffer,
             ppi,
             temp: p.TEMP,
-            temp_buffer: i32::MIN,
+            temp_buffer: units::TEMPERATURE_UNAVAILABLE,
             sequence: u16::MAX,
+            cycles: 0,
+            probe_enabled: false,
+            temp_fresh: false,
+            reset_reason,
         };
 
         peripherals.setup_ppi();
@@ -191,6 +206,7 @@ impl Peripherals {
         if self.temp.events_datardy.read().events_datardy().bit() {
             self.temp.events_datardy.reset();
             self.temp_buffer = self.temp.temp.read().temp().bits() as i32;
+            self.temp_fresh = true;
             Ok(())
         } else {
             Err(())
@@ -199,20 +215,56 @@ impl Peripherals {
 
     pub fn get_measurement(&mut self) -> Measurement {
         (self.sequence, _) = self.sequence.overflowing_add(1);
+        self.cycles += 1;
+        let moisture_frequency = self.counter.cc[0].read().cc().bits();
+
+        let mut status = Status::empty();
+        if !self.probe_enabled {
+            status |= Status::LOW_ENERGY;
+        } else if moisture_frequency == 0 {
+            // The oscillator always runs when powered, so no pulses means it isn't connected
+            status |= Status::PROBE_FAULT;
+        }
+        if !self.temp_fresh {
+            status |= Status::TEMPERATURE_STALE;
+        }
+        if self.cycles == 1 {
+            status |= Status::FIRST_AFTER_RESET;
+        }
+        self.probe_enabled = false;
+        self.temp_fresh = false;
+
         Measurement {
             id: SENSOR_ID,
             capacitor_voltage: self.adc_buffer[0],
-            moisture_frequency: self.counter.cc[0].read().cc().bits(),
+            moisture_frequency,
             temperature: self.temp_buffer,
-            sequence: self.sequence
+            sequence: self.sequence,
+            status,
         }
     }
 
+    /// Approximate seconds since reset, for timestamping buffered measurements.
+    ///
+    /// RTC1 starts over at the beginning of every measurement cycle, so this is the number of
+    /// whole cycles since the first measurement (which is taken right after reset), plus the
+    /// time into the current one.
+    pub fn uptime_seconds(&self) -> u32 {
+        let completed_cycles = self.cycles.saturating_sub(1);
+        let ticks = self.rtc.counter.read().counter().bits();
+        completed_cycles * SLEEP_SECONDS + ticks / units::RTC_TICKS_PER_SECOND
+    }
+
+    pub fn reset_reason(&self) -> ResetReason {
+        self.reset_reason
+    }
+
     pub fn trigger_rtc_overflow(&self) {
         self.rtc.tasks_trigovrflw.write(|w| w.tasks_trigovrflw().variant(TASKS_TRIGOVRFLW_AW::TRIGGER));
     }
 
     pub fn enable_probe(&mut self) {
+        self.probe_enabled = true;
         self.probe_enable.set_high().void_unwrap()
     }
 
@@ -239,17 +291,35 @@ impl Peripherals {
 /// Enables events for Compare0, Compare1, and maybe Compare3.
 ///
 /// Enables interrupts for Compare3.
+/// Reads and clears RESETREAS. The register accumulates reasons until it is cleared, so otherwise
+/// the next reset would report this one's reason as well.
+fn read_reset_reason(power: &pac::POWER) -> ResetReason {
+    let bits = power.resetreas.read().bits();
+    power.resetreas.write(|w| unsafe { w.bits(bits) });
+    // If more than one is set, report the lowest. No bits at all means power-on or brown-out.
+    match bits {
+        0 => ResetReason::PowerOn,
+        _ if bits & (1 << 0) != 0 => ResetReason::Pin,
+        _ if bits & (1 << 1) != 0 => ResetReason::Watchdog,
+        _ if bits & (1 << 2) != 0 => ResetReason::SoftReset,
+        _ if bits & (1 << 3) != 0 => ResetReason::Lockup,
+        _ if bits & (1 << 16) != 0 => ResetReason::WakeFromOff,
+        _ if bits & (1 << 18) != 0 => ResetReason::Debugger,
+        _ => ResetReason::Other(bits.trailing_zeros() as u8),
+    }
+}
+
 fn setup_rtc1(rtc1: pac::RTC1, core: &mut cortex_m::Peripherals) -> Result<pac::RTC1, SetupError>
 {
-    // const PRESCALER: u32 = 0xFFF;
-    const PRESCALER: u32 = 0x007;
-    const ONE_SECOND: u32 = clocks::LFCLK_FREQ / (PRESCALER + 1);
-    const QUARTER_SECOND: u32 = ONE_SECOND / 4;
+    // The prescaler and gate window are shared with the bridge, which needs them to convert the
+    // pulse count into a frequency.
+    const _: () = assert!(clocks::LFCLK_FREQ == units::LFCLK_FREQ);
+    const ONE_SECOND: u32 = units::RTC_TICKS_PER_SECOND;
 
-    let mut rtc1 = rtc::Rtc::new(rtc1, PRESCALER)?;
-    rtc1.set_compare(rtc::RtcCompareReg::Compare0, QUARTER_SECOND)?;
+    let mut rtc1 = rtc::Rtc::new(rtc1, units::RTC_PRESCALER)?;
+    rtc1.set_compare(rtc::RtcCompareReg::Compare0, units::MOISTURE_SETTLE_TICKS)?;
     rtc1.enable_event(rtc::RtcInterrupt::Compare0);
-    rtc1.set_compare(rtc::RtcCompareReg::Compare1, ONE_SECOND + QUARTER_SECOND)?;
+    rtc1.set_compare(rtc::RtcCompareReg::Compare1, units::MOISTURE_SETTLE_TICKS + units::MOISTURE_GATE_TICKS)?;
     rtc1.enable_event(rtc::RtcInterrupt::Compare1);
 
     // The "Trigger Overflow" task sets the register to (Overflow - 0x0F), so to get the sleep
@@ -346,8 +416,15 @@ fn setup_counter(timer1: &mut pac::TIMER1)
 }
 
 /// Sets up a single ADC channel for measuring the voltage on the main capacitor.
+///
+/// The resolution, gain and reference must match the constants in `soil_sensor_common::units`,
+/// which are used to convert readings into volts.
 fn setup_adc(adc: &mut pac::SAADC, dma_buffer: &mut [i16])
 {
+    const _: () = assert!(units::ADC_RESOLUTION_BITS == 14);
+    const _: () = assert!(units::ADC_GAIN == 1.0 / 4.0);
+    const _: () = assert!(units::ADC_REFERENCE_VDD_FRACTION == 1.0 / 4.0);
+
     adc.resolution.write(|w| w.val().variant(resolution::VAL_A::_14BIT));
     adc.oversample.write(|w| w.oversample().variant(oversample::OVERSAMPLE_A::OVER256X));
 